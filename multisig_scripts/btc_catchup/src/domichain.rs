@@ -171,6 +171,18 @@ impl<'de> Deserialize<'de> for JsonSignature {
 pub struct DomiTransactionMeta {
     pub err: Option<Value>,
     pub status: Value,
+    #[serde(default)]
+    pub post_token_balances: Vec<DomiTokenBalance>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DomiTokenBalance {
+    #[serde(deserialize_with = "from_str")]
+    pub mint: Pubkey,
+    /// Owner of the token account
+    pub owner: Option<String>,
+    pub ui_token_amount: Value,
 }
 
 #[derive(Deserialize, Debug)]
@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::BTreeSet;
 
 use crate::{mempool, BtcTransaction, BtcTransactionType};
 
@@ -28,41 +28,10 @@ pub async fn get_btc_transactions(address: &str) -> Vec<BtcTransaction> {
     for tx in txs {
         assert!(tx.status.confirmed);
 
-        let block = tx.status.block_height;
-
-        let vin_multisig = tx
-            .vin
-            .iter()
-            .any(|vin| vin.prevout.scriptpubkey_address == multisig_address);
-        let vout_multisig = tx.vout.iter().any(|vout| {
-            vout.scriptpubkey_address.as_ref().map(|a| a.as_str()) == Some(multisig_address)
-        });
-        let tx_type = match (vin_multisig, vout_multisig) {
-            (true, true) => {
-                assert!(tx
-                    .vin
-                    .iter()
-                    .all(|vin| &vin.prevout.scriptpubkey_address == multisig_address));
+        let tx_type = btc_transaction_type(&tx, multisig_address);
 
-                let source_amount: u64 = tx.vin.iter().map(|vin| vin.prevout.value).sum();
-                let destination_amount: u64 = tx
-                    .vout
-                    .iter()
-                    .filter_map(|vout| {
-                        (vout.scriptpubkey_address.as_ref().map(|a| a.as_str())
-                            == Some(multisig_address))
-                        .then_some(vout.value)
-                    })
-                    .sum();
-
-                assert!(source_amount > destination_amount);
-
-                BtcTransactionType::Withdraw // multisig_address in input and output (change)
-            }
-            (true, false) => BtcTransactionType::Withdraw, // multisig_address in input
-            (false, true) => BtcTransactionType::Deposit,  // multisig_address in output
-            (false, false) => panic!("No multisig address in transaction"),
-        };
+        let block = tx.status.block_height;
+        let block_hash = tx.status.block_hash;
 
         let from_address;
         let to_address;
@@ -70,17 +39,12 @@ pub async fn get_btc_transactions(address: &str) -> Vec<BtcTransaction> {
 
         match tx_type {
             BtcTransactionType::Deposit => {
-                let input_addresses: HashSet<_> = tx
+                // Sender wallet could spend from multiple addresses, take the first one
+                let input_addresses: BTreeSet<_> = tx
                     .vin
                     .iter()
                     .map(|vin| vin.prevout.scriptpubkey_address.as_str())
                     .collect();
-                if input_addresses.len() > 1 {
-                    panic!(
-                        "Could not define single `from_address`. TX ID: {}",
-                        &tx.txid
-                    );
-                }
                 from_address = input_addresses.into_iter().next().unwrap().to_string();
 
                 // Discard other outgoing BTC
@@ -107,34 +71,34 @@ pub async fn get_btc_transactions(address: &str) -> Vec<BtcTransaction> {
                     .iter()
                     .all(|vout| vout.scriptpubkey_address.is_some()));
 
-                let input_addresses: HashSet<_> = tx
+                // Multi-source withdrawal spends several deposit addresses, this is one of them
+                from_address = multisig_address.to_string();
+
+                // Change of any source is not a destination. Take the first recipient
+                let input_addresses: BTreeSet<_> = tx
                     .vin
                     .iter()
                     .map(|vin| vin.prevout.scriptpubkey_address.as_str())
                     .collect();
-                if input_addresses.len() > 1 {
-                    panic!("Could not define single `from_address`. Should be single `multisig_address`");
+                to_address = tx
+                    .vout
+                    .iter()
+                    .map(|vout| vout.scriptpubkey_address.as_ref().unwrap().as_str())
+                    .find(|address| !input_addresses.contains(address))
+                    .unwrap()
+                    .to_string();
+
+                amount = withdrawn_amount(&tx, multisig_address);
+            }
+            BtcTransactionType::Consolidation => {
+                if !include_withdraws {
+                    continue;
                 }
-                from_address = input_addresses.into_iter().next().unwrap().to_string();
 
-                // Ugnore change addresses
-                let destination_amounts: HashMap<_, _> =
-                    tx.vout
-                        .iter()
-                        .filter_map(|vout| {
-                            (vout.scriptpubkey_address.as_ref().unwrap() != multisig_address)
-                                .then_some((
-                                    vout.scriptpubkey_address.as_ref().unwrap().as_str(),
-                                    vout.value,
-                                ))
-                        })
-                        .collect();
-                assert_eq!(destination_amounts.len(), 1);
-                let (destination, destination_amount) =
-                    destination_amounts.into_iter().next().unwrap();
-                assert_ne!(destination_amount, 0);
-                amount = destination_amount + tx.fee;
-                to_address = destination.to_string();
+                // Only fee leaves the multisig_address
+                from_address = multisig_address.to_string();
+                to_address = multisig_address.to_string();
+                amount = tx.fee;
             }
         }
 
@@ -149,6 +113,7 @@ pub async fn get_btc_transactions(address: &str) -> Vec<BtcTransaction> {
             tx_type,
             amount,
             block,
+            block_hash,
         })
     }
 
@@ -161,6 +126,158 @@ pub async fn get_btc_transactions(address: &str) -> Vec<BtcTransaction> {
     result
 }
 
+/// Inputs of `multisig_address` minus its change, including its share of the fee.
+///
+/// Inputs of other sources of multi-source withdrawal are not counted.
+fn withdrawn_amount(tx: &mempool::Transaction, multisig_address: &str) -> u64 {
+    let spent: u64 = tx
+        .vin
+        .iter()
+        .filter(|vin| vin.prevout.scriptpubkey_address == multisig_address)
+        .map(|vin| vin.prevout.value)
+        .sum();
+    let change: u64 = tx
+        .vout
+        .iter()
+        .filter(|vout| {
+            vout.scriptpubkey_address.as_ref().map(|a| a.as_str()) == Some(multisig_address)
+        })
+        .map(|vout| vout.value)
+        .sum();
+    spent.saturating_sub(change)
+}
+
+/// Direction of `tx` relative to `multisig_address`
+fn btc_transaction_type(tx: &mempool::Transaction, multisig_address: &str) -> BtcTransactionType {
+    let vin_multisig = tx
+        .vin
+        .iter()
+        .any(|vin| vin.prevout.scriptpubkey_address == multisig_address);
+    let vout_multisig = tx.vout.iter().any(|vout| {
+        vout.scriptpubkey_address.as_ref().map(|a| a.as_str()) == Some(multisig_address)
+    });
+    match (vin_multisig, vout_multisig) {
+        (true, true) => {
+            // Multi-source withdrawal also spends other deposit addresses
+            let only_multisig_inputs = tx
+                .vin
+                .iter()
+                .all(|vin| vin.prevout.scriptpubkey_address == multisig_address);
+            let self_transfer = tx.vout.iter().all(|vout| {
+                vout.scriptpubkey_address.as_ref().map(|a| a.as_str()) == Some(multisig_address)
+            });
+            if only_multisig_inputs && self_transfer {
+                BtcTransactionType::Consolidation // multisig_address UTXOs merged
+            } else {
+                BtcTransactionType::Withdraw // multisig_address in input and output (change)
+            }
+        }
+        (true, false) => BtcTransactionType::Withdraw, // multisig_address in input
+        (false, true) => BtcTransactionType::Deposit,  // multisig_address in output
+        (false, false) => panic!("No multisig address in transaction"),
+    }
+}
+
+#[test]
+fn test_btc_transaction_type_multi_source() {
+    let multisig_address = "bc1qrqd3f0k9a6fcyvxnvpathv0mj59paqrpge84zw0fmuuz2r0956eq24nzlv";
+    let other_multisig_address = "bc1q35yhc5khmqr6q5wxne6dud233wzefy43k4w9sv";
+    let vin = |address: &str, value: u64| {
+        serde_json::json!({
+            "txid": "0000000000000000000000000000000000000000000000000000000000000000",
+            "vout": 0,
+            "prevout": {
+                "scriptpubkey": "",
+                "scriptpubkey_asm": "",
+                "scriptpubkey_type": "v0_p2wsh",
+                "scriptpubkey_address": address,
+                "value": value
+            },
+            "scriptsig": "",
+            "scriptsig_asm": "",
+            "witness": null,
+            "is_coinbase": false,
+            "sequence": 4294967293u32
+        })
+    };
+    let vout = |address: &str, value: u64| {
+        serde_json::json!({
+            "scriptpubkey": "",
+            "scriptpubkey_asm": "",
+            "scriptpubkey_type": "v0_p2wpkh",
+            "scriptpubkey_address": address,
+            "value": value
+        })
+    };
+    let tx = |vin: Vec<serde_json::Value>, vout: Vec<serde_json::Value>| {
+        serde_json::from_value::<mempool::Transaction>(serde_json::json!({
+            "txid": "1111111111111111111111111111111111111111111111111111111111111111",
+            "version": 2,
+            "locktime": 0,
+            "vin": vin,
+            "vout": vout,
+            "size": 400,
+            "weight": 1000,
+            "sigops": 0,
+            "fee": 1000,
+            "status": {
+                "confirmed": true,
+                "block_height": 840719,
+                "block_hash": "0000000000000000000170deaa4ccf2de2f1c94346dfef40318d0a7c5178ffd3",
+                "block_time": 1713994081
+            }
+        }))
+        .unwrap()
+    };
+
+    // Two deposit addresses spent, change returns to the first one
+    let withdrawal = tx(
+        vec![
+            vin(multisig_address, 30_000),
+            vin(other_multisig_address, 20_000),
+        ],
+        vec![
+            vout("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", 45_000),
+            vout(multisig_address, 4_000),
+        ],
+    );
+    assert!(matches!(
+        btc_transaction_type(&withdrawal, multisig_address),
+        BtcTransactionType::Withdraw
+    ));
+    assert!(matches!(
+        btc_transaction_type(&withdrawal, other_multisig_address),
+        BtcTransactionType::Withdraw
+    ));
+    assert_eq!(withdrawn_amount(&withdrawal, multisig_address), 26_000);
+    assert_eq!(
+        withdrawn_amount(&withdrawal, other_multisig_address),
+        20_000
+    );
+
+    // Other deposit address pays more than `multisig_address` gets back
+    let withdrawal = tx(
+        vec![
+            vin(multisig_address, 1_000),
+            vin(other_multisig_address, 50_000),
+        ],
+        vec![vout(multisig_address, 50_000)],
+    );
+    assert!(matches!(
+        btc_transaction_type(&withdrawal, multisig_address),
+        BtcTransactionType::Withdraw
+    ));
+
+    let consolidation = tx(
+        vec![vin(multisig_address, 30_000), vin(multisig_address, 20_000)],
+        vec![vout(multisig_address, 49_000)],
+    );
+    assert!(matches!(
+        btc_transaction_type(&consolidation, multisig_address),
+        BtcTransactionType::Consolidation
+    ));
+}
+
 #[tokio::test]
 async fn test_get_btc_transactions() {
     // Random address
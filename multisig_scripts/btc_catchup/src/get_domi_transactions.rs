@@ -96,12 +96,23 @@ pub async fn get_domi_transactions(
             .to_string();
         let block = tx_info.slot;
 
-        // FIXME: find destination of mint
-        // let to_address = "UNDEFINED".to_string();
+        // Service could mint to its own account and transfer, so its zero balance is skipped
+        let to_owner = tx_info
+            .meta
+            .as_ref()
+            .unwrap()
+            .post_token_balances
+            .iter()
+            .filter(|balance| {
+                balance.mint == token_mint_address
+                    && balance.ui_token_amount["amount"].as_str() != Some("0")
+            })
+            .filter_map(|balance| balance.owner.as_deref()?.parse::<Pubkey>().ok())
+            .find(|owner| *owner != service_address);
 
         all_txs.push(DomiTransaction::Mint(DomiMint {
             token_mint_address,
-            // to_address,
+            to_owner,
             amount,
             block,
         }));
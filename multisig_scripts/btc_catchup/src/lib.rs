@@ -21,6 +21,7 @@ type DomiBlock = u64;
 type BtcTxHash = String;
 type BtcAddress = String;
 type BtcBlock = u64;
+type BtcBlockHash = String;
 
 type Amount = String;
 
@@ -127,6 +128,7 @@ pub async fn do_catchup(
                     }
                 }
             }
+            BtcTransactionType::Consolidation => {}
         }
     }
     let unpaired_mints = all_domi_transactions;
@@ -183,6 +185,8 @@ async fn test_do_catchup() {
 pub enum BtcTransactionType {
     Deposit,
     Withdraw,
+    /// Service spends UTXOs of multisig address back to itself. No Domichain counterpart
+    Consolidation,
 }
 
 #[derive(Debug)]
@@ -195,6 +199,7 @@ pub struct BtcTransaction {
     pub tx_type: BtcTransactionType,
     pub amount: Amount,
     pub block: BtcBlock,
+    pub block_hash: BtcBlockHash,
 }
 
 /// Incoming and outgoing transactions of BTC address
@@ -205,7 +210,8 @@ pub async fn get_btc_transactions(address: &str) -> Vec<BtcTransaction> {
 #[derive(Debug)]
 pub struct DomiMint {
     pub token_mint_address: DomiAddress,
-    // to_address: DomiAddress,
+    /// Owner of the token account holding minted tokens after the transaction
+    pub to_owner: Option<DomiAddress>,
     pub amount: Amount,
     pub block: DomiBlock,
 }
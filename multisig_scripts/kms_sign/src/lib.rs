@@ -70,14 +70,17 @@ fn debug_sign_big_numbers(signature: &[u8]) {
 }
 
 pub async fn sign_aws(digest: Vec<u8>) -> Result<Vec<u8>, kms::Error> {
-    let key_arn = get_env("KEY_ARN");
+    sign_aws_key(&get_env("KEY_ARN"), digest).await
+}
 
+/// Sign `digest` by AWS KMS key `key_arn`. Returns DER signature
+pub async fn sign_aws_key(key_arn: &str, digest: Vec<u8>) -> Result<Vec<u8>, kms::Error> {
     let config = aws_config::load_from_env().await;
     let client = aws_sdk_kms::Client::new(&config);
 
     let sign = client
         .sign()
-        .set_key_id(Some(key_arn))
+        .set_key_id(Some(key_arn.to_string()))
         .set_signing_algorithm(Some(SigningAlgorithmSpec::EcdsaSha256))
         .set_message(Some(Blob::new(digest)))
         .set_message_type(Some(kms::types::MessageType::Digest))
@@ -150,15 +153,24 @@ pub async fn google_kms_client(
     Ok(kms_client)
 }
 
-// Source: https://github.com/abdolence/gcloud-sdk-rs/blob/master/examples/secrets-manager-client/src/main.rs
 pub async fn sign_google(digest: Vec<u8>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let key_name = get_env("KEY_NAME");
-    let kms_client = google_kms_client().await?;
+    sign_google_key(&get_env("KEY_NAME"), digest)
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)
+}
+
+// Source: https://github.com/abdolence/gcloud-sdk-rs/blob/master/examples/secrets-manager-client/src/main.rs
+/// Sign `digest` by Google KMS key version `key_name`. Returns DER signature
+pub async fn sign_google_key(
+    key_name: &str,
+    digest: Vec<u8>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let kms_client = google_kms_client().await.map_err(|e| e.to_string())?;
 
     let response = kms_client
         .get()
         .asymmetric_sign(AsymmetricSignRequest {
-            name: key_name,
+            name: key_name.to_string(),
             digest: Some(gcloud_sdk::google::cloud::kms::v1::Digest {
                 digest: Some(gcloud_sdk::google::cloud::kms::v1::digest::Digest::Sha256(
                     digest,
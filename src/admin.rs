@@ -0,0 +1,244 @@
+use axum::{
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    Json,
+};
+use bdk::FeeRate;
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+use crate::{
+    db::WithdrawalStatus,
+    fee_bump::bump_withdrawal_fee,
+    sign_multisig_tx::{process_withdrawal, SignMultisigTxRequest},
+    utils::serde_convert,
+    withdrawal::recover_withdrawal,
+    AppState,
+};
+
+type AdminError = (StatusCode, Json<Value>);
+
+fn admin_error(status_code: StatusCode, message: impl ToString) -> AdminError {
+    (
+        status_code,
+        Json(json!({
+            "status": "error",
+            "message": message.to_string(),
+        })),
+    )
+}
+
+/// Check `Authorization: Bearer <ADMIN_TOKEN>` header. Admin API is disabled without `ADMIN_TOKEN`
+pub fn check_admin_token(state: &AppState, headers: &HeaderMap) -> Result<(), AdminError> {
+    let Some(admin_token) = &state.config.admin_token else {
+        return Err(admin_error(StatusCode::NOT_FOUND, "Admin API is disabled"));
+    };
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match bearer {
+        Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => Ok(()),
+        _ => Err(admin_error(StatusCode::UNAUTHORIZED, "Unauthorized")),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn parse_withdrawal_id(withdrawal_id: &str) -> Result<Bson, AdminError> {
+    ObjectId::parse_str(withdrawal_id)
+        .map(Bson::ObjectId)
+        .map_err(|_| {
+            admin_error(
+                StatusCode::BAD_REQUEST,
+                format!("withdrawal_id is invalid: {withdrawal_id}"),
+            )
+        })
+}
+
+#[derive(Deserialize)]
+pub struct BumpFeeRequest {
+    withdrawal_id: String,
+    /// sat/vB, recommended fee rate by default
+    fee_rate: Option<f32>,
+}
+
+/// Replace stuck withdrawal TX with higher fee rate one
+pub async fn bump_fee(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<BumpFeeRequest>,
+) -> Result<Json<Value>, AdminError> {
+    check_admin_token(&state, &headers)?;
+    let id = parse_withdrawal_id(&request.withdrawal_id)?;
+    let fee_rate = request.fee_rate.map(FeeRate::from_sat_per_vb);
+
+    info!("admin: bump fee of withdrawal {id}");
+    // Separate thread to catch any errors
+    let bump_result = tokio::spawn(async move { bump_withdrawal_fee(&state, id, fee_rate).await })
+        .await
+        .map_err(|task_error| {
+            error!("admin: bump fee thread panicked: {task_error:#?}");
+            admin_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal service error")
+        })?;
+
+    match bump_result {
+        Ok(tx_id) => Ok(Json(json!({
+            "status": "ok",
+            "tx_id": tx_id,
+        }))),
+        Err(bump_error) => Err(admin_error(
+            StatusCode::BAD_REQUEST,
+            format!("{bump_error:#}"),
+        )),
+    }
+}
+
+/// Withdrawals above approval threshold, oldest first
+pub async fn withdrawals_awaiting_approval(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, AdminError> {
+    check_admin_token(&state, &headers)?;
+    let withdrawals = state
+        .db
+        .find_withdrawals_by_status(WithdrawalStatus::AwaitingApproval)
+        .await
+        .map_err(|db_error| {
+            error!("admin: failed to get withdrawals awaiting approval: {db_error}");
+            admin_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal service error")
+        })?;
+    let withdrawals: Vec<Value> = withdrawals
+        .iter()
+        .map(|withdrawal| {
+            json!({
+                "withdrawal_id": withdrawal.get_object_id("_id").map(|id| id.to_hex()).ok(),
+                "domi_address": withdrawal.get_str("domi_address").ok(),
+                "mint_address": withdrawal.get_str("mint_address").ok(),
+                "withdraw_address": withdrawal.get_str("withdraw_address").ok(),
+                "withdraw_amount": withdrawal.get_str("withdraw_amount").ok(),
+                "btci_amount": withdrawal.get_str("btci_amount").ok(),
+                "created_at": withdrawal.get_datetime("created_at").ok().map(|date| date.to_string()),
+            })
+        })
+        .collect();
+    Ok(Json(json!({
+        "status": "ok",
+        "withdrawals": withdrawals,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct ApproveWithdrawalRequest {
+    withdrawal_id: String,
+}
+
+/// Continue signing of the withdrawal awaiting approval
+pub async fn approve_withdrawal(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ApproveWithdrawalRequest>,
+) -> Result<Json<Value>, AdminError> {
+    check_admin_token(&state, &headers)?;
+    let id = parse_withdrawal_id(&request.withdrawal_id)?;
+    let withdrawal =
+        claim_awaiting_approval(&state, id.clone(), doc! { "approved_at": DateTime::now() })
+            .await?;
+
+    info!("admin: withdrawal {id} is approved");
+    let request: SignMultisigTxRequest =
+        serde_convert::<_, _>(withdrawal.get_document("request").map_err(|_| {
+            admin_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Withdrawal request is missing",
+            )
+        })?);
+    tokio::spawn(process_withdrawal(state, id, request));
+    Ok(Json(json!({
+        "status": "ok",
+        "withdrawal_status": WithdrawalStatus::Verified.as_str(),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct RejectWithdrawalRequest {
+    withdrawal_id: String,
+    reason: Option<String>,
+}
+
+/// Refund BTCi of the withdrawal awaiting approval
+pub async fn reject_withdrawal(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<RejectWithdrawalRequest>,
+) -> Result<Json<Value>, AdminError> {
+    check_admin_token(&state, &headers)?;
+    let id = parse_withdrawal_id(&request.withdrawal_id)?;
+    let reason = request
+        .reason
+        .unwrap_or_else(|| "rejected by operator".to_string());
+    claim_awaiting_approval(&state, id.clone(), doc! { "rejected_at": DateTime::now() }).await?;
+
+    info!("admin: withdrawal {id} is rejected: {reason}");
+    // Separate thread to catch any errors
+    let refund_result = tokio::spawn(async move { recover_withdrawal(&state, id, &reason).await })
+        .await
+        .map_err(|task_error| {
+            error!("admin: reject thread panicked: {task_error:#?}");
+            admin_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal service error")
+        })?;
+
+    match refund_result {
+        Ok(status) => Ok(Json(json!({
+            "status": "ok",
+            "withdrawal_status": status.as_str(),
+        }))),
+        Err(refund_error) => Err(admin_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("{refund_error:#}"),
+        )),
+    }
+}
+
+/// Move withdrawal out of approval queue, so it's approved or rejected only once
+async fn claim_awaiting_approval(
+    state: &AppState,
+    id: Bson,
+    update: Document,
+) -> Result<Document, AdminError> {
+    let internal_error = |db_error: mongodb::error::Error| {
+        error!("admin: failed to claim withdrawal: {db_error}");
+        admin_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal service error")
+    };
+    let claimed = state
+        .db
+        .claim_withdrawal(
+            id.clone(),
+            WithdrawalStatus::AwaitingApproval,
+            WithdrawalStatus::Verified,
+            update,
+        )
+        .await
+        .map_err(internal_error)?;
+    if !claimed {
+        return Err(admin_error(
+            StatusCode::CONFLICT,
+            format!("Withdrawal {id} is not awaiting approval"),
+        ));
+    }
+    state
+        .db
+        .find_withdrawal(id.clone())
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(|| {
+            admin_error(
+                StatusCode::NOT_FOUND,
+                format!("Withdrawal {id} is not found"),
+            )
+        })
+}
@@ -0,0 +1,28 @@
+use serde_json::{json, Value};
+use tracing::error;
+
+use crate::Args;
+
+/// Report an event which requires operator attention.
+///
+/// Always logged at `ERROR` level and additionally posted to `ALERT_WEBHOOK_URL` if configured.
+pub async fn send_alert(config: &Args, subject: &str, details: Value) {
+    error!("ALERT: {subject}: {details:#}");
+
+    let Some(webhook_url) = config.alert_webhook_url.clone() else {
+        return;
+    };
+    let result = reqwest::Client::new()
+        .post(webhook_url)
+        .json(&json!({
+            "service": env!("CARGO_PKG_NAME"),
+            "subject": subject,
+            "details": details,
+        }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(webhook_error) = result {
+        error!("Failed to send alert to webhook: {webhook_error}");
+    }
+}
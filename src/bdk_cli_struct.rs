@@ -1,70 +1,69 @@
-use std::{borrow::Cow, path::Path, sync::Arc, time::Instant};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
-use bdk::{bitcoin::Network, FeeRate};
+use anyhow::{anyhow, bail, Context};
+use bdk::{
+    bitcoin::{
+        bip32::{DerivationPath, ExtendedPrivKey, KeySource},
+        blockdata::script::Instruction,
+        ecdsa,
+        hashes::Hash,
+        psbt::PartiallySignedTransaction,
+        secp256k1::{self, Message, Secp256k1},
+        sighash::{EcdsaSighashType, SighashCache},
+        Address, Network, PublicKey, Script, ScriptBuf, Txid,
+    },
+    blockchain::{Blockchain, ElectrumBlockchain},
+    database::MemoryDatabase,
+    electrum_client::Client,
+    keys::{
+        bip39::{Language, Mnemonic, WordCount},
+        DerivableKey, DescriptorKey, ExtendedKey, GeneratableKey, GeneratedKey, IntoDescriptorKey,
+    },
+    miniscript::{policy::Concrete, Descriptor, Segwitv0},
+    wallet::AddressIndex,
+    Error, FeeRate, KeychainKind, SignOptions, SyncOptions, TransactionDetails, Wallet,
+};
 use cached::{proc_macro::cached, Return, TimedCache};
-use futures::Future;
 use serde::{Deserialize, Serialize};
-use serde_json::from_value;
-use tokio::fs::remove_dir_all;
-use tracing::info;
+use tracing::{error, info};
 
-use crate::{
-    bdk_cli::{exec_with_json_output, try_exec_with_json_output, WALLET_DIR_PERMIT},
-    estimate_fee::get_vbytes,
-};
+use crate::estimate_fee::get_vbytes;
 
+/// Keys, descriptors, TXs and signing of multisig wallets, done in-process by BDK
+/// the same way as `bdk-cli` commands in comments
 #[derive(Debug)]
 pub struct BdkCli {
     pub network: Network,
-    pub cli_path: Arc<Path>,
-    pub cli_path_patched: Arc<Path>,
-    pub temp_wallet_dir: Option<Arc<Path>>,
-    pub descriptor: Option<String>,
 }
 
 #[allow(dead_code)]
 impl BdkCli {
-    pub async fn new(
-        network: Network,
-        cli_path: Arc<Path>,
-        cli_path_patched: Arc<Path>,
-        temp_wallet_dir: Option<Arc<Path>>,
-        descriptor: Option<String>,
-    ) -> Self {
-        assert!(tokio::fs::try_exists(&cli_path).await.unwrap());
-        assert!(tokio::fs::try_exists(&cli_path_patched).await.unwrap());
-
-        if let Some(temp_wallet_dir) = &temp_wallet_dir {
-            // Not exists
-            assert!(!tokio::fs::try_exists(temp_wallet_dir).await.unwrap());
-        }
-
-        Self {
-            network,
-            cli_path,
-            cli_path_patched,
-            temp_wallet_dir,
-            descriptor,
-        }
+    pub fn new(network: Network) -> Self {
+        Self { network }
     }
 
+    // bdk-cli key generate
     pub async fn generate_key(&self) -> CliGenerateKeyResult {
-        let result = exec_with_json_output(
-            &["--network", &self.network.to_string(), "key", "generate"],
-            self.cli_path.as_ref(),
-        )
-        .await;
-        let GenerateKeyInnerResult {
-            fingerprint,
-            mnemonic,
-            xprv,
-        } = from_value(result).unwrap();
+        let mnemonic: GeneratedKey<_, Segwitv0> =
+            Mnemonic::generate((WordCount::Words24, Language::English))
+                .expect("Mnemonic generation error");
+        let mnemonic = mnemonic.into_key();
+        let xkey: ExtendedKey = mnemonic.clone().into_extended_key().unwrap();
+        let xprv = xkey.into_xprv(self.network).unwrap();
+        let fingerprint = xprv.fingerprint(&Secp256k1::new());
+        let xprv = xprv.to_string();
 
         let xpub = self.get_pubkey(&xprv).await;
 
         CliGenerateKeyResult {
-            fingerprint,
-            mnemonic,
+            fingerprint: fingerprint.to_string(),
+            mnemonic: mnemonic.to_string(),
             xprv,
             xpub,
         }
@@ -72,22 +71,18 @@ impl BdkCli {
 
     // export XPUB_00=$(bdk-cli key derive --xprv $XPRV_00 --path "m/84'/1'/0'/0" | jq -r ".xpub")
     pub async fn get_pubkey(&self, xprv: &str) -> String {
-        let result = exec_with_json_output(
-            &[
-                "--network",
-                &self.network.to_string(),
-                "key",
-                "derive",
-                "--xprv",
-                xprv,
-                "--path",
-                "m/84'/1'/0'/0",
-            ],
-            self.cli_path.as_ref(),
-        )
-        .await;
-        let result: GetPubkeyInnerResult = from_value(result).unwrap();
-        result.xpub
+        let secp = Secp256k1::new();
+        let xprv = ExtendedPrivKey::from_str(xprv).unwrap();
+        let path = DerivationPath::from_str("m/84'/1'/0'/0").unwrap();
+        let derived_xprv = xprv.derive_priv(&secp, &path).unwrap();
+        let origin: KeySource = (xprv.fingerprint(&secp), path);
+        let derived_xprv_desc_key: DescriptorKey<Segwitv0> = derived_xprv
+            .into_descriptor_key(Some(origin), DerivationPath::default())
+            .unwrap();
+        let DescriptorKey::Secret(desc_seckey, _, _) = derived_xprv_desc_key else {
+            unreachable!("extended private key is derived into secret descriptor key");
+        };
+        desc_seckey.to_public(&secp).unwrap().to_string()
     }
 
     pub async fn get_multi_descriptor(
@@ -103,16 +98,7 @@ impl BdkCli {
         // export MULTI_DESCRIPTOR_00=$(bdk-cli compile "thresh(3,pk($DESCRIPTOR_00),pk($XPUB_01),pk($XPUB_02))" | jq -r '.descriptor')
         let desc_00 =
             format!("thresh(3,pk({descriptor_00}),pk({xpub_01}),pk({xpub_02}),pk({xpub_03}))");
-        let multi_descriptor_00_ = exec_with_json_output(
-            &["--network", &self.network.to_string(), "compile", &desc_00],
-            self.cli_path.as_ref(),
-        )
-        .await;
-        let multi_descriptor_00 = multi_descriptor_00_["descriptor"]
-            .as_str()
-            .unwrap()
-            .to_owned();
-        multi_descriptor_00
+        compile_policy(&desc_00)
     }
 
     pub async fn get_pub_multi_descriptor(
@@ -124,372 +110,258 @@ impl BdkCli {
     ) -> String {
         // export MULTI_DESCRIPTOR_01=$(bdk-cli compile "thresh(3,pk($XPUB_00),pk($XPUB_01),pk($XPUB_02))" | jq -r '.descriptor')
         let desc_01 = format!("thresh(3,pk({xpub_00}),pk({xpub_01}),pk({xpub_02}),pk({xpub_03}))");
-        let multi_descriptor_01_ = exec_with_json_output(
-            &["--network", &self.network.to_string(), "compile", &desc_01],
-            self.cli_path.as_ref(),
-        )
-        .await;
-        let multi_descriptor_01 = multi_descriptor_01_["descriptor"]
-            .as_str()
-            .unwrap()
-            .to_owned();
-        multi_descriptor_01
+        compile_policy(&desc_01)
     }
 
     pub async fn get_multi_address(&self, multi_descriptor_00: &str) -> String {
-        // Clear temporary bdk cache
-        let multi_address_ = self
-            .with_temp_wallet_dir(|| async {
-                exec_with_json_output(
-                    self.wallet_args(multi_descriptor_00, &["get_new_address"])
-                        .iter(),
-                    self.cli_path.as_ref(),
-                )
-                .await
-                // bdk_cli_wallet_inner(multi_descriptor_00, &["get_new_address"], &self.cli_path)
-                //     .await
-            })
-            .await;
-        // let multi_address_ =
-        //     bdk_cli_wallet_temp_inner(multi_descriptor_00, &["get_new_address"], &self.cli_path)
-        //         .await;
-        let multi_address = multi_address_["address"].as_str().unwrap().to_owned();
-        multi_address
-    }
-
-    fn wallet_args(&self, descriptor: &str, args: &[&str]) -> Vec<String> {
-        let network = self.network.to_string();
-        let mut cli_args = vec![
-            "--network",
-            &network,
-            "wallet",
-            // "--wallet",
-            // "wallet_name_temp", // TODO: config
-            "--descriptor",
-            descriptor,
-        ];
-        cli_args.extend_from_slice(args);
-        cli_args.into_iter().map(|s| s.to_owned()).collect()
+        // Wallet without history, so it is the first address of the descriptor
+        let wallet = Wallet::new(
+            multi_descriptor_00,
+            None,
+            self.network,
+            MemoryDatabase::default(),
+        )
+        .unwrap();
+        let multi_address = wallet.get_address(AddressIndex::New).unwrap().address;
+        multi_address.to_string()
     }
 
-    fn wallet_args_online(&self, descriptor: &str, args: &[&str]) -> Vec<String> {
-        let is_electrum = true;
-
-        let server = if is_electrum {
-            // cargo install bdk-cli --features compiler,electrum
-            match self.network {
-                Network::Bitcoin => "ssl://electrum.blockstream.info:50002",
-                Network::Testnet => "ssl://electrum.blockstream.info:60002",
-                Network::Signet => todo!(),
-                Network::Regtest => todo!(),
-                _ => todo!(),
+    /// Returns fee, vbytes and confirmed balance of the wallet
+    pub async fn estimate_fee(
+        &self,
+        multi_descriptor_00: &str,
+        recipients: &[(String, u64)],
+        fee_rate: FeeRate,
+    ) -> Result<(u64, u64, u64), &'static str> {
+        // bdk-cli wallet --descriptor $MULTI_DESCRIPTOR_00 sync
+        let Return {
+            was_cached,
+            value: wallet,
+        } = synced_wallet(self.network, multi_descriptor_00)
+            .await
+            .map_err(|sync_error| {
+                error!("Wallet sync failed: {sync_error}");
+                "Wallet sync failed. Try again later"
+            })?;
+        info!("sync was_cached: {was_cached:?}");
+
+        let network = self.network;
+        let recipients = recipients.to_vec();
+        tokio::task::spawn_blocking(move || {
+            let wallet = wallet.lock().unwrap();
+
+            let balance = wallet.get_balance().map_err(balance_error)?;
+            info!("balance: {balance:?}");
+
+            let confirmed = balance.confirmed;
+            if confirmed == 0 {
+                return Err("Confirmed balance is zero");
             }
-        } else {
-            // cargo install bdk-cli --features compiler,esplora-ureq
-            // https://github.com/Blockstream/esplora/blob/master/API.md
-            match self.network {
-                Network::Bitcoin => "https://blockstream.info/api",
-                Network::Testnet => "https://blockstream.info/testnet/api",
-                Network::Signet => todo!(),
-                Network::Regtest => todo!(),
-                _ => todo!(),
+            let amount: u64 = recipients.iter().map(|(_, amount)| amount).sum();
+            if amount > confirmed {
+                return Err("Confirmed balance less than withdraw amount");
             }
-        };
 
-        let network = self.network.to_string();
-        let mut cli_args = vec![
-            "--network",
-            &network,
-            "wallet",
-            "--server",
-            server,
-            // "--stop_gap", // Slows down electrum
-            // "1",
-            // "--conc",
-            // "8",
-            "--timeout",
-            "15",
-            // "--wallet",
-            // "wallet_name_temp", // TODO: config
-            "--descriptor",
-            descriptor,
-        ];
-        cli_args.extend_from_slice(args);
-        cli_args.into_iter().map(|s| s.to_owned()).collect()
+            // Trying to calculate `send_amount`. Creating test transaction to figure out fees.
+            // Then deduct the fees from the provided amount.
+            let recipient_scripts = recipient_scripts(network, &recipients)?;
+            let fee = test_tx_fee(&wallet, &recipient_scripts, amount, confirmed, fee_rate)?;
+
+            let vbytes = get_vbytes(fee, fee_rate);
+
+            Ok((fee, vbytes, confirmed))
+        })
+        .await
+        .map_err(|task_error| {
+            error!("Estimate fee task failed: {task_error}");
+            "Internal service error. Try again later"
+        })?
     }
 
-    pub async fn estimate_fee(
+    pub async fn onesig(
         &self,
         multi_descriptor_00: &str,
-        to_address: &str,
-        amount: &str,
+        recipients: &[(String, u64)],
         fee_rate: FeeRate,
-    ) -> Result<(u64, u64), &'static str> {
-        let estimate_fee_result = self
-            .with_temp_wallet_dir(|| async {
-                // bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 sync
-                let Return {
-                    was_cached,
-                    value: sync_output,
-                } = execute_wallet_sync(&self, multi_descriptor_00).await;
-                info!("sync was_cached: {was_cached:?}");
-                info!("sync output: {sync_output:?}");
-
-                // bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 get_balance | jq
-                let get_balance_result = exec_with_json_output(
-                    self.wallet_args(&multi_descriptor_00, &["get_balance"])
-                        .iter(),
-                    self.cli_path.as_ref(),
-                )
-                .await;
-                info!("get_balance_result: {get_balance_result:?}");
-
-                let confirmed = get_balance_result["satoshi"]["confirmed"]
-                    .as_number()
-                    .unwrap()
-                    .as_u64()
-                    .unwrap();
-                if confirmed == 0 {
-                    return Err("Confirmed balance is zero");
+        deduct_fee: bool,
+        quoted_fee: Option<u64>,
+    ) -> Result<OnesigOutput, &'static str> {
+        let multi_descriptor_00 = multi_descriptor_00.to_string();
+        let network = self.network;
+        let recipients = recipients.to_vec();
+        tokio::task::spawn_blocking(move || {
+            // bdk-cli wallet --descriptor $MULTI_DESCRIPTOR_00 sync
+            let wallet = sync_wallet(network, &multi_descriptor_00).map_err(|sync_error| {
+                error!("Wallet sync failed: {sync_error}");
+                "Wallet sync failed. Try again later"
+            })?;
+
+            let balance = wallet.get_balance().map_err(balance_error)?;
+            info!("balance: {balance:?}");
+
+            let prev_tx_in_process = balance.immature > 0 || balance.trusted_pending > 0 || balance.untrusted_pending > 0;
+
+            let confirmed = balance.confirmed;
+            if confirmed == 0 {
+                if prev_tx_in_process {
+                    return Err("Previous transaction if not confirmed yet. Confirmed balance is zero");
                 }
-                let amount: u64 = amount.parse().unwrap();
-                if amount > confirmed {
-                    return Err("Confirmed balance less than withdraw amount");
+                return Err("Confirmed balance is zero");
+            }
+            let amount: u64 = recipients.iter().map(|(_, amount)| amount).sum();
+            if amount > confirmed {
+                if prev_tx_in_process {
+                    return Err("Previous transaction if not confirmed yet. Confirmed balance less than withdraw amount");
                 }
+                return Err("Confirmed balance less than withdraw amount");
+            }
 
-                // export CHANGE_ID=$(bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 policies | jq -r ".external.id")
-                let change_id_ = exec_with_json_output(
-                    self.wallet_args(&multi_descriptor_00, &["policies"]).iter(),
-                    self.cli_path.as_ref(),
-                )
-                .await;
-                let change_id = change_id_["external"]["id"].as_str().unwrap();
-
-                // Trying to calculate `send_amount`. Creating test transaction to figure out fees.
-                // Then deduct the fees from the provided amount.
-                let test_fees_full_amount = try_exec_with_json_output(
-                    self.wallet_args(
-                        &multi_descriptor_00,
-                        &[
-                            "create_tx",
-                            "--to",
-                            &format!("{to_address}:{amount}"),
-                            "--external_policy",
-                            &format!("{{\"{change_id}\": [0,1,3]}}"),
-                            "--fee_rate",
-                            &format!("{}", fee_rate.as_sat_per_vb()),
-                        ],
-                    )
-                    .iter(),
-                    self.cli_path.as_ref(),
-                )
-                .await;
-                // Could fail with error message
-                let fee = match test_fees_full_amount {
-                    Ok(output_json) => {
-                        let details = &output_json["details"];
-                        let fee = details["fee"].as_number().unwrap().as_u64().unwrap();
-                        let sent = details["sent"].as_number().unwrap().as_u64().unwrap();
-                        let received = details["received"].as_number().unwrap().as_u64().unwrap();
-                        let output = sent.checked_sub(received).unwrap();
-                        assert_eq!(output, amount + fee);
-                        fee
-                    }
-                    Err(output) => {
-                        let stderr = String::from_utf8_lossy(&output.stderr);
-
-                        if stderr.contains("Output below the dust limit") {
-                            return Err("Output below the dust limit");
-                        }
-
-                        // Insufficient funds: 5000 sat available of 5147 sat needed
-                        let pattern = " sat available of ";
-                        let (before, after) = stderr.split_once(pattern).unwrap();
-                        let (_rest, sat_available) = before.rsplit_once(' ').unwrap();
-                        let (sat_needed, _rest) = after.split_once(' ').unwrap();
-                        let sat_available: u64 = sat_available.parse().unwrap();
-                        let sat_needed: u64 = sat_needed.parse().unwrap();
-                        if sat_available == 0 {
-                            return Err("Fee exceeds the available balance");
-                        }
-                        assert_eq!(sat_available, confirmed);
-
-                        let fee = sat_needed - amount;
-                        fee
-                    }
-                };
-
-                let vbytes = get_vbytes(fee, fee_rate);
-
-                Ok((fee, vbytes))
-            })
-            .await;
+            // Trying to calculate `send_amount`. Creating test transaction to figure out fees.
+            // Then deduct the fees from the provided amount.
+            let recipient_scripts = recipient_scripts(network, &recipients)?;
+            let fee = test_tx_fee(&wallet, &recipient_scripts, amount, confirmed, fee_rate)?;
+            // Quoted fee is deducted instead of the estimated one, so outputs are exactly as quoted
+            let fee = match quoted_fee {
+                Some(quoted_fee) if fee > quoted_fee => return Err("Fee is above quoted fee, get a new quote"),
+                Some(quoted_fee) if deduct_fee => quoted_fee,
+                _ => fee,
+            };
+
+            // Recipient receives exact amount, fee is paid on top of it
+            if !deduct_fee && amount + fee > confirmed {
+                if prev_tx_in_process {
+                    return Err("Previous transaction if not confirmed yet. Confirmed balance less than withdraw amount and fee");
+                }
+                return Err("Confirmed balance less than withdraw amount and fee");
+            }
+
+            // total_amount = send_amount + fee, fee is split between recipients pro rata
+            let amounts: Vec<u64> = recipients.iter().map(|(_, amount)| *amount).collect();
+            let fee_shares = if deduct_fee { split_fee(fee, &amounts) } else { vec![0; amounts.len()] };
+            let mut send_recipients = Vec::new();
+            for ((script, amount), fee_share) in recipient_scripts.into_iter().zip(fee_shares) {
+                if amount <= fee_share {
+                    return Err("Withdraw amount is less than fee");
+                }
+                send_recipients.push((script, amount - fee_share));
+            }
 
-        estimate_fee_result
+            info!("send_recipients: {send_recipients:?}");
+
+            // export UNSIGNED_PSBT=$(bdk-cli wallet --descriptor $MULTI_DESCRIPTOR_00 create_tx --to $TO_ADDRESS:$AMOUNT --external_policy "{\"$CHANGE_ID\": [0,1,3]}" --enable_rbf | jq -r '.psbt')
+            // Allow fee bump of stuck withdrawal
+            let (unsigned_psbt, details) = create_tx(&wallet, &send_recipients, fee_rate, true)
+                .map_err(create_tx_error)?;
+
+            // Coin selection of the final TX could differ from the test one
+            let fee = match (deduct_fee, details.fee) {
+                (true, _) => fee,
+                (false, Some(fee)) => fee,
+                (false, None) => return Err("Failed to create transaction"),
+            };
+
+            // export ONESIG_PSBT=$(bdk-cli wallet --descriptor $MULTI_DESCRIPTOR_00 sign --psbt $UNSIGNED_PSBT | jq -r '.psbt')
+            let onesig_psbt = sign_psbt(&wallet, unsigned_psbt);
+
+            Ok(OnesigOutput { onesig_psbt, fee })
+        })
+        .await
+        .map_err(|task_error| {
+            error!("Onesig task failed: {task_error}");
+            "Internal service error. Try again later"
+        })?
     }
 
-    pub async fn onesig(
+    /// Create and sign replacement of unconfirmed `txid` with higher `fee_rate`.
+    /// Additional fee is deducted from the `shrink_address` output, or from change if not set.
+    pub async fn bump_fee_onesig(
         &self,
         xprv_00: &str,
         xpub_01: &str,
         xpub_02: &str,
         xpub_03: &str,
-        to_address: &str,
-        amount: &str,
+        txid: &str,
+        shrink_address: Option<&str>,
         fee_rate: FeeRate,
-    ) -> Result<OnesigOutput, &'static str> {
+    ) -> Result<OnesigOutput, String> {
         let multi_descriptor_00 = self
             .get_multi_descriptor(xprv_00, xpub_01, xpub_02, xpub_03)
             .await;
 
-        let onesig_result = self
-            .with_temp_wallet_dir(|| async {
-                // bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 sync
-                let start_sync = Instant::now();
-                let sync_output = exec_with_json_output(
-                    self.wallet_args_online(&multi_descriptor_00, &["sync"])
-                        .iter(),
-                    self.cli_path.as_ref(),
-                )
-                .await;
-                info!("sync output: {:?}", sync_output);
-                info!("sync took: {:?}", start_sync.elapsed());
-
-                // bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 get_balance | jq
-                let get_balance_result = exec_with_json_output(
-                    self.wallet_args(&multi_descriptor_00, &["get_balance"])
-                        .iter(),
-                    self.cli_path.as_ref(),
-                )
-                .await;
-                info!("get_balance_result: {get_balance_result:?}");
-
-                let balance: GetBalanceOutput =
-                    serde_json::from_value(get_balance_result["satoshi"].clone()).unwrap();
-
-                let prev_tx_in_process = balance.immature > 0 || balance.trusted_pending > 0 || balance.untrusted_pending > 0;
-                
-                let confirmed = balance.confirmed;
-                if confirmed == 0 {
-                    if prev_tx_in_process {
-                        return Err("Previous transaction if not confirmed yet. Confirmed balance is zero");
-                    }
-                    return Err("Confirmed balance is zero");
-                }
-                let amount: u64 = amount.parse().unwrap();
-                if amount > confirmed {
-                    if prev_tx_in_process {
-                        return Err("Previous transaction if not confirmed yet. Confirmed balance less than withdraw amount");
-                    }
-                    return Err("Confirmed balance less than withdraw amount");
-                }
+        let network = self.network;
+        let txid = txid.to_string();
+        let shrink_address = shrink_address.map(str::to_string);
+        tokio::task::spawn_blocking(move || {
+            // Wallet should know the original TX
+            let wallet = sync_wallet(network, &multi_descriptor_00)
+                .map_err(|error| format!("sync failed: {error}"))?;
+
+            // bdk-cli wallet --descriptor $MULTI_DESCRIPTOR_00 bump_fee --txid $TXID --fee_rate $FEE_RATE --shrink $TO_ADDRESS
+            let txid =
+                Txid::from_str(&txid).map_err(|error| format!("bump_fee failed: {error}"))?;
+            let mut builder = wallet
+                .build_fee_bump(txid)
+                .map_err(|error| format!("bump_fee failed: {error}"))?;
+            builder.fee_rate(fee_rate);
+            if let Some(shrink_address) = shrink_address {
+                builder
+                    .allow_shrinking(address_script(network, &shrink_address)?)
+                    .map_err(|error| format!("bump_fee failed: {error}"))?;
+            }
+            let (unsigned_psbt, details) = builder
+                .finish()
+                .map_err(|error| format!("bump_fee failed: {error}"))?;
+            let fee = details.fee.ok_or("bump_fee failed: fee is unknown")?;
 
-                // export CHANGE_ID=$(bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 policies | jq -r ".external.id")
-                let change_id_ = exec_with_json_output(
-                    self.wallet_args(&multi_descriptor_00, &["policies"]).iter(),
-                    self.cli_path.as_ref(),
-                )
-                .await;
-                let change_id = change_id_["external"]["id"].as_str().unwrap();
-
-                // Trying to calculate `send_amount`. Creating test transaction to figure out fees.
-                // Then deduct the fees from the provided amount.
-                let test_fees_full_amount = try_exec_with_json_output(
-                    self.wallet_args(
-                        &multi_descriptor_00,
-                        &[
-                            "create_tx",
-                            "--to",
-                            &format!("{to_address}:{amount}"),
-                            "--external_policy",
-                            &format!("{{\"{change_id}\": [0,1,3]}}"),
-                            "--fee_rate",
-                            &format!("{}", fee_rate.as_sat_per_vb()),
-                        ],
-                    )
-                    .iter(),
-                    self.cli_path.as_ref(),
-                )
-                .await;
-                // Could fail with error message
-                let fee = match test_fees_full_amount {
-                    Ok(output_json) => {
-                        let details = &output_json["details"];
-                        let fee = details["fee"].as_number().unwrap().as_u64().unwrap();
-                        let sent = details["sent"].as_number().unwrap().as_u64().unwrap();
-                        let received = details["received"].as_number().unwrap().as_u64().unwrap();
-                        let output = sent.checked_sub(received).unwrap();
-                        assert_eq!(output, amount + fee);
-                        fee
-                    }
-                    Err(output) => {
-                        let stderr = String::from_utf8_lossy(&output.stderr);
-                        // Insufficient funds: 5000 sat available of 5147 sat needed
-                        let pattern = " sat available of ";
-                        let (before, after) = stderr.split_once(pattern).unwrap();
-                        let (_rest, sat_available) = before.rsplit_once(' ').unwrap();
-                        let (sat_needed, _rest) = after.split_once(' ').unwrap();
-                        let sat_available: u64 = sat_available.parse().unwrap();
-                        assert_eq!(sat_available, confirmed);
-
-                        let sat_needed: u64 = sat_needed.parse().unwrap();
-
-                        let fee = sat_needed - amount;
-                        fee
-                    }
-                };
-
-                // total_amount = send_amount + fee
-                let send_amount = amount - fee;
-
-                // Testing: Hardcoded
-                // let send_amount = 3000;
-
-                info!("send_amount: {send_amount}");
-
-                // export UNSIGNED_PSBT=$(bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 create_tx --to $TO_ADDRESS:$AMOUNT --external_policy "{\"$CHANGE_ID\": [0,1]}" | jq -r '.psbt')
-                let create_tx_result = exec_with_json_output(
-                    self.wallet_args(
-                        &multi_descriptor_00,
-                        &[
-                            "create_tx",
-                            "--to",
-                            &format!("{to_address}:{send_amount}"),
-                            "--external_policy",
-                            &format!("{{\"{change_id}\": [0,1,3]}}"),
-                            "--fee_rate",
-                            &format!("{}", fee_rate.as_sat_per_vb()),
-                        ],
-                    )
-                    .iter(),
-                    self.cli_path.as_ref(),
-                )
-                .await;
-
-                // let details = &create_tx_result["details"];
-                // let fee = details["fee"].as_number().unwrap().as_u64().unwrap();
-                // let sent = details["sent"].as_number().unwrap().as_u64().unwrap();
-                // let received = details["received"].as_number().unwrap().as_u64().unwrap();
-                // let output = sent.checked_sub(received).unwrap();
-                // assert_eq!(output, send_amount + fee);
-                // assert_eq!(output, amount);
-
-                let unsigned_psbt = create_tx_result["psbt"].as_str().unwrap();
-
-                // export ONESIG_PSBT=$(bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 sign --psbt $UNSIGNED_PSBT | jq -r '.psbt')
-                let onesig_psbt_ = exec_with_json_output(
-                    self.wallet_args(&multi_descriptor_00, &["sign", "--psbt", unsigned_psbt])
-                        .iter(),
-                    self.cli_path.as_ref(),
-                )
-                .await;
-                let onesig_psbt = onesig_psbt_["psbt"].as_str().unwrap().to_string();
-
-                Ok(OnesigOutput { onesig_psbt, fee })
-            })
+            let onesig_psbt = sign_psbt(&wallet, unsigned_psbt);
+
+            Ok(OnesigOutput { onesig_psbt, fee })
+        })
+        .await
+        .map_err(|task_error| format!("bump_fee task failed: {task_error}"))?
+    }
+
+    /// Create and sign TX spending all confirmed UTXOs of the wallet to its own `multi_address`
+    pub async fn consolidate_onesig(
+        &self,
+        xprv_00: &str,
+        xpub_01: &str,
+        xpub_02: &str,
+        xpub_03: &str,
+        multi_address: &str,
+        fee_rate: FeeRate,
+    ) -> Result<OnesigOutput, String> {
+        let multi_descriptor_00 = self
+            .get_multi_descriptor(xprv_00, xpub_01, xpub_02, xpub_03)
             .await;
 
-        onesig_result
+        let network = self.network;
+        let multi_address = multi_address.to_string();
+        tokio::task::spawn_blocking(move || {
+            let wallet = sync_wallet(network, &multi_descriptor_00)
+                .map_err(|error| format!("sync failed: {error}"))?;
+
+            // bdk-cli wallet --descriptor $MULTI_DESCRIPTOR_00 create_tx --send_all --to $MULTI_ADDRESS:0 --external_policy "{\"$CHANGE_ID\": [0,1,3]}" --fee_rate $FEE_RATE --enable_rbf
+            let policy_path = external_policy_path(&wallet)
+                .map_err(|error| format!("create_tx failed: {error}"))?;
+            let mut builder = wallet.build_tx();
+            builder
+                .drain_wallet()
+                .drain_to(address_script(network, &multi_address)?)
+                .policy_path(policy_path, KeychainKind::External)
+                .fee_rate(fee_rate)
+                .enable_rbf();
+            let (unsigned_psbt, details) = builder
+                .finish()
+                .map_err(|error| format!("create_tx failed: {error}"))?;
+            let fee = details.fee.ok_or("create_tx failed: fee is unknown")?;
+
+            let onesig_psbt = sign_psbt(&wallet, unsigned_psbt);
+
+            Ok(OnesigOutput { onesig_psbt, fee })
+        })
+        .await
+        .map_err(|task_error| format!("create_tx task failed: {task_error}"))?
     }
 
     pub async fn secondsig(
@@ -500,42 +372,20 @@ impl BdkCli {
         xpub_03: &str,
         onesig_psbt: &str,
         key_arn: &str,
-    ) -> String {
-        // export MULTI_DESCRIPTOR_01=$(cat multi_descriptor_01.json)
-        // export ONESIG_PSBT=$(cat onesig_psbt.json)
-        // export KEY_ARN="arn:aws:kms:us-east-2:571922870935:key/17be5d9e-d752-4350-bbc1-68993fa25a4f"
-
-        let multi_descriptor_01 = self
-            .get_pub_multi_descriptor(xpub_00, xpub_01, xpub_02, xpub_03)
-            .await;
+    ) -> anyhow::Result<String> {
+        let KmsSignOutput { psbt: secondsig_psbt, .. } = self
+            .aws_kms_sign(xpub_00, xpub_01, xpub_02, xpub_03, onesig_psbt, key_arn)
+            .await?;
 
-        // export SECONDSIG_PSBT=$(./bdk-cli/target/release/bdk-cli wallet --aws_kms $KEY_ARN --wallet wallet_name_msd01 --descriptor $MULTI_DESCRIPTOR_01 sign --psbt $ONESIG_PSBT | jq -r '.psbt')
-
-        let result = self
-            .with_temp_wallet_dir(|| async {
-                let secondsig_psbt_ = exec_with_json_output(
-                    self.wallet_args(
-                        &multi_descriptor_01,
-                        &["--aws_kms", key_arn, "sign", "--psbt", onesig_psbt],
-                    )
-                    .iter(),
-                    self.cli_path_patched.as_ref(),
-                )
-                .await;
-                let secondsig_psbt = secondsig_psbt_["psbt"].as_str().unwrap();
-
-                if onesig_psbt == secondsig_psbt {
-                    return Err("Secondsig don't change PSBT");
-                }
+        if onesig_psbt == secondsig_psbt {
+            bail!("Secondsig don't change PSBT");
+        }
 
-                // if !secondsig_psbt_["is_finalized"].as_bool().unwrap() {
-                //     return Err("ERROR: Still not finalized after secondsig");
-                // }
+        // if !secondsig_psbt_["is_finalized"].as_bool().unwrap() {
+        //     return Err("ERROR: Still not finalized after secondsig");
+        // }
 
-                Ok(secondsig_psbt.to_string())
-            })
-            .await;
-        result.unwrap()
+        Ok(secondsig_psbt)
     }
 
     pub async fn thirdsig(
@@ -546,120 +396,337 @@ impl BdkCli {
         xpub_03: &str,
         secondsig_psbt: &str,
         key_name: &str,
-    ) -> String {
-        // export MULTI_DESCRIPTOR_01=$(cat multi_descriptor_01.json)
-        // export ONESIG_PSBT=$(cat onesig_psbt.json)
-        // export KEY_ARN="arn:aws:kms:us-east-2:571922870935:key/17be5d9e-d752-4350-bbc1-68993fa25a4f"
+    ) -> anyhow::Result<String> {
+        let KmsSignOutput { psbt: thirdsig_psbt, is_finalized } = self
+            .google_kms_sign(xpub_00, xpub_01, xpub_02, xpub_03, secondsig_psbt, key_name)
+            .await?;
 
-        let pub_multi_descriptor = self
-            .get_pub_multi_descriptor(xpub_00, xpub_01, xpub_02, xpub_03)
-            .await;
+        if secondsig_psbt == thirdsig_psbt {
+            bail!("Thirdsig don't change PSBT");
+        }
 
-        // export SECONDSIG_PSBT=$(./bdk-cli/target/release/bdk-cli wallet --aws_kms $KEY_ARN --wallet wallet_name_msd01 --descriptor $MULTI_DESCRIPTOR_01 sign --psbt $ONESIG_PSBT | jq -r '.psbt')
-
-        let result = self
-            .with_temp_wallet_dir(|| async {
-                let key_name: Cow<str> = if !key_name.contains("/cryptoKeyVersions/") {
-                    let mut key_name = key_name.to_string();
-                    key_name.push_str("/cryptoKeyVersions/1");
-                    key_name.into()
-                } else {
-                    key_name.into()
-                };
-
-                let thirdsig_psbt_ = exec_with_json_output(
-                    self.wallet_args(
-                        &pub_multi_descriptor,
-                        &["--google_kms", &key_name, "sign", "--psbt", secondsig_psbt],
-                    )
-                    .iter(),
-                    self.cli_path_patched.as_ref(),
-                )
-                .await;
-                let thirdsig_psbt = thirdsig_psbt_["psbt"].as_str().unwrap();
-
-                if secondsig_psbt == thirdsig_psbt {
-                    return Err("Thirdsig don't change PSBT");
-                }
+        if !is_finalized {
+            bail!("Still not finalized after thirdsig");
+        }
 
-                if !thirdsig_psbt_["is_finalized"].as_bool().unwrap() {
-                    return Err("ERROR: Still not finalized after secondsig");
-                }
+        Ok(thirdsig_psbt)
+    }
 
-                Ok(thirdsig_psbt.to_string())
-            })
+    /// Sign inputs of the descriptor by AWS KMS key. PSBT could have inputs of other descriptors
+    pub async fn aws_kms_sign(
+        &self,
+        xpub_00: &str,
+        xpub_01: &str,
+        xpub_02: &str,
+        xpub_03: &str,
+        psbt: &str,
+        key_arn: &str,
+    ) -> anyhow::Result<KmsSignOutput> {
+        let multi_descriptor_01 = self
+            .get_pub_multi_descriptor(xpub_00, xpub_01, xpub_02, xpub_03)
             .await;
-        result.unwrap()
+
+        // Key 01 is the AWS KMS key
+        self.kms_sign(&multi_descriptor_01, psbt, xpub_01, KmsKey::Aws(key_arn))
+            .await
+            .context("AWS KMS sign failed")
     }
 
-    pub async fn send(
+    /// Sign inputs of the descriptor by Google KMS key. PSBT could have inputs of other descriptors
+    pub async fn google_kms_sign(
         &self,
         xpub_00: &str,
         xpub_01: &str,
         xpub_02: &str,
         xpub_03: &str,
-        thirdsig_psbt: &str,
-    ) -> String {
+        psbt: &str,
+        key_name: &str,
+    ) -> anyhow::Result<KmsSignOutput> {
         let pub_multi_descriptor = self
             .get_pub_multi_descriptor(xpub_00, xpub_01, xpub_02, xpub_03)
             .await;
 
+        let key_name: Cow<str> = if !key_name.contains("/cryptoKeyVersions/") {
+            let mut key_name = key_name.to_string();
+            key_name.push_str("/cryptoKeyVersions/1");
+            key_name.into()
+        } else {
+            key_name.into()
+        };
+
+        // Key 03 is the Google KMS key
+        self.kms_sign(
+            &pub_multi_descriptor,
+            psbt,
+            xpub_03,
+            KmsKey::Google(&key_name),
+        )
+        .await
+        .context("Google KMS sign failed")
+    }
+
+    /// Add signature of KMS key `pubkey` to each input with the key in its witness script,
+    /// then finalize inputs of `pub_multi_descriptor` which have enough signatures
+    async fn kms_sign(
+        &self,
+        pub_multi_descriptor: &str,
+        psbt: &str,
+        pubkey: &str,
+        kms_key: KmsKey<'_>,
+    ) -> anyhow::Result<KmsSignOutput> {
+        let mut psbt = PartiallySignedTransaction::from_str(psbt)?;
+        let pubkey = PublicKey::from_str(pubkey)?;
+
+        let mut sighashes = Vec::new();
+        let mut sighash_cache = SighashCache::new(&psbt.unsigned_tx);
+        for (index, input) in psbt.inputs.iter().enumerate() {
+            if input.final_script_witness.is_some() || input.partial_sigs.contains_key(&pubkey) {
+                continue;
+            }
+            let (Some(witness_script), Some(utxo)) = (&input.witness_script, &input.witness_utxo)
+            else {
+                continue;
+            };
+            if !script_has_key(witness_script, &pubkey) {
+                continue;
+            }
+            let hash_ty = input
+                .sighash_type
+                .map(|sighash_type| sighash_type.ecdsa_hash_ty())
+                .transpose()?
+                .unwrap_or(EcdsaSighashType::All);
+            let sighash =
+                sighash_cache.segwit_signature_hash(index, witness_script, utxo.value, hash_ty)?;
+            sighashes.push((index, hash_ty, sighash.to_byte_array()));
+        }
+
+        let secp = Secp256k1::verification_only();
+        for (index, hash_ty, digest) in sighashes {
+            let der_signature = kms_key.sign(digest.to_vec()).await?;
+            let mut sig = secp256k1::ecdsa::Signature::from_der(&der_signature)?;
+            // KMS signature could have high S, which is non-standard
+            sig.normalize_s();
+            secp.verify_ecdsa(&Message::from_slice(&digest)?, &sig, &pubkey.inner)?;
+            psbt.inputs[index]
+                .partial_sigs
+                .insert(pubkey, ecdsa::Signature { sig, hash_ty });
+        }
+
+        let wallet = Wallet::new(
+            pub_multi_descriptor,
+            None,
+            self.network,
+            MemoryDatabase::default(),
+        )?;
+        let is_finalized = wallet.finalize_psbt(&mut psbt, SignOptions::default())?;
+
+        Ok(KmsSignOutput {
+            psbt: psbt.to_string(),
+            is_finalized,
+        })
+    }
+
+    pub async fn send(
+        &self,
+        _xpub_00: &str,
+        _xpub_01: &str,
+        _xpub_02: &str,
+        _xpub_03: &str,
+        thirdsig_psbt: &str,
+    ) -> anyhow::Result<String> {
         // # broadcast
-        // export TX_ID=$(bdk-cli wallet --wallet wallet_name_msd01 --descriptor $MULTI_DESCRIPTOR_01 broadcast --psbt $SECONDSIG_PSBT)
-        // echo $TX_ID
-        self.with_temp_wallet_dir(|| async {
-            let tx_id_ = exec_with_json_output(
-                self.wallet_args_online(
-                    &pub_multi_descriptor,
-                    &["broadcast", "--psbt", thirdsig_psbt],
-                )
-                .iter(),
-                self.cli_path.as_ref(),
-            )
-            .await;
-            info!("broadcast output: {tx_id_:?}");
-            let tx_id = tx_id_["txid"].as_str().unwrap().to_string();
+        // export TX_ID=$(bdk-cli wallet --descriptor $MULTI_DESCRIPTOR_01 broadcast --psbt $SECONDSIG_PSBT)
+        let tx = PartiallySignedTransaction::from_str(thirdsig_psbt)?.extract_tx();
+        let network = self.network;
+        tokio::task::spawn_blocking(move || -> anyhow::Result<String> {
+            let blockchain = ElectrumBlockchain::from(Client::new(electrum_server(network))?);
+            blockchain.broadcast(&tx).context("broadcast failed")?;
+            let tx_id = tx.txid().to_string();
+            info!("broadcast TX: {tx_id}");
 
             // echo "Check: https://mempool.space/testnet/tx/$(echo $TX_ID | jq -r ".txid")"
-            tx_id
+            Ok(tx_id)
         })
-        .await
+        .await?
     }
+}
+
+/// KMS key of a cosigner
+enum KmsKey<'a> {
+    Aws(&'a str),
+    Google(&'a str),
+}
 
-    async fn remove_temp_wallet_dir(&self) {
-        // FIXME: enable deletion?
-        if let Some(temp_wallet_dir) = &self.temp_wallet_dir {
-            let _ = remove_dir_all(temp_wallet_dir).await;
+impl KmsKey<'_> {
+    /// DER signature of the `digest`
+    async fn sign(&self, digest: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match self {
+            KmsKey::Aws(key_arn) => Ok(kms_sign::sign_aws_key(key_arn, digest).await?),
+            KmsKey::Google(key_name) => kms_sign::sign_google_key(key_name, digest)
+                .await
+                .map_err(|error| anyhow!(error)),
         }
     }
+}
 
-    pub async fn with_temp_wallet_dir<T, F: Future<Output = T>>(&self, f: impl FnOnce() -> F) -> T {
-        let wallet_dir_permit = WALLET_DIR_PERMIT.acquire().await.unwrap();
-        self.remove_temp_wallet_dir().await;
-        let result = f().await;
-        self.remove_temp_wallet_dir().await;
-        drop(wallet_dir_permit);
-        result
+/// bdk-cli compile: segwit v0 descriptor of the policy, with checksum
+fn compile_policy(policy: &str) -> String {
+    let policy = Concrete::<String>::from_str(policy).unwrap();
+    let miniscript = policy.compile::<Segwitv0>().unwrap();
+    Descriptor::new_wsh(miniscript).unwrap().to_string()
+}
+
+/// Wallet of the descriptor synced with Electrum server. Blocking
+fn sync_wallet(network: Network, descriptor: &str) -> Result<Wallet<MemoryDatabase>, Error> {
+    let start_sync = Instant::now();
+    let wallet = Wallet::new(descriptor, None, network, MemoryDatabase::default())?;
+    let blockchain = ElectrumBlockchain::from(Client::new(electrum_server(network))?);
+    wallet.sync(&blockchain, SyncOptions::default())?;
+    info!("sync took: {:?}", start_sync.elapsed());
+    Ok(wallet)
+}
+
+/// `--external_policy {"<id>": [0,1,3]}`: keys 00, 01 (AWS KMS) and 03 (Google KMS) sign,
+/// ledger key 02 is not used
+fn external_policy_path(
+    wallet: &Wallet<MemoryDatabase>,
+) -> Result<BTreeMap<String, Vec<usize>>, Error> {
+    let policy = wallet
+        .policies(KeychainKind::External)?
+        .ok_or_else(|| Error::Generic("descriptor has no spending policy".to_string()))?;
+    Ok(BTreeMap::from([(policy.id, vec![0, 1, 3])]))
+}
+
+/// `create_tx` with `--to` of each recipient and external policy of the wallet
+fn create_tx(
+    wallet: &Wallet<MemoryDatabase>,
+    recipients: &[(ScriptBuf, u64)],
+    fee_rate: FeeRate,
+    enable_rbf: bool,
+) -> Result<(PartiallySignedTransaction, TransactionDetails), Error> {
+    let mut builder = wallet.build_tx();
+    builder
+        .set_recipients(recipients.to_vec())
+        .policy_path(external_policy_path(wallet)?, KeychainKind::External)
+        .fee_rate(fee_rate);
+    if enable_rbf {
+        builder.enable_rbf();
     }
+    builder.finish()
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CliGenerateKeyResult {
-    pub fingerprint: String,
-    pub mnemonic: String,
-    pub xprv: String,
-    pub xpub: String,
+/// Fee of test TX sending `amount` to recipients. If confirmed balance doesn't cover
+/// `amount` and fee, it's the fee of spending the whole balance
+fn test_tx_fee(
+    wallet: &Wallet<MemoryDatabase>,
+    recipients: &[(ScriptBuf, u64)],
+    amount: u64,
+    confirmed: u64,
+    fee_rate: FeeRate,
+) -> Result<u64, &'static str> {
+    match create_tx(wallet, recipients, fee_rate, false) {
+        Ok((_, details)) => {
+            let fee = details.fee.ok_or("Failed to create transaction")?;
+            if details.sent.checked_sub(details.received) != Some(amount + fee) {
+                error!("Test TX details {details:?} don't match amount {amount} sat");
+                return Err("Failed to create transaction");
+            }
+            Ok(fee)
+        }
+        Err(Error::InsufficientFunds { available: 0, .. }) => {
+            Err("Fee exceeds the available balance")
+        }
+        Err(Error::InsufficientFunds { needed, available }) => {
+            if available != confirmed {
+                error!("Available {available} sat is different from confirmed balance {confirmed} sat");
+                return Err("Confirmed balance is changed. Try again later");
+            }
+            Ok(needed - amount)
+        }
+        Err(error) => Err(create_tx_error(error)),
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct GenerateKeyInnerResult {
-    pub fingerprint: String,
-    pub mnemonic: String,
-    pub xprv: String,
+/// Response message of `create_tx` error, unexpected errors are logged
+fn create_tx_error(error: Error) -> &'static str {
+    match error {
+        Error::OutputBelowDustLimit(_) => "Output below the dust limit",
+        Error::InsufficientFunds { .. } => "Confirmed balance less than withdraw amount and fee",
+        error => {
+            error!("create_tx failed: {error}");
+            "Failed to create transaction"
+        }
+    }
+}
+
+fn balance_error(error: Error) -> &'static str {
+    error!("Wallet balance failed: {error}");
+    "Internal service error. Try again later"
+}
+
+/// Sign by the private key 00 of the wallet
+fn sign_psbt(wallet: &Wallet<MemoryDatabase>, mut psbt: PartiallySignedTransaction) -> String {
+    wallet.sign(&mut psbt, SignOptions::default()).unwrap();
+    psbt.to_string()
+}
+
+fn address_script(network: Network, address: &str) -> Result<ScriptBuf, &'static str> {
+    let address = Address::from_str(address)
+        .ok()
+        .and_then(|address| address.require_network(network).ok())
+        .ok_or("Invalid recipient address")?;
+    Ok(address.script_pubkey())
+}
+
+fn recipient_scripts(
+    network: Network,
+    recipients: &[(String, u64)],
+) -> Result<Vec<(ScriptBuf, u64)>, &'static str> {
+    recipients
+        .iter()
+        .map(|(to_address, amount)| Ok((address_script(network, to_address)?, *amount)))
+        .collect()
+}
+
+fn script_has_key(script: &Script, pubkey: &PublicKey) -> bool {
+    let pubkey = pubkey.to_bytes();
+    script.instructions().any(|instruction| {
+        matches!(instruction, Ok(Instruction::PushBytes(bytes)) if bytes.as_bytes() == pubkey.as_slice())
+    })
+}
+
+/// Split `fee` between recipients in proportion to their amounts.
+/// Rounding remainder is paid by the first recipients, one sat each.
+pub fn split_fee(fee: u64, amounts: &[u64]) -> Vec<u64> {
+    let total: u64 = amounts.iter().sum();
+    if total == 0 {
+        return vec![0; amounts.len()];
+    }
+    let mut shares: Vec<u64> = amounts
+        .iter()
+        .map(|amount| (fee as u128 * *amount as u128 / total as u128) as u64)
+        .collect();
+    let remainder = fee - shares.iter().sum::<u64>();
+    for share in shares.iter_mut().take(remainder as usize) {
+        *share += 1;
+    }
+    shares
+}
+
+pub fn electrum_server(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "ssl://electrum.blockstream.info:50002",
+        Network::Testnet => "ssl://electrum.blockstream.info:60002",
+        Network::Signet => todo!(),
+        Network::Regtest => todo!(),
+        _ => todo!(),
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct GetPubkeyInnerResult {
+pub struct CliGenerateKeyResult {
+    pub fingerprint: String,
+    pub mnemonic: String,
     pub xprv: String,
     pub xpub: String,
 }
@@ -670,30 +737,79 @@ pub struct OnesigOutput {
     pub fee: u64,
 }
 
-#[derive(Debug, Deserialize)]
-struct GetBalanceOutput {
-    pub confirmed: u64,
-    pub immature: u64,
-    pub trusted_pending: u64,
-    pub untrusted_pending: u64,
-}
+type SharedWallet = Arc<Mutex<Wallet<MemoryDatabase>>>;
 
 #[cached(
     sync_writes = true,
+    result = true,
     with_cached_flag = true,
-    ty = "TimedCache<String, Return<serde_json::Value>>",
+    ty = "TimedCache<String, Return<SharedWallet>>",
     create = "{ TimedCache::with_lifespan(60) }",
     convert = r#"{ multi_descriptor_00.to_string() }"#
 )]
-async fn execute_wallet_sync(cli: &BdkCli, multi_descriptor_00: &str) -> Return<serde_json::Value> {
-    // bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 sync
-    let start_sync = Instant::now();
-    let sync_output = exec_with_json_output(
-        cli.wallet_args_online(multi_descriptor_00, &["sync"])
-            .iter(),
-        cli.cli_path.as_ref(),
-    )
-    .await;
-    info!("sync took: {:?}", start_sync.elapsed());
-    Return::new(sync_output)
-}
\ No newline at end of file
+async fn synced_wallet(
+    network: Network,
+    multi_descriptor_00: &str,
+) -> Result<Return<SharedWallet>, String> {
+    let multi_descriptor_00 = multi_descriptor_00.to_string();
+    // Electrum sync of BDK wallet is blocking
+    let wallet = tokio::task::spawn_blocking(move || sync_wallet(network, &multi_descriptor_00))
+        .await
+        .map_err(|task_error| task_error.to_string())?
+        .map_err(|sync_error| sync_error.to_string())?;
+    Ok(Return::new(Arc::new(Mutex::new(wallet))))
+}
+
+#[derive(Debug, Clone)]
+pub struct KmsSignOutput {
+    pub psbt: String,
+    /// All inputs of PSBT are finalized
+    pub is_finalized: bool,
+}
+
+#[test]
+fn test_split_fee() {
+    assert_eq!(split_fee(300, &[1000]), vec![300]);
+    assert_eq!(split_fee(300, &[1000, 2000]), vec![100, 200]);
+    assert_eq!(split_fee(100, &[1000, 1000, 1000]), vec![34, 33, 33]);
+    assert_eq!(split_fee(0, &[1000, 2000]), vec![0, 0]);
+}
+
+/// Keys, descriptors and address are the same as `bdk-cli` 0.27.1 ones in comments of `BdkCli`
+#[tokio::test]
+async fn test_bdk_cli_compatibility() {
+    let bdk_cli = BdkCli::new(Network::Testnet);
+    // bdk-cli key restore --mnemonic "abandon ... about"
+    let xprv_00 = "tprv8ZgxMBicQKsPe5YMU9gHen4Ez3ApihUfykaqUorj9t6FDqy3nP6eoXiAo2ssvpAjoLroQxHqr3R5nE3a5dU3DHTjTgJDd7zrbniJr6nrCzd";
+    let xpub_01 = "02002c5c77d7951eaa1818a7b409181b2e4a81e93e6eb44c6fe92c637c492725bb";
+    // bdk-cli key derive of key restored from mnemonic "zoo ... wrong"
+    let xpub_02 = "[3f635a63/84'/1'/0'/0]tpubDFC4gmFWYgfw3sTckVuzNDpVfcBfEd5VbiJ36GRcY9Pa5EkUQGwiUSGTWSmwKVYqQLChRFRjtfM91yDjsECZDt2jytgKeXRbPptfJDs2Y2b/*";
+    let xpub_03 = "036f0694a43f05fd642f1fe0b3bd023b1322df39080c5624a5ba8bede20fcd9dc2";
+
+    let xpub_00 = bdk_cli.get_pubkey(xprv_00).await;
+    assert_eq!(
+        xpub_00,
+        "[73c5da0a/84'/1'/0'/0]tpubDFd87GgwwqSRLStzXaBq4mJvFm8e9quGD5L9E5XMhtJnJUgFGErWJFwgBr9RLyXGdzDhfAChbKF6p2RaZsArrJAgAhTWNWFDWyDkshPRodD/*"
+    );
+
+    let multi_descriptor_00 = bdk_cli
+        .get_multi_descriptor(xprv_00, xpub_01, xpub_02, xpub_03)
+        .await;
+    assert_eq!(
+        multi_descriptor_00,
+        format!("wsh(multi(3,{xprv_00}/84h/1h/0h/0/*,{xpub_01},{xpub_02},{xpub_03}))#mzzcfrz6")
+    );
+
+    let multi_descriptor_01 = bdk_cli
+        .get_pub_multi_descriptor(&xpub_00, xpub_01, xpub_02, xpub_03)
+        .await;
+    assert_eq!(
+        multi_descriptor_01,
+        format!("wsh(multi(3,{xpub_00},{xpub_01},{xpub_02},{xpub_03}))#34hjnvcr")
+    );
+
+    // bdk-cli wallet --descriptor $MULTI_DESCRIPTOR_00 get_new_address
+    let address = "tb1q3zqhwcp22sthu27jzdn9jvu0e0vugdmz4rxlatvxwqzajf95sacszpzp0k";
+    assert_eq!(bdk_cli.get_multi_address(&multi_descriptor_00).await, address);
+    assert_eq!(bdk_cli.get_multi_address(&multi_descriptor_01).await, address);
+}
@@ -1,14 +1,18 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use btc_catchup::{
     get_btc_transactions, get_domi_transactions, BtcTransaction, BtcTransactionType,
     DomiTransaction,
 };
 use domichain_sdk::pubkey::Pubkey;
-use tracing::warn;
+use tracing::{error, warn};
 
 use crate::{
     db::DB,
+    mempool::TxStatus,
     watch_addresses::{process_confirmed_transaction, Confirmed, Vin, VinPrevout, Vout},
     AppState,
 };
@@ -92,32 +96,44 @@ pub async fn process_catchup(
         let vout = btc_tx
             .vout
             .into_iter()
-            .filter_map(|vout| {
-                vout.scriptpubkey_address.map(|scriptpubkey_address| Vout {
-                    scriptpubkey_address: scriptpubkey_address,
-                    value: vout.value,
-                })
+            .map(|vout| Vout {
+                scriptpubkey_address: vout.scriptpubkey_address,
+                value: vout.value,
             })
             .collect();
         let vin = btc_tx
             .vin
             .into_iter()
             .map(|vin| Vin {
-                prevout: VinPrevout {
-                    scriptpubkey_address: vin.prevout.scriptpubkey_address,
-                },
+                prevout: Some(VinPrevout {
+                    scriptpubkey_address: Some(vin.prevout.scriptpubkey_address),
+                }),
             })
             .collect();
         let confirmed_tx = Confirmed {
             txid: btc_tx.tx_id,
             vin,
             vout,
+            status: TxStatus {
+                confirmed: true,
+                block_height: Some(btc_tx.block),
+                block_hash: Some(btc_tx.block_hash),
+            },
         };
         assert!(matches!(btc_tx.tx_type, BtcTransactionType::Deposit));
         let multisig_address = btc_tx.to_address;
-        process_confirmed_transaction(app_state, &multisig_address, confirmed_tx).await;
+        let tx_hash = confirmed_tx.txid.clone();
+        if let Err(process_error) =
+            process_confirmed_transaction(app_state, &multisig_address, confirmed_tx).await
+        {
+            error!("Failed to process missed deposit TX {tx_hash}: {process_error:#}");
+        }
     }
 
+    // Below minimum deposits are minted together, amounts of single deposits don't match
+    let accumulated_tx_hashes: HashSet<String> =
+        HashSet::from_iter(app_state.db.get_accumulated_tx_hashes().await.unwrap());
+    amount_mismatch.retain(|(btc_tx, _domi_tx)| !accumulated_tx_hashes.contains(&btc_tx.tx_id));
     amount_mismatch.retain(|(btc_tx, _domi_tx)| {
         let skip_txs = [
             "f697db2d2962b976150aae2c2292fdb3df3938c82fe67327aa5600d29fa0d75f",
@@ -0,0 +1,598 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::bail;
+use btc_catchup::{get_domi_transactions, DomiMint, DomiTransaction};
+use clap::ValueEnum;
+use domichain_sdk::pubkey::Pubkey;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use serde_json::json;
+use tokio::time::interval;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    alert::send_alert,
+    db::DepositStatus,
+    mempool::{get_tip_height, get_tx_status, TxStatus},
+    mint_token::{get_account_address, mint_token_inner},
+    AppState,
+};
+
+const TRACKER_INTERVAL: Duration = Duration::from_secs(60);
+/// Minting deposits younger than this could still have mint transaction in flight
+const MINTING_RECOVERY_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// Required confirmations depending on deposit amount.
+///
+/// Format: `<below_sat>:<confirmations>,...,<confirmations>`.
+/// Example: `1000000:1,100000000:3,6` — 1 conf under 0.01 BTC, 3 under 1 BTC, 6 above.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfirmationPolicy {
+    /// Sorted by amount threshold
+    thresholds: Vec<(u64, u64)>,
+    default_confirmations: u64,
+}
+
+impl ConfirmationPolicy {
+    pub fn required_confirmations(&self, value: u64) -> u64 {
+        self.thresholds
+            .iter()
+            .find_map(|&(below, confirmations)| (value < below).then_some(confirmations))
+            .unwrap_or(self.default_confirmations)
+    }
+}
+
+impl FromStr for ConfirmationPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_u64 = |v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid number {v:?}: {e}"))
+        };
+
+        let mut thresholds = Vec::new();
+        let mut default_confirmations = None;
+        for part in s.split(',') {
+            if default_confirmations.is_some() {
+                return Err("default confirmations should be the last entry".to_string());
+            }
+            match part.split_once(':') {
+                Some((below, confirmations)) => {
+                    thresholds.push((parse_u64(below)?, parse_u64(confirmations)?));
+                }
+                None => default_confirmations = Some(parse_u64(part)?),
+            }
+        }
+        let default_confirmations =
+            default_confirmations.ok_or("default confirmations is missing")?;
+        if !thresholds.windows(2).all(|w| w[0].0 < w[1].0) {
+            return Err("amount thresholds should be increasing".to_string());
+        }
+        if thresholds
+            .iter()
+            .map(|(_, c)| c)
+            .chain([&default_confirmations])
+            .any(|&c| c == 0)
+        {
+            return Err("confirmations should be positive".to_string());
+        }
+
+        Ok(Self {
+            thresholds,
+            default_confirmations,
+        })
+    }
+}
+
+/// What to do with confirmed deposits below `MIN_DEPOSIT_AMOUNT`
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum BelowMinimumPolicy {
+    /// Mint once total of below minimum deposits to the address crosses the minimum
+    Accumulate,
+    /// Keep for operator
+    Manual,
+}
+
+/// Mint BTCi for pending deposits which reached required confirmations depth
+/// and re-validate recently minted deposits against chain reorganisations
+pub async fn track_confirmations(state: AppState) {
+    let mut tracker_interval = interval(TRACKER_INTERVAL);
+    loop {
+        tracker_interval.tick().await;
+        if let Err(error) = recover_minting_deposits(&state).await {
+            error!("Minting recovery error: {error:#}");
+        }
+        if let Err(error) = check_pending_deposits(&state).await {
+            error!("Confirmation tracker error: {error:#}");
+        }
+        if state.config.below_minimum_policy == BelowMinimumPolicy::Accumulate {
+            if let Err(error) = accumulate_below_minimum_deposits(&state).await {
+                error!("Below minimum deposits accumulation error: {error:#}");
+            }
+        }
+        if let Err(error) = check_minted_deposits(&state).await {
+            error!("Reorg tracker error: {error:#}");
+        }
+    }
+}
+
+/// Update confirmations of mempool and pending deposits and mint ones deep enough
+async fn check_pending_deposits(state: &AppState) -> anyhow::Result<()> {
+    let btc_network = state.config.btc_network;
+
+    let mut pending = state
+        .db
+        .find_deposits_by_status(DepositStatus::Mempool)
+        .await?;
+    pending.extend(
+        state
+            .db
+            .find_deposits_by_status(DepositStatus::Pending)
+            .await?,
+    );
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let tip_height = get_tip_height(btc_network).await?;
+
+    for deposit in pending {
+        let tx_hash = deposit.get_str("tx_hash")?;
+        let tx_status = match get_tx_status(btc_network, tx_hash).await {
+            Ok(Some(tx_status)) => tx_status,
+            Ok(None) => {
+                warn!("Pending deposit TX {tx_hash} is not found");
+                if deposit.get_str("status")? == DepositStatus::Mempool.as_str() {
+                    state.db.drop_mempool_deposits(tx_hash).await?;
+                }
+                continue;
+            }
+            Err(error) => {
+                error!("Failed to get deposit TX {tx_hash} status: {error:#}");
+                continue;
+            }
+        };
+        let (Some(block_height), Some(block_hash)) = (tx_status.block_height, tx_status.block_hash)
+        else {
+            // Could be unconfirmed again after reorg. Not minted yet, just wait
+            info!("Deposit TX {tx_hash} is not confirmed");
+            continue;
+        };
+        let confirmations = (tip_height + 1).saturating_sub(block_height);
+        let required_confirmations = deposit.get_i64("required_confirmations")? as u64;
+
+        state
+            .db
+            .update_tx(
+                deposit.get("_id").unwrap().clone(),
+                doc! {
+                    "status": DepositStatus::Pending.as_str(),
+                    "confirmed": true,
+                    "block_height": block_height as i64,
+                    "block_hash": &block_hash,
+                    "confirmations": confirmations as i64,
+                },
+            )
+            .await?;
+
+        if confirmations < required_confirmations {
+            info!("Deposit TX {tx_hash}: {confirmations}/{required_confirmations} confirmations");
+            continue;
+        }
+        let mint_value: u64 = deposit_mint_value(&deposit)?.parse()?;
+        if mint_value < state.config.min_deposit_amount {
+            warn!("Deposit TX {tx_hash} of {mint_value} sat is below minimum");
+            state
+                .db
+                .update_tx(
+                    deposit.get("_id").unwrap().clone(),
+                    doc! { "status": DepositStatus::BelowMinimum.as_str() },
+                )
+                .await?;
+            if state.config.below_minimum_policy == BelowMinimumPolicy::Manual {
+                send_alert(
+                    &state.config,
+                    "Deposit below minimum requires manual handling",
+                    json!({
+                        "tx_hash": tx_hash,
+                        "multi_address": deposit.get_str("multi_address").ok(),
+                        "value": mint_value,
+                        "min_deposit_amount": state.config.min_deposit_amount,
+                    }),
+                )
+                .await;
+            }
+            continue;
+        }
+        if let Err(error) = mint_deposit(state, &deposit).await {
+            error!("Failed to mint deposit TX {tx_hash}: {error:#}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that recently minted deposits are still in the best chain.
+/// Deposits disappeared from the chain are quarantined because their BTCi is unbacked.
+async fn check_minted_deposits(state: &AppState) -> anyhow::Result<()> {
+    let btc_network = state.config.btc_network;
+    let tip_height = get_tip_height(btc_network).await?;
+    let since_height = tip_height.saturating_sub(state.config.reorg_check_depth);
+
+    let mut deposits = state.db.find_minted_deposits_since(since_height).await?;
+    deposits.extend(
+        state
+            .db
+            .find_deposits_by_status(DepositStatus::Quarantined)
+            .await?,
+    );
+
+    for deposit in deposits {
+        let id = deposit.get("_id").unwrap().clone();
+        let tx_hash = deposit.get_str("tx_hash")?;
+        let recorded_block_hash = deposit.get_str("block_hash").ok();
+        let is_quarantined = deposit.get_str("status")? == DepositStatus::Quarantined.as_str();
+
+        let tx_status = match get_tx_status(btc_network, tx_hash).await {
+            Ok(tx_status) => tx_status,
+            Err(error) => {
+                error!("Failed to get deposit TX {tx_hash} status: {error:#}");
+                continue;
+            }
+        };
+
+        match tx_status {
+            Some(TxStatus {
+                confirmed: true,
+                block_height: Some(block_height),
+                block_hash: Some(block_hash),
+            }) => {
+                if recorded_block_hash != Some(block_hash.as_str()) {
+                    warn!(
+                        "Deposit TX {tx_hash} moved to another block: {recorded_block_hash:?} -> {block_hash}"
+                    );
+                }
+                let mut update = doc! {
+                    "block_height": block_height as i64,
+                    "block_hash": &block_hash,
+                    "confirmations": ((tip_height + 1).saturating_sub(block_height)) as i64,
+                };
+                if is_quarantined {
+                    update.insert("status", DepositStatus::Minted.as_str());
+                    send_alert(
+                        &state.config,
+                        "Quarantined deposit is confirmed again",
+                        json!({ "tx_hash": tx_hash, "block_hash": block_hash }),
+                    )
+                    .await;
+                }
+                state.db.update_tx(id, update).await?;
+            }
+            tx_status => {
+                if is_quarantined {
+                    continue;
+                }
+                let reason = if tx_status.is_some() {
+                    "deposit TX is unconfirmed after reorg"
+                } else {
+                    "deposit TX is dropped from the chain"
+                };
+                state
+                    .db
+                    .update_tx(
+                        id,
+                        doc! {
+                            "status": DepositStatus::Quarantined.as_str(),
+                            "quarantine_reason": reason,
+                            "quarantined_at": DateTime::now(),
+                        },
+                    )
+                    .await?;
+                send_alert(
+                    &state.config,
+                    "Minted deposit disappeared from the chain",
+                    json!({
+                        "reason": reason,
+                        "tx_hash": tx_hash,
+                        "block_hash": recorded_block_hash,
+                        "multi_address": deposit.get_str("multi_address").ok(),
+                        "value": deposit.get_str("value").ok(),
+                        "mint_address": deposit.get_str("mint_address").ok(),
+                        "domi_address": deposit.get_str("domi_address").ok(),
+                    }),
+                )
+                .await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// BTCi amount to mint in sat: accumulated total or deposit value
+fn deposit_mint_value(deposit: &Document) -> mongodb::bson::document::ValueAccessResult<&str> {
+    deposit
+        .get_str("mint_value")
+        .or_else(|_| deposit.get_str("value"))
+}
+
+/// Merge below minimum deposits of each address once their total crosses the minimum.
+///
+/// The latest deposit carries the total in `mint_value` and is minted as usual,
+/// others are marked as `accumulated` into it.
+async fn accumulate_below_minimum_deposits(state: &AppState) -> anyhow::Result<()> {
+    let min_deposit_amount = state.config.min_deposit_amount;
+    let below_minimum = state
+        .db
+        .find_deposits_by_status(DepositStatus::BelowMinimum)
+        .await?;
+    if below_minimum.is_empty() {
+        return Ok(());
+    }
+    // Deposits accumulated into not yet promoted carrier, if previous run was interrupted
+    let accumulated = state
+        .db
+        .find_deposits_by_status(DepositStatus::Accumulated)
+        .await?;
+
+    let mut by_address: BTreeMap<String, Vec<&Document>> = BTreeMap::new();
+    for deposit in &below_minimum {
+        let multi_address = deposit.get_str("multi_address")?.to_string();
+        by_address.entry(multi_address).or_default().push(deposit);
+    }
+    let below_minimum_ids: HashSet<&Bson> = below_minimum
+        .iter()
+        .filter_map(|deposit| deposit.get("_id"))
+        .collect();
+    for deposit in &accumulated {
+        let orphan = deposit
+            .get("accumulated_into")
+            .is_some_and(|carrier_id| below_minimum_ids.contains(carrier_id));
+        if orphan {
+            let multi_address = deposit.get_str("multi_address")?.to_string();
+            by_address.entry(multi_address).or_default().push(deposit);
+        }
+    }
+
+    for (multi_address, deposits) in by_address {
+        let mut total = 0;
+        for deposit in &deposits {
+            total += deposit.get_str("value")?.parse::<u64>()?;
+        }
+        if total < min_deposit_amount {
+            debug!("Below minimum deposits to {multi_address}: {total}/{min_deposit_amount} sat");
+            continue;
+        }
+
+        // `_id` is ObjectId, so the last below minimum deposit is the latest one
+        let carrier = deposits
+            .iter()
+            .filter(|deposit| {
+                deposit.get_str("status").ok() == Some(DepositStatus::BelowMinimum.as_str())
+            })
+            .max_by_key(|deposit| deposit.get_object_id("_id").ok())
+            .unwrap();
+        let carrier_id = carrier.get("_id").unwrap().clone();
+        let accumulated_ids: Vec<Bson> = deposits
+            .iter()
+            .filter_map(|deposit| deposit.get("_id").cloned())
+            .filter(|id| *id != carrier_id)
+            .collect();
+        info!(
+            "Accumulated {} deposits to {multi_address}: {total} sat",
+            deposits.len(),
+        );
+
+        state
+            .db
+            .mark_deposits_accumulated(&accumulated_ids, carrier_id.clone())
+            .await?;
+        state
+            .db
+            .update_tx(
+                carrier_id,
+                doc! {
+                    "status": DepositStatus::Pending.as_str(),
+                    "mint_value": total.to_string(),
+                    "accumulated_deposits": accumulated_ids,
+                },
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Mint BTCi for a deposit record.
+///
+/// Deposit is claimed atomically (`pending` -> `minting`), so concurrent or repeated calls mint once.
+/// If mint result is unknown the deposit stays in `minting` state for [`recover_minting_deposits`].
+/// Operator returns it to `pending` if the mint never happened.
+pub async fn mint_deposit(state: &AppState, deposit: &Document) -> anyhow::Result<()> {
+    let db = &state.db;
+    let id = deposit.get("_id").unwrap().clone();
+    let multi_address = deposit.get_str("multi_address")?.to_string();
+    let value = deposit_mint_value(deposit)?.to_string();
+
+    // Find corresponding DOMI address
+    let Some(data) = db.find_by_deposit_address(&multi_address).await? else {
+        bail!("multisig address {multi_address} doesn't found");
+    };
+    let domi_address = data.get_str("domi_address")?.to_string();
+
+    if !db.claim_deposit_for_mint(id.clone()).await? {
+        info!("Deposit {id} is already claimed for mint");
+        return Ok(());
+    }
+
+    // Fail if:
+    // - network issue
+    // - insufficient balance
+    // - address already exists
+    let config = state.config.clone();
+    let mint_domi_address = domi_address.clone();
+    let mint_result =
+        tokio::spawn(async move { mint_token_inner(&config, &value, &mint_domi_address).await })
+            .await;
+    info!("mint_result: {mint_result:#?}");
+    let mint_result = match mint_result {
+        Ok(Ok(mint_result)) => mint_result,
+        Ok(Err(mint_error)) => {
+            db.update_tx(id, doc! { "mint_error": format!("{mint_error:#}") })
+                .await?;
+            bail!("mint error: {mint_error:#}");
+        }
+        Err(task_error) => {
+            db.update_tx(id, doc! { "mint_error": format!("{task_error}") })
+                .await?;
+            bail!("mint task panicked: {task_error}");
+        }
+    };
+
+    let minted = if state.config.dry_run {
+        // Mint address is of the simulated mint, nothing is minted
+        doc! {
+            "status": DepositStatus::DryRunMinted.as_str(),
+            "dry_run": true,
+            "dry_run_mint_address": mint_result.mint_address,
+            "domi_address": domi_address,
+        }
+    } else {
+        doc! {
+            "status": DepositStatus::Minted.as_str(),
+            "minted": true,
+            "mint_address": mint_result.mint_address,
+            "account_address": mint_result.account_address,
+            "domi_address": domi_address,
+        }
+    };
+    let res = db.update_tx(id, minted).await?;
+    assert_eq!(res.matched_count, 1);
+    assert_eq!(res.modified_count, 1);
+    assert_eq!(res.upserted_id, None);
+
+    Ok(())
+}
+
+/// Resolve deposits which stuck in `minting` state: process crashed or mint result is unknown.
+///
+/// Finalized Domichain mints of the service which are not recorded in DB are matched
+/// by amount and owner of the minted tokens, which should be `domi_address` of the deposit:
+/// - single match: mint is adopted and deposit is marked as minted
+/// - no match or multiple matches: could not decide, operator is alerted once.
+///   Deposit stays in `minting` state, since the mint could have landed late
+///   or be missing from the transaction list, and minting again would mint twice
+pub async fn recover_minting_deposits(state: &AppState) -> anyhow::Result<()> {
+    let minting_before = DateTime::from_millis(
+        DateTime::now().timestamp_millis() - MINTING_RECOVERY_DELAY.as_millis() as i64,
+    );
+    let stuck = state.db.find_stuck_minting_deposits(minting_before).await?;
+    if stuck.is_empty() {
+        return Ok(());
+    }
+    warn!("Found {} deposits stuck in minting state", stuck.len());
+
+    let recorded_mints: HashSet<Pubkey> = HashSet::from_iter(state.db.get_all_mints().await);
+    let mut unrecorded_mints: Vec<DomiMint> = get_domi_transactions(
+        state.config.spl_token_program_id,
+        state.config.domichain_service_address,
+    )
+    .await
+    .into_iter()
+    .filter_map(|domi_tx| match domi_tx {
+        DomiTransaction::Mint(mint) => {
+            (!recorded_mints.contains(&mint.token_mint_address)).then_some(mint)
+        }
+        DomiTransaction::Burn(_) => None,
+    })
+    .collect();
+
+    for deposit in stuck {
+        let id = deposit.get("_id").unwrap().clone();
+        let value = deposit_mint_value(&deposit)?;
+        let multi_address = deposit.get_str("multi_address")?;
+        let Some(key) = state.db.find_by_deposit_address(multi_address).await? else {
+            bail!("multisig address {multi_address} doesn't found");
+        };
+        let domi_address: Pubkey = key.get_str("domi_address")?.parse()?;
+
+        let candidates: Vec<usize> = unrecorded_mints
+            .iter()
+            .enumerate()
+            .filter_map(|(i, mint)| {
+                (mint.amount == value && mint.to_owner == Some(domi_address)).then_some(i)
+            })
+            .collect();
+        match candidates.as_slice() {
+            [index] => {
+                let mint = unrecorded_mints.remove(*index);
+                info!("Found mint {} for deposit {id}", mint.token_mint_address);
+                let account_address = get_account_address(mint.token_mint_address);
+                state
+                    .db
+                    .update_tx(
+                        id,
+                        doc! {
+                            "status": DepositStatus::Minted.as_str(),
+                            "minted": true,
+                            "mint_address": mint.token_mint_address.to_string(),
+                            "account_address": account_address.to_string(),
+                            "domi_address": domi_address.to_string(),
+                        },
+                    )
+                    .await?;
+            }
+            _ => {
+                let title = if candidates.is_empty() {
+                    "No mint found for deposit stuck in minting state"
+                } else {
+                    "Could not resolve deposit stuck in minting state"
+                };
+                send_alert(
+                    &state.config,
+                    title,
+                    json!({
+                        "deposit_id": id.to_string(),
+                        "tx_hash": deposit.get_str("tx_hash").ok(),
+                        "value": value,
+                        "domi_address": domi_address.to_string(),
+                        "candidate_mints": candidates
+                            .iter()
+                            .map(|&i| unrecorded_mints[i].token_mint_address.to_string())
+                            .collect::<Vec<_>>(),
+                    }),
+                )
+                .await;
+                // Left for operator, don't alert on every tracker tick
+                state
+                    .db
+                    .update_tx(id, doc! { "recovery_alerted": true })
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_confirmation_policy() {
+    let policy: ConfirmationPolicy = "1000000:1,100000000:3,6".parse().unwrap();
+    assert_eq!(policy.required_confirmations(300), 1);
+    assert_eq!(policy.required_confirmations(999_999), 1);
+    assert_eq!(policy.required_confirmations(1_000_000), 3);
+    assert_eq!(policy.required_confirmations(99_999_999), 3);
+    assert_eq!(policy.required_confirmations(100_000_000), 6);
+
+    let policy: ConfirmationPolicy = "2".parse().unwrap();
+    assert_eq!(policy.required_confirmations(u64::MAX), 2);
+
+    assert!("1000000:1".parse::<ConfirmationPolicy>().is_err());
+    assert!("6,1000000:1".parse::<ConfirmationPolicy>().is_err());
+    assert!("100:1,10:3,6".parse::<ConfirmationPolicy>().is_err());
+    assert!("100:0,6".parse::<ConfirmationPolicy>().is_err());
+}
@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use bdk::FeeRate;
+use mongodb::bson::{doc, Bson};
+use tokio::time::interval;
+use tracing::{error, info, warn};
+
+use crate::{
+    bdk_cli_struct::{BdkCli, OnesigOutput},
+    mempool::{get_address_utxos, get_recommended_fee_rate},
+    psbt_validator::PsbtPolicy,
+    withdrawal::psbt_txid,
+    AppState,
+};
+
+const CONSOLIDATION_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Periodically merge UTXOs of deposit addresses while fee rate is below the ceiling.
+///
+/// Does nothing if `consolidation_max_fee_rate` is not configured.
+pub async fn consolidate_utxos(state: AppState) {
+    let Some(max_fee_rate) = state.config.consolidation_max_fee_rate else {
+        return;
+    };
+    let mut consolidation_interval = interval(CONSOLIDATION_INTERVAL);
+    loop {
+        consolidation_interval.tick().await;
+        if let Err(error) = run_consolidation(&state, max_fee_rate).await {
+            error!("UTXO consolidation error: {error:#}");
+        }
+    }
+}
+
+async fn run_consolidation(state: &AppState, max_fee_rate: f32) -> anyhow::Result<()> {
+    let btc_network = state.config.btc_network;
+
+    let fee_rate = get_recommended_fee_rate(btc_network).await;
+    if fee_rate.as_sat_per_vb() > max_fee_rate {
+        info!(
+            "UTXO consolidation skipped: fee rate {} sat/vB is above {max_fee_rate} sat/vB",
+            fee_rate.as_sat_per_vb()
+        );
+        return Ok(());
+    }
+
+    for multi_address in state.db.get_all_multisig_addresses().await {
+        // Consolidation would conflict with inputs selected by a withdrawal being signed.
+        // UTXOs are listed under the lease, after such withdrawal is sent
+        let _deposit_lease = state
+            .lease_deposit_addresses(&[multi_address.clone()])
+            .await;
+
+        let utxos = match get_address_utxos(btc_network, &multi_address).await {
+            Ok(utxos) => utxos,
+            Err(error) => {
+                warn!("Failed to get {multi_address} UTXOs: {error:#}");
+                continue;
+            }
+        };
+        // Unconfirmed UTXOs mean a withdrawal or previous consolidation is not settled yet
+        if utxos.iter().any(|utxo| !utxo.status.confirmed) {
+            continue;
+        }
+        if utxos.len() < state.config.consolidation_min_utxos {
+            continue;
+        }
+
+        let utxo_count = utxos.len();
+        let input_value: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+        let id = state
+            .db
+            .insert_consolidation(doc! {
+                "multi_address": &multi_address,
+                "status": "created",
+                "utxo_count": utxo_count as i64,
+                "input_value": input_value as i64,
+                "fee_rate": fee_rate.as_sat_per_vb() as f64,
+            })
+            .await?;
+
+        let result = tokio::spawn({
+            let state = state.clone();
+            let id = id.clone();
+            let multi_address = multi_address.clone();
+            async move { consolidate_address(&state, id, &multi_address, fee_rate).await }
+        })
+        .await
+        .map_err(|join_error| anyhow!("consolidation panicked: {join_error}"))
+        .and_then(|result| result);
+        match result {
+            Ok(tx_id) => info!("Consolidated {utxo_count} UTXOs of {multi_address}: {tx_id}"),
+            Err(error) => {
+                warn!("Failed to consolidate UTXOs of {multi_address}: {error:#}");
+                state
+                    .db
+                    .update_consolidation(
+                        id,
+                        doc! { "status": "failed", "error": format!("{error:#}") },
+                    )
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sign consolidation TX by the same cosigners as withdrawals and broadcast it, unless in dry run
+async fn consolidate_address(
+    state: &AppState,
+    id: Bson,
+    multi_address: &str,
+    fee_rate: FeeRate,
+) -> anyhow::Result<String> {
+    let config = &state.config;
+
+    let Some(key) = state.db.find_by_deposit_address(multi_address).await? else {
+        bail!("keys of {multi_address} are not found");
+    };
+    let private_key_00: serde_json::Value = serde_json::from_str(key.get_str("private_key_00")?)?;
+    let xprv_00 = private_key_00["xprv"]
+        .as_str()
+        .ok_or_else(|| anyhow!("xprv is missing"))?;
+    let xpub_00 = key.get_str("public_key_00")?;
+    let xpub_01 = key.get_str("public_key_01")?;
+    let xpub_02 = key.get_str("public_key_02")?;
+    let xpub_03 = key.get_str("public_key_03")?;
+    let key_arn = key.get_str("public_key_arn_01")?;
+    let key_name = key.get_str("public_key_name_03")?;
+
+    let cli = BdkCli::new(config.btc_network);
+
+    let OnesigOutput { onesig_psbt, fee } = cli
+        .consolidate_onesig(xprv_00, xpub_01, xpub_02, xpub_03, multi_address, fee_rate)
+        .await
+        .map_err(|consolidate_error| anyhow!(consolidate_error))?;
+    // Everything returns to the deposit address
+    let psbt_policy = PsbtPolicy {
+        network: config.btc_network,
+        recipients: Vec::new(),
+        deducted_fee: None,
+        descriptors: vec![
+            cli.get_pub_multi_descriptor(xpub_00, xpub_01, xpub_02, xpub_03)
+                .await,
+        ],
+        max_fee: config.withdrawal_max_fee,
+    };
+
+    psbt_policy
+        .check(&onesig_psbt)
+        .context("PSBT is rejected before secondsig")?;
+    let secondsig_psbt = cli
+        .secondsig(xpub_00, xpub_01, xpub_02, xpub_03, &onesig_psbt, key_arn)
+        .await?;
+    psbt_policy
+        .check(&secondsig_psbt)
+        .context("PSBT is rejected before thirdsig")?;
+    let thirdsig_psbt = cli
+        .thirdsig(
+            xpub_00,
+            xpub_01,
+            xpub_02,
+            xpub_03,
+            &secondsig_psbt,
+            key_name,
+        )
+        .await?;
+    let tx_id = psbt_txid(&thirdsig_psbt)?;
+
+    // Record TX before broadcast, so it is known even if service stops in between
+    state
+        .db
+        .update_consolidation(
+            id.clone(),
+            doc! {
+                "status": "signed",
+                "tx_id": &tx_id,
+                "fee": fee as i64,
+                "thirdsig_psbt": &thirdsig_psbt,
+            },
+        )
+        .await?;
+
+    if config.dry_run {
+        state
+            .db
+            .update_consolidation(id, doc! { "status": "dry_run" })
+            .await?;
+        return Ok(tx_id);
+    }
+
+    let sent_tx_id = cli
+        .send(xpub_00, xpub_01, xpub_02, xpub_03, &thirdsig_psbt)
+        .await?;
+    if sent_tx_id != tx_id {
+        warn!("Sent TX ID {sent_tx_id} is different from PSBT TX ID {tx_id}");
+    }
+
+    state
+        .db
+        .update_consolidation(id, doc! { "status": "sent" })
+        .await?;
+
+    Ok(tx_id)
+}
@@ -10,13 +10,14 @@ use bitcoin::PublicKey;
 use domichain_sdk::pubkey::Pubkey;
 use futures::TryStreamExt;
 use kms_sign::parse_asn_pubkey;
-use mongodb::bson::{self, doc, Bson, Document};
+use mongodb::bson::{self, doc, Bson, DateTime, Document};
 use mongodb::client_encryption::{ClientEncryption, MasterKey};
-use mongodb::error::Result;
+use mongodb::error::{ErrorKind, Result, WriteError, WriteFailure};
 use mongodb::mongocrypt::ctx::{Algorithm, KmsProvider};
+use mongodb::options::{FindOptions, IndexOptions};
 use mongodb::results::{InsertOneResult, UpdateResult};
 use mongodb::{options::ClientOptions, Client};
-use mongodb::{Collection, Namespace};
+use mongodb::{Collection, IndexModel, Namespace};
 use primitive_types::U256;
 use serde::Deserialize;
 use tokio::fs::read_to_string;
@@ -24,6 +25,115 @@ use tracing::info;
 
 const DATAKEY_NAME: &str = "encryption_btc";
 
+/// Deposit record state in `transactions` collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepositStatus {
+    /// Seen in mempool, not confirmed yet
+    Mempool,
+    /// Disappeared from mempool before confirmation
+    Dropped,
+    /// Confirmed in block, waiting for required confirmations depth
+    Pending,
+    Minting,
+    Minted,
+    /// Mint is simulated in dry run, nothing is minted. Not a mint for reorg checks and catchup
+    DryRunMinted,
+    /// Minted, but deposit TX disappeared from the chain. Requires manual handling
+    Quarantined,
+    /// Funded from another service multisig. Not minted, requires manual review
+    Review,
+    /// Confirmed, but value is below `MIN_DEPOSIT_AMOUNT`
+    BelowMinimum,
+    /// Below minimum deposit minted as part of total of `accumulated_into` deposit
+    Accumulated,
+}
+
+impl DepositStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DepositStatus::Mempool => "mempool",
+            DepositStatus::Dropped => "dropped",
+            DepositStatus::Pending => "pending",
+            DepositStatus::Minting => "minting",
+            DepositStatus::Minted => "minted",
+            DepositStatus::DryRunMinted => "dry_run_minted",
+            DepositStatus::Quarantined => "quarantined",
+            DepositStatus::Review => "review",
+            DepositStatus::BelowMinimum => "below_minimum",
+            DepositStatus::Accumulated => "accumulated",
+        }
+    }
+}
+
+/// Withdrawal steps in order of execution. Recorded after each step is done
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WithdrawalStatus {
+    /// Request is received
+    Created,
+    /// Request signature and BTCi transfer to service are verified
+    Verified,
+    /// Amount is above approval threshold, operator should approve or reject it
+    AwaitingApproval,
+    Onesig,
+    Secondsig,
+    /// BTC TX is fully signed
+    Thirdsig,
+    /// BTCi is burned, BTC TX should be sent
+    Burned,
+    /// BTC TX is broadcasted
+    Sent,
+    /// Dry run: BTC TX is fully signed and BTCi burn is simulated, but TX is not broadcasted
+    DryRun,
+    /// Requires manual handling, see `error`
+    Failed,
+    /// BTCi is returned to the user
+    Refunded,
+}
+
+impl WithdrawalStatus {
+    pub const ALL: [WithdrawalStatus; 11] = [
+        WithdrawalStatus::Created,
+        WithdrawalStatus::Verified,
+        WithdrawalStatus::AwaitingApproval,
+        WithdrawalStatus::Onesig,
+        WithdrawalStatus::Secondsig,
+        WithdrawalStatus::Thirdsig,
+        WithdrawalStatus::Burned,
+        WithdrawalStatus::Sent,
+        WithdrawalStatus::DryRun,
+        WithdrawalStatus::Failed,
+        WithdrawalStatus::Refunded,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WithdrawalStatus::Created => "created",
+            WithdrawalStatus::Verified => "verified",
+            WithdrawalStatus::AwaitingApproval => "awaiting_approval",
+            WithdrawalStatus::Onesig => "onesig",
+            WithdrawalStatus::Secondsig => "secondsig",
+            WithdrawalStatus::Thirdsig => "thirdsig",
+            WithdrawalStatus::Burned => "burned",
+            WithdrawalStatus::Sent => "sent",
+            WithdrawalStatus::DryRun => "dry_run",
+            WithdrawalStatus::Failed => "failed",
+            WithdrawalStatus::Refunded => "refunded",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|withdrawal_status| withdrawal_status.as_str() == status)
+    }
+
+    /// Withdrawal is not finished yet and should be resumed or compensated.
+    /// Withdrawal awaiting approval is left to the operator.
+    pub fn is_in_progress(&self) -> bool {
+        *self < WithdrawalStatus::Sent && *self != WithdrawalStatus::AwaitingApproval
+    }
+}
+
 #[allow(dead_code)]
 pub struct DB {
     client: Client,
@@ -31,6 +141,11 @@ pub struct DB {
     client_encryption: ClientEncryption,
     keys_collection: Collection<Document>,
     transactions_collection: Collection<Document>,
+    withdrawals_collection: Collection<Document>,
+    consumed_signatures_collection: Collection<Document>,
+    request_nonces_collection: Collection<Document>,
+    consumed_quotes_collection: Collection<Document>,
+    consolidations_collection: Collection<Document>,
 }
 
 impl DB {
@@ -85,6 +200,90 @@ impl DB {
         let transactions_collection = client_decryption
             .database("btc")
             .collection::<Document>("transactions");
+        let withdrawals_collection = client_decryption
+            .database("btc")
+            .collection::<Document>("withdrawals");
+        let consumed_signatures_collection = client_decryption
+            .database("btc")
+            .collection::<Document>("consumed_signatures");
+        let request_nonces_collection = client_decryption
+            .database("btc")
+            .collection::<Document>("request_nonces");
+        let consumed_quotes_collection = client_decryption
+            .database("btc")
+            .collection::<Document>("consumed_quotes");
+        let consolidations_collection = client_decryption
+            .database("btc")
+            .collection::<Document>("consolidations");
+
+        // Deposit is unique by TX output. Legacy records without `vout` are not indexed
+        transactions_collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "tx_hash": 1, "vout": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .name("deposit_outpoint".to_string())
+                            .unique(true)
+                            .partial_filter_expression(doc! { "vout": { "$exists": true } })
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // BTCi transfer could back only one withdrawal
+        consumed_signatures_collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "btci_tx_signature": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .name("btci_tx_signature".to_string())
+                            .unique(true)
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // Signed request could be used only once
+        request_nonces_collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "domi_address": 1, "nonce": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .name("domi_address_nonce".to_string())
+                            .unique(true)
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // Fee quote could be used only once
+        consumed_quotes_collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "quote_id": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .name("quote_id".to_string())
+                            .unique(true)
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await
+            .unwrap();
 
         Self {
             client,
@@ -92,6 +291,11 @@ impl DB {
             client_encryption,
             keys_collection,
             transactions_collection,
+            withdrawals_collection,
+            consumed_signatures_collection,
+            request_nonces_collection,
+            consumed_quotes_collection,
+            consolidations_collection,
         }
     }
 
@@ -293,6 +497,34 @@ impl DB {
         Ok(meta)
     }
 
+    /// Deposit addresses of the wallet without any recorded deposit, oldest first
+    pub async fn find_unused_deposit_addresses(&self, domi_address: &str) -> Result<Vec<String>> {
+        let multi_addresses: Vec<String> = self
+            .keys_collection
+            .find(
+                Some(doc! { "domi_address": domi_address }),
+                FindOptions::builder().sort(doc! { "_id": 1 }).build(),
+            )
+            .await?
+            .try_filter_map(|document| async move {
+                Ok(document.get_str("multi_address").ok().map(str::to_string))
+            })
+            .try_collect()
+            .await?;
+
+        let mut unused = Vec::new();
+        for multi_address in multi_addresses {
+            let deposits = self
+                .transactions_collection
+                .count_documents(doc! { "multi_address": &multi_address }, None)
+                .await?;
+            if deposits == 0 {
+                unused.push(multi_address);
+            }
+        }
+        Ok(unused)
+    }
+
     pub async fn find_by_mint_address(
         &self,
         mint_address: &str,
@@ -350,21 +582,162 @@ impl DB {
             .await
     }
 
-    /// Insert a new unique BTC transaction. Checks uniqueness
-    pub async fn insert_tx(&self, insert: Document) -> Result<InsertOneResult> {
-        // Check that TX hash is unique
-        let new_tx_hash = insert.get_str("tx_hash").unwrap();
-        let existing_tx = self
+    /// Deposit of TX to the multisig address. Single TX could pay to several deposit addresses
+    pub async fn find_deposit(
+        &self,
+        tx_hash: &str,
+        multi_address: &str,
+    ) -> Result<Option<Document>> {
+        self.transactions_collection
+            .find_one(
+                Some(doc! { "tx_hash": tx_hash, "multi_address": multi_address }),
+                None,
+            )
+            .await
+    }
+
+    /// All deposits to the multisig address, oldest first
+    pub async fn find_deposits_by_address(&self, multi_address: &str) -> Result<Vec<Document>> {
+        self.transactions_collection
+            .find(
+                Some(doc! { "multi_address": multi_address }),
+                FindOptions::builder().sort(doc! { "_id": 1 }).build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Mark not yet confirmed deposits of TX as dropped from mempool
+    pub async fn drop_mempool_deposits(&self, tx_hash: &str) -> Result<UpdateResult> {
+        self.transactions_collection
+            .update_many(
+                doc! {
+                    "tx_hash": tx_hash,
+                    "status": DepositStatus::Mempool.as_str(),
+                },
+                doc! {
+                    "$set": { "status": DepositStatus::Dropped.as_str() },
+                },
+                None,
+            )
+            .await
+    }
+
+    /// Insert a new unique BTC deposit. Uniqueness of (`tx_hash`, `vout`) is enforced by index.
+    ///
+    /// Returns `None` if deposit is already inserted.
+    pub async fn insert_tx(&self, insert: Document) -> Result<Option<InsertOneResult>> {
+        match self.transactions_collection.insert_one(insert, None).await {
+            Ok(result) => Ok(Some(result)),
+            Err(error) if is_duplicate_key_error(&error) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Atomically move deposit from `pending` to `minting` state. Returns `false` if already claimed
+    pub async fn claim_deposit_for_mint(&self, id: Bson) -> Result<bool> {
+        let result = self
             .transactions_collection
-            .find_one(Some(doc! {"tx_hash": new_tx_hash}), None)
+            .update_one(
+                doc! {
+                    "_id": id,
+                    "status": DepositStatus::Pending.as_str(),
+                },
+                doc! {
+                    "$set": {
+                        "status": DepositStatus::Minting.as_str(),
+                        "mint_started_at": DateTime::now(),
+                    },
+                    // Deposit returned to `pending` by operator is recovered again
+                    "$unset": { "recovery_alerted": "" },
+                },
+                None,
+            )
+            .await?;
+        Ok(result.modified_count == 1)
+    }
+
+    pub async fn mark_deposits_accumulated(
+        &self,
+        ids: &[Bson],
+        carrier_id: Bson,
+    ) -> Result<UpdateResult> {
+        self.transactions_collection
+            .update_many(
+                doc! { "_id": { "$in": ids } },
+                doc! {
+                    "$set": {
+                        "status": DepositStatus::Accumulated.as_str(),
+                        "accumulated_into": carrier_id,
+                    },
+                },
+                None,
+            )
             .await
-            .unwrap();
-        assert_eq!(
-            existing_tx, None,
-            "Trying to insert duplicate transaction: exisitng = {existing_tx:#?}, new = {insert:#?}",
-        );
+    }
+
+    /// TX hashes of deposits minted as a part of accumulated total
+    pub async fn get_accumulated_tx_hashes(&self) -> Result<Vec<String>> {
+        self.transactions_collection
+            .find(
+                Some(doc! {
+                    "$or": [
+                        { "status": DepositStatus::Accumulated.as_str() },
+                        { "mint_value": { "$exists": true } },
+                    ],
+                }),
+                None,
+            )
+            .await?
+            .try_filter_map(|document| async move {
+                Ok(document.get_str("tx_hash").ok().map(str::to_string))
+            })
+            .try_collect()
+            .await
+    }
+
+    pub async fn find_stuck_minting_deposits(
+        &self,
+        started_before: DateTime,
+    ) -> Result<Vec<Document>> {
+        self.transactions_collection
+            .find(
+                Some(doc! {
+                    "status": DepositStatus::Minting.as_str(),
+                    "mint_started_at": { "$lt": started_before },
+                    "recovery_alerted": { "$ne": true },
+                }),
+                None,
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    pub async fn find_deposits_by_status(&self, status: DepositStatus) -> Result<Vec<Document>> {
+        self.transactions_collection
+            .find(Some(doc! { "status": status.as_str() }), None)
+            .await?
+            .try_collect()
+            .await
+    }
 
-        self.transactions_collection.insert_one(insert, None).await
+    /// Minted deposits confirmed at or above the block height
+    pub async fn find_minted_deposits_since(&self, block_height: u64) -> Result<Vec<Document>> {
+        self.transactions_collection
+            .find(
+                Some(doc! {
+                    "status": DepositStatus::Minted.as_str(),
+                    // Dry run records are not mints
+                    "dry_run": { "$ne": true },
+                    "block_height": { "$gte": block_height as i64 },
+                }),
+                None,
+            )
+            .await?
+            .try_collect()
+            .await
     }
 
     pub async fn update_tx(&self, id: Bson, update: Document) -> Result<UpdateResult> {
@@ -381,6 +754,345 @@ impl DB {
             .await
     }
 
+    /// Insert a new withdrawal in `created` state
+    pub async fn insert_withdrawal(&self, mut insert: Document) -> Result<Bson> {
+        let now = DateTime::now();
+        insert.insert("status", WithdrawalStatus::Created.as_str());
+        insert.insert("created_at", now);
+        insert.insert("updated_at", now);
+        let InsertOneResult { inserted_id, .. } =
+            self.withdrawals_collection.insert_one(insert, None).await?;
+        Ok(inserted_id)
+    }
+
+    /// Record withdrawal step with its artifacts
+    pub async fn update_withdrawal(
+        &self,
+        id: Bson,
+        status: WithdrawalStatus,
+        mut update: Document,
+    ) -> Result<UpdateResult> {
+        update.insert("status", status.as_str());
+        update.insert("updated_at", DateTime::now());
+        self.withdrawals_collection
+            .update_one(doc! { "_id": id }, doc! { "$set": update }, None)
+            .await
+    }
+
+    pub async fn find_withdrawal(&self, id: Bson) -> Result<Option<Document>> {
+        self.withdrawals_collection
+            .find_one(Some(doc! { "_id": id }), None)
+            .await
+    }
+
+    /// Withdrawals which are not sent, failed or refunded
+    pub async fn find_withdrawals_in_progress(&self) -> Result<Vec<Document>> {
+        let statuses: Vec<&str> = WithdrawalStatus::ALL
+            .iter()
+            .filter(|status| status.is_in_progress())
+            .map(WithdrawalStatus::as_str)
+            .collect();
+        self.withdrawals_collection
+            .find(Some(doc! { "status": { "$in": statuses } }), None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    pub async fn find_withdrawals_by_status(
+        &self,
+        status: WithdrawalStatus,
+    ) -> Result<Vec<Document>> {
+        self.withdrawals_collection
+            .find(
+                Some(doc! { "status": status.as_str() }),
+                FindOptions::builder().sort(doc! { "_id": 1 }).build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Move withdrawal from `from` to `to` status.
+    ///
+    /// Returns `false` if withdrawal is not in `from` status, e.g. it's already claimed by another request.
+    pub async fn claim_withdrawal(
+        &self,
+        id: Bson,
+        from: WithdrawalStatus,
+        to: WithdrawalStatus,
+        mut update: Document,
+    ) -> Result<bool> {
+        update.insert("status", to.as_str());
+        update.insert("updated_at", DateTime::now());
+        let result = self
+            .withdrawals_collection
+            .update_one(
+                doc! { "_id": id, "status": from.as_str() },
+                doc! { "$set": update },
+                None,
+            )
+            .await?;
+        Ok(result.modified_count == 1)
+    }
+
+    /// Sum of BTCi amounts of withdrawals created since `since`, by the wallet or all of them.
+    /// Failed, refunded and not yet verified withdrawals are not counted.
+    pub async fn sum_withdrawals_since(
+        &self,
+        domi_address: Option<&str>,
+        since: DateTime,
+        exclude_id: Bson,
+    ) -> Result<u64> {
+        let not_counted = [
+            WithdrawalStatus::Created.as_str(),
+            WithdrawalStatus::Failed.as_str(),
+            WithdrawalStatus::Refunded.as_str(),
+        ];
+        let mut filter = doc! {
+            "_id": { "$ne": exclude_id },
+            "created_at": { "$gte": since },
+            "status": { "$nin": not_counted.to_vec() },
+        };
+        if let Some(domi_address) = domi_address {
+            filter.insert("domi_address", domi_address);
+        }
+        self.withdrawals_collection
+            .find(Some(filter), None)
+            .await?
+            .try_fold(0, |sum, withdrawal| async move {
+                // Withdrawals before fee modes have no `btci_amount`
+                let amount = withdrawal
+                    .get_str("btci_amount")
+                    .or_else(|_| withdrawal.get_str("withdraw_amount"))
+                    .ok()
+                    .and_then(|amount| amount.parse::<u64>().ok())
+                    .unwrap_or_default();
+                Ok(sum + amount)
+            })
+            .await
+    }
+
+    /// Update withdrawal fields without changing its status
+    pub async fn set_withdrawal_fields(&self, id: Bson, update: Document) -> Result<UpdateResult> {
+        self.withdrawals_collection
+            .update_one(doc! { "_id": id }, doc! { "$set": update }, None)
+            .await
+    }
+
+    /// Mark refund of the withdrawal as started.
+    ///
+    /// Returns `false` if refund is already started, so BTCi is never returned twice.
+    pub async fn claim_withdrawal_refund(&self, id: Bson) -> Result<bool> {
+        let now = DateTime::now();
+        let result = self
+            .withdrawals_collection
+            .update_one(
+                doc! { "_id": id, "refund_started_at": { "$exists": false } },
+                doc! { "$set": { "refund_started_at": now, "updated_at": now } },
+                None,
+            )
+            .await?;
+        Ok(result.modified_count == 1)
+    }
+
+    /// Record BTCi transfer of the withdrawal refund
+    pub async fn add_withdrawal_refund_transfer(
+        &self,
+        id: Bson,
+        refund_transfer: Document,
+    ) -> Result<UpdateResult> {
+        self.withdrawals_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$push": { "refund_transfers": refund_transfer } },
+                None,
+            )
+            .await
+    }
+
+    /// Record replacement BTC TX of the withdrawal before its broadcast
+    pub async fn add_withdrawal_replacement(
+        &self,
+        id: Bson,
+        tx_id: &str,
+        fee_bump: Document,
+    ) -> Result<UpdateResult> {
+        self.withdrawals_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$push": {
+                        "tx_ids": tx_id,
+                        "fee_bumps": fee_bump,
+                    },
+                },
+                None,
+            )
+            .await
+    }
+
+    /// Record burn of additional fee of the withdrawal and clear its `burning_fee` marker
+    pub async fn add_withdrawal_fee_burn(
+        &self,
+        id: Bson,
+        burned_amount: u64,
+        burn_signature: &str,
+    ) -> Result<UpdateResult> {
+        self.withdrawals_collection
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": {
+                        "burned_amount": burned_amount.to_string(),
+                        "updated_at": DateTime::now(),
+                    },
+                    "$unset": { "burning_fee": "" },
+                    "$push": { "fee_burn_signatures": burn_signature },
+                },
+                None,
+            )
+            .await
+    }
+
+    /// Sent withdrawals which BTC TX is not confirmed yet
+    pub async fn find_unconfirmed_withdrawals(&self) -> Result<Vec<Document>> {
+        self.withdrawals_collection
+            .find(
+                Some(doc! {
+                    "status": WithdrawalStatus::Sent.as_str(),
+                    "confirmed": { "$ne": true },
+                }),
+                None,
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// Bind BTCi transfer signature to the withdrawal.
+    ///
+    /// Returns `false` if signature is already consumed by another withdrawal.
+    pub async fn consume_signature(
+        &self,
+        btci_tx_signature: &str,
+        withdrawal_id: Bson,
+    ) -> Result<bool> {
+        let insert = doc! {
+            "btci_tx_signature": btci_tx_signature,
+            "withdrawal_id": &withdrawal_id,
+            "consumed_at": DateTime::now(),
+        };
+        match self
+            .consumed_signatures_collection
+            .insert_one(insert, None)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(error) if is_duplicate_key_error(&error) => {
+                let consumed = self
+                    .consumed_signatures_collection
+                    .find_one(Some(doc! { "btci_tx_signature": btci_tx_signature }), None)
+                    .await?;
+                Ok(
+                    consumed.and_then(|consumed| consumed.get("withdrawal_id").cloned())
+                        == Some(withdrawal_id),
+                )
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Bind nonce of the signed request to the withdrawal.
+    ///
+    /// Returns `false` if nonce of the wallet is already used by another withdrawal.
+    pub async fn consume_request_nonce(
+        &self,
+        domi_address: &str,
+        nonce: &str,
+        withdrawal_id: Bson,
+    ) -> Result<bool> {
+        let insert = doc! {
+            "domi_address": domi_address,
+            "nonce": nonce,
+            "withdrawal_id": &withdrawal_id,
+            "consumed_at": DateTime::now(),
+        };
+        match self
+            .request_nonces_collection
+            .insert_one(insert, None)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(error) if is_duplicate_key_error(&error) => {
+                let consumed = self
+                    .request_nonces_collection
+                    .find_one(
+                        Some(doc! { "domi_address": domi_address, "nonce": nonce }),
+                        None,
+                    )
+                    .await?;
+                Ok(
+                    consumed.and_then(|consumed| consumed.get("withdrawal_id").cloned())
+                        == Some(withdrawal_id),
+                )
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Bind fee quote to the withdrawal.
+    ///
+    /// Returns `false` if the quote is already used by another withdrawal.
+    pub async fn consume_fee_quote(&self, quote_id: &str, withdrawal_id: Bson) -> Result<bool> {
+        let insert = doc! {
+            "quote_id": quote_id,
+            "withdrawal_id": &withdrawal_id,
+            "consumed_at": DateTime::now(),
+        };
+        match self
+            .consumed_quotes_collection
+            .insert_one(insert, None)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(error) if is_duplicate_key_error(&error) => {
+                let consumed = self
+                    .consumed_quotes_collection
+                    .find_one(Some(doc! { "quote_id": quote_id }), None)
+                    .await?;
+                Ok(
+                    consumed.and_then(|consumed| consumed.get("withdrawal_id").cloned())
+                        == Some(withdrawal_id),
+                )
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Record a new UTXO consolidation run of the deposit address
+    pub async fn insert_consolidation(&self, mut insert: Document) -> Result<Bson> {
+        let now = DateTime::now();
+        insert.insert("created_at", now);
+        insert.insert("updated_at", now);
+        let InsertOneResult { inserted_id, .. } = self
+            .consolidations_collection
+            .insert_one(insert, None)
+            .await?;
+        Ok(inserted_id)
+    }
+
+    pub async fn update_consolidation(
+        &self,
+        id: Bson,
+        mut update: Document,
+    ) -> Result<UpdateResult> {
+        update.insert("updated_at", DateTime::now());
+        self.consolidations_collection
+            .update_one(doc! { "_id": id }, doc! { "$set": update }, None)
+            .await
+    }
+
     /// Get info about all AWS KMS keys and choose one based on hash
     pub async fn get_aws_kms_pubkey(&self, hash: U256) -> (String, String, String) {
         #[allow(dead_code)]
@@ -453,7 +1165,13 @@ impl DB {
 
     pub async fn get_all_mints(&self) -> Vec<Pubkey> {
         self.transactions_collection
-            .find(Some(doc! { "mint_address": { "$exists": true } }), None)
+            .find(
+                Some(doc! {
+                    "mint_address": { "$exists": true },
+                    "dry_run": { "$ne": true },
+                }),
+                None,
+            )
             .await
             .unwrap()
             .map_ok(|document| {
@@ -492,6 +1210,7 @@ impl DB {
                     Some(doc! {
                         "multi_address": multisig_address,
                         "mint_address": { "$exists": true },
+                        "dry_run": { "$ne": true },
                     }),
                     None,
                 )
@@ -510,6 +1229,13 @@ impl DB {
     }
 }
 
+pub fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(WriteError { code: 11000, .. }))
+    )
+}
+
 pub fn get_compressed_pubkey(pubkey_asn_str: &str) -> String {
     let pubkey_asn_bytes = BASE64_STANDARD.decode(pubkey_asn_str).unwrap();
     let pubkey_bytes = parse_asn_pubkey(&pubkey_asn_bytes).unwrap();
@@ -1,6 +1,7 @@
 use std::{str::FromStr, time::Duration};
 
-use domichain_sdk::{pubkey::Pubkey, signature::Signature};
+use base64::prelude::*;
+use domichain_sdk::{message::Message, pubkey::Pubkey, signature::Signature};
 use reqwest::Url;
 use serde::{de, Deserialize};
 use serde_json::{json, Value};
@@ -136,12 +137,15 @@ pub async fn get_transaction(
     Ok(res.result)
 }
 
-pub async fn get_transaction_poll(rpc_url: Url, signature: Signature) -> DomiTransaction {
+pub async fn get_transaction_poll(
+    rpc_url: Url,
+    signature: Signature,
+) -> Result<DomiTransaction, reqwest::Error> {
     let mut duration = Duration::from_millis(500);
     let mut attempts = 8;
     loop {
         if let Ok(tx) = get_transaction(rpc_url.clone(), signature).await {
-            return tx;
+            return Ok(tx);
         }
         sleep(duration).await;
         duration = duration
@@ -150,9 +154,180 @@ pub async fn get_transaction_poll(rpc_url: Url, signature: Signature) -> DomiTra
             .min(Duration::from_secs(10));
         attempts -= 1;
         if attempts == 0 {
-            return get_transaction(rpc_url.clone(), signature).await.unwrap();
+            return get_transaction(rpc_url.clone(), signature).await;
+        }
+    }
+}
+
+/// Token amount of the account, `None` if the account doesn't exist.
+///
+/// See: https://solana.com/docs/rpc/http/gettokenaccountbalance
+pub async fn get_token_account_balance(
+    rpc_url: Url,
+    token_account: Pubkey,
+) -> anyhow::Result<Option<u64>> {
+    let client = reqwest::Client::new();
+    let res: Value = client
+        .post(rpc_url)
+        .json(&json!({
+          "jsonrpc": "2.0",
+          "id": 1,
+          "method": "getTokenAccountBalance",
+          "params": [token_account.to_string()]
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    if !res["error"].is_null() {
+        return Ok(None);
+    }
+    let amount = res["result"]["value"]["amount"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("getTokenAccountBalance: amount is missing"))?;
+    Ok(Some(amount.parse()?))
+}
+
+/// See: https://solana.com/docs/rpc/http/getminimumbalanceforrentexemption
+pub async fn get_minimum_balance_for_rent_exemption(
+    rpc_url: Url,
+    data_len: usize,
+) -> anyhow::Result<u64> {
+    let client = reqwest::Client::new();
+    let res: Value = client
+        .post(rpc_url)
+        .json(&json!({
+          "jsonrpc": "2.0",
+          "id": 1,
+          "method": "getMinimumBalanceForRentExemption",
+          "params": [data_len]
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    res["result"]
+        .as_u64()
+        .ok_or_else(|| anyhow::anyhow!("getMinimumBalanceForRentExemption error: {}", res["error"]))
+}
+
+/// Simulate transaction of `message` without signing it: signatures are not verified
+/// and blockhash is replaced by the node. Fails with the transaction error and its logs.
+///
+/// See: https://solana.com/docs/rpc/http/simulatetransaction
+pub async fn simulate_transaction(rpc_url: Url, message: &Message) -> anyhow::Result<()> {
+    // Wire format: compact-u16 signature count, empty signatures, message
+    let signature_count = message.header.num_required_signatures;
+    anyhow::ensure!(signature_count < 0x80, "too many signers");
+    let mut tx = vec![signature_count];
+    tx.resize(1 + signature_count as usize * 64, 0);
+    tx.extend(message.serialize());
+
+    let client = reqwest::Client::new();
+    let res: Value = client
+        .post(rpc_url)
+        .json(&json!({
+          "jsonrpc": "2.0",
+          "id": 1,
+          "method": "simulateTransaction",
+          "params": [
+            BASE64_STANDARD.encode(tx),
+            {
+              "encoding": "base64",
+              "sigVerify": false,
+              "replaceRecentBlockhash": true,
+            }
+          ]
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let result = &res["result"]["value"];
+    if result.is_null() {
+        anyhow::bail!("simulateTransaction error: {}", res["error"]);
+    }
+    if !result["err"].is_null() {
+        anyhow::bail!(
+            "simulated transaction failed: {}, logs: {}",
+            result["err"],
+            result["logs"]
+        );
+    }
+    Ok(())
+}
+
+/// Burn of `amount` tokens from `token_account` in a successful transaction since `since`
+/// (Unix time in seconds). Returns signature of the burn transaction.
+///
+/// See: https://solana.com/docs/rpc/http/getsignaturesforaddress
+pub async fn find_token_burn(
+    rpc_url: Url,
+    token_account: Pubkey,
+    amount: u64,
+    since: i64,
+) -> anyhow::Result<Option<Signature>> {
+    let client = reqwest::Client::new();
+    let res: Value = client
+        .post(rpc_url.clone())
+        .json(&json!({
+          "jsonrpc": "2.0",
+          "id": 1,
+          "method": "getSignaturesForAddress",
+          "params": [token_account.to_string()]
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let signatures = res["result"]
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("getSignaturesForAddress: {}", res["error"]))?;
+
+    let token_account = token_account.to_string();
+    let amount = amount.to_string();
+    for signature_info in signatures {
+        // Newest first
+        if signature_info["blockTime"]
+            .as_i64()
+            .is_some_and(|block_time| block_time < since)
+        {
+            break;
+        }
+        if !signature_info["err"].is_null() {
+            continue;
+        }
+        let signature = signature_info["signature"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("getSignaturesForAddress: signature is missing"))?;
+        // Raw JSON: burn transaction could have instructions which are not parsed
+        let tx: Value = client
+            .post(rpc_url.clone())
+            .json(&json!({
+              "jsonrpc": "2.0",
+              "id": 1,
+              "method": "getTransaction",
+              "params": [signature, "jsonParsed"]
+            }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let instructions = tx["result"]["transaction"]["message"]["instructions"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("getTransaction {signature}: {}", tx["error"]))?;
+        let is_burn = instructions.iter().any(|ix| {
+            let info = &ix["parsed"]["info"];
+            matches!(ix["parsed"]["type"].as_str(), Some("burn" | "burnChecked"))
+                && info["account"].as_str() == Some(&token_account)
+                && (info["amount"].as_str() == Some(&amount)
+                    || info["tokenAmount"]["amount"].as_str() == Some(&amount))
+        });
+        if is_burn {
+            return Ok(Some(Signature::from_str(signature)?));
         }
     }
+    Ok(None)
 }
 
 #[tokio::test]
@@ -160,6 +335,6 @@ async fn test_get_transaction() {
     let tx = get_transaction_poll(
         Url::from_str("https://api.testnet.domichain.io").unwrap(),
         Signature::from_str("45LT1XzdoHuNU7v1jXsi2xk5oE5Wgqb8aZpq71iTPqnFWMVsdehHTW56x2PyJJ5BPfGTTAmeeMdY34nazdbRkKsm").unwrap(),
-    ).await;
+    ).await.unwrap();
     dbg!(tx);
 }
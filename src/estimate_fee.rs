@@ -1,13 +1,15 @@
 use axum::{extract::State, Json};
 use bdk::FeeRate;
-use domichain_sdk::pubkey::Pubkey;
+use domichain_sdk::{pubkey::Pubkey, signature::Signature};
 use serde::{Deserialize, Serialize};
 use serde_json::Number;
-use tracing::{debug, info};
+use tracing::{debug, error, info};
 
 use crate::{
     bdk_cli_struct::BdkCli,
+    fee_quote::FeeQuote,
     mempool::{get_mempool_url, get_recommended_fee_rates, RecommendedFeesResp},
+    sign_multisig_tx::{WithdrawalFeeMode, WithdrawalRecipient},
     utils::{serde_as_str, serde_convert},
     AppState, Args,
 };
@@ -19,6 +21,12 @@ pub struct EstimateFeeRequest {
     /// BTC withdraw destination address
     withdraw_address: String,
     withdraw_amount: String,
+    /// BTC outputs of multi-recipient withdrawal, instead of `withdraw_address` and `withdraw_amount`
+    #[serde(default)]
+    recipients: Vec<WithdrawalRecipient>,
+    /// Fee mode of the withdrawal, `quote` is valid only for it
+    #[serde(default)]
+    fee_mode: WithdrawalFeeMode,
 }
 
 #[derive(Serialize)]
@@ -30,13 +38,34 @@ pub struct RecommendedFeeRates {
     minimum_fee: Number,
 }
 
+#[derive(Serialize)]
+pub struct WithdrawalTotals {
+    /// BTCi to transfer with the withdrawal request
+    btci_amount: String,
+    /// BTC received by `withdraw_address`, or by all recipients in total
+    recipient_amount: String,
+}
+
+/// Totals by withdrawal fee mode at `fastest_fee` rate. `null` if mode is not possible
+#[derive(Serialize)]
+pub struct FeeModeTotals {
+    deduct_from_amount: Option<WithdrawalTotals>,
+    exact_amount: Option<WithdrawalTotals>,
+}
+
 #[derive(Serialize)]
 #[serde(untagged)]
 pub enum EstimateFeeResponse {
     Ok {
         status: String,
         vbytes: u64,
+        fee: u64,
+        totals: FeeModeTotals,
         recommended_fee_rates: RecommendedFeeRates,
+        /// Fixes `fee` at `fastest_fee` rate for `/sign_multisig_tx`
+        quote: FeeQuote,
+        #[serde(with = "serde_as_str")]
+        quote_signature: Signature,
     },
     Error {
         status: String,
@@ -49,27 +78,19 @@ pub async fn estimate_fee(
     Json(request): Json<EstimateFeeRequest>,
 ) -> Json<EstimateFeeResponse> {
     let Args {
-        bdk_cli_path_default,
-        bdk_cli_path_patched,
         btc_network,
+        domichain_service_keypair_path,
         ..
     } = state.config;
 
-    let temp_wallet_dir = None;
-    let descriptor = None;
-    let cli = BdkCli::new(
-        btc_network,
-        bdk_cli_path_default,
-        bdk_cli_path_patched,
-        temp_wallet_dir,
-        descriptor,
-    )
-    .await;
+    let cli = BdkCli::new(btc_network);
 
     let EstimateFeeRequest {
         mint_address,
         withdraw_address,
         withdraw_amount,
+        recipients,
+        fee_mode,
     } = request;
 
     let (transaction, key) = if let Some(data) = state
@@ -105,8 +126,29 @@ pub async fn estimate_fee(
     // let key_arn = key["public_key_arn_01"].as_str().unwrap();
     // let key_name = key["public_key_name_03"].as_str().unwrap();
 
-    let to_address = &withdraw_address;
-    let amount = &withdraw_amount;
+    let recipients = if recipients.is_empty() {
+        vec![WithdrawalRecipient {
+            address: withdraw_address,
+            amount: withdraw_amount,
+        }]
+    } else {
+        recipients
+    };
+    let Ok(recipients) = recipients
+        .into_iter()
+        .map(|recipient| {
+            recipient
+                .amount
+                .parse::<u64>()
+                .map(|amount| (recipient.address, amount))
+        })
+        .collect::<Result<Vec<_>, _>>()
+    else {
+        return Json(EstimateFeeResponse::Error {
+            status: "error".to_string(),
+            message: "withdraw_amount is invalid".to_string(),
+        });
+    };
 
     let multi_descriptor_00 = cli
         .get_multi_descriptor(xprv_00, xpub_01, xpub_02, xpub_03)
@@ -121,11 +163,11 @@ pub async fn estimate_fee(
     let recommended_fee = &recommended_fee_rates.fastest_fee;
     let fee_rate = FeeRate::from_sat_per_vb(recommended_fee.as_f64().unwrap() as f32);
 
-    let (fee, vbytes) = match cli
-        .estimate_fee(&multi_descriptor_00, to_address, amount, fee_rate)
+    let (fee, vbytes, confirmed) = match cli
+        .estimate_fee(&multi_descriptor_00, &recipients, fee_rate)
         .await
     {
-        Ok((fee, vbytes)) => (fee, vbytes),
+        Ok(estimate) => estimate,
         Err(error) => {
             return Json(EstimateFeeResponse::Error {
                 status: "error".to_string(),
@@ -137,6 +179,56 @@ pub async fn estimate_fee(
     info!("fee_rate: {fee_rate:?}");
     info!("vbytes: {vbytes}");
 
+    let amount: u64 = recipients.iter().map(|(_, amount)| amount).sum();
+    let totals = FeeModeTotals {
+        deduct_from_amount: amount
+            .checked_sub(fee)
+            .map(|recipient_amount| WithdrawalTotals {
+                btci_amount: amount.to_string(),
+                recipient_amount: recipient_amount.to_string(),
+            }),
+        exact_amount: (amount + fee <= confirmed).then(|| WithdrawalTotals {
+            btci_amount: (amount + fee).to_string(),
+            recipient_amount: amount.to_string(),
+        }),
+    };
+
+    let fee_mode_possible = match fee_mode {
+        WithdrawalFeeMode::DeductFromAmount => totals.deduct_from_amount.is_some(),
+        WithdrawalFeeMode::ExactAmount => totals.exact_amount.is_some(),
+    };
+    let quote = fee_mode_possible
+        .then(|| {
+            FeeQuote::new(
+                mint_address,
+                &recipients,
+                fee_mode,
+                fee_rate.as_sat_per_vb(),
+                vbytes,
+                fee,
+            )
+        })
+        .flatten();
+    let Some(quote) = quote else {
+        return Json(EstimateFeeResponse::Error {
+            status: "error".to_string(),
+            message: format!(
+                "{} fee mode is not possible for the amount",
+                fee_mode.as_str()
+            ),
+        });
+    };
+    let quote_signature = match quote.sign(&domichain_service_keypair_path) {
+        Ok(signature) => signature,
+        Err(sign_error) => {
+            error!("estimate_fee: failed to sign quote: {sign_error:#}");
+            return Json(EstimateFeeResponse::Error {
+                status: "error".to_string(),
+                message: "Internal service error. Try again later".to_string(),
+            });
+        }
+    };
+
     let RecommendedFeesResp {
         fastest_fee,
         half_hour_fee,
@@ -147,6 +239,8 @@ pub async fn estimate_fee(
     return Json(EstimateFeeResponse::Ok {
         status: "ok".to_string(),
         vbytes,
+        fee,
+        totals,
         recommended_fee_rates: RecommendedFeeRates {
             fastest_fee,
             half_hour_fee,
@@ -154,6 +248,8 @@ pub async fn estimate_fee(
             economy_fee,
             minimum_fee,
         },
+        quote,
+        quote_signature,
     });
 }
 
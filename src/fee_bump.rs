@@ -0,0 +1,369 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use bdk::FeeRate;
+use domichain_sdk::pubkey::Pubkey;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use serde_json::json;
+use tokio::time::interval;
+use tracing::{error, info, warn};
+
+use crate::{
+    alert::send_alert,
+    bdk_cli_struct::{BdkCli, OnesigOutput},
+    db::WithdrawalStatus,
+    mempool::{get_recommended_fee_rate, get_tx_status},
+    psbt_validator::PsbtPolicy,
+    withdrawal::{
+        burn_withdrawal_fee, deposit_addresses, is_exact_amount, psbt_txid, recover_fee_burn,
+        refund_fee_excess, withdrawal_recipients,
+    },
+    AppState,
+};
+
+const WITHDRAWAL_TRACKER_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Min fee rate increase of replacement TX, sat/vB
+const MIN_FEE_RATE_INCREMENT: f32 = 1.0;
+
+/// Watch confirmation of sent withdrawals and bump fee of stuck ones, if enabled
+pub async fn track_withdrawals(state: AppState) {
+    let mut tracker_interval = interval(WITHDRAWAL_TRACKER_INTERVAL);
+    loop {
+        tracker_interval.tick().await;
+        // Separate thread to keep tracking after a panic
+        let check_state = state.clone();
+        match tokio::spawn(async move { check_sent_withdrawals(&check_state).await }).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => error!("Withdrawal tracker error: {error:#}"),
+            Err(task_error) => error!("Withdrawal tracker panicked: {task_error:#?}"),
+        }
+    }
+}
+
+async fn check_sent_withdrawals(state: &AppState) -> anyhow::Result<()> {
+    let btc_network = state.config.btc_network;
+    for withdrawal in state.db.find_unconfirmed_withdrawals().await? {
+        let id = withdrawal.get("_id").unwrap().clone();
+        let tx_ids = withdrawal_tx_ids(&withdrawal);
+
+        // Original TX or any of its replacements could be confirmed
+        let mut found = false;
+        let mut confirmed = None;
+        for tx_id in &tx_ids {
+            match get_tx_status(btc_network, tx_id).await {
+                Ok(Some(tx_status)) => {
+                    found = true;
+                    if tx_status.confirmed {
+                        confirmed = Some((tx_id.clone(), tx_status));
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    warn!("Failed to get withdrawal TX {tx_id} status: {error:#}");
+                    found = true;
+                }
+            }
+        }
+
+        if let Some((tx_id, tx_status)) = confirmed {
+            info!("Withdrawal {id} TX {tx_id} is confirmed");
+            let mut update = doc! {
+                "confirmed": true,
+                "tx_id": &tx_id,
+                "tx_missing": false,
+            };
+            if let Some(block_height) = tx_status.block_height {
+                update.insert("block_height", block_height as i64);
+            }
+            state.db.set_withdrawal_fields(id.clone(), update).await?;
+            if let Err(error) = refund_fee_excess(state, id.clone(), &withdrawal).await {
+                error!("Withdrawal {id} fee excess refund error: {error:#}");
+            }
+            continue;
+        }
+
+        if !found {
+            if !withdrawal.get_bool("tx_missing").unwrap_or(false) {
+                send_alert(
+                    &state.config,
+                    "Withdrawal BTC transaction disappeared",
+                    json!({
+                        "withdrawal_id": id.to_string(),
+                        "tx_ids": tx_ids,
+                    }),
+                )
+                .await;
+                state
+                    .db
+                    .set_withdrawal_fields(id, doc! { "tx_missing": true })
+                    .await?;
+            }
+            continue;
+        }
+
+        let Some(auto_fee_bump_after) = state.config.auto_fee_bump_after_minutes else {
+            continue;
+        };
+        if state.config.dry_run {
+            continue;
+        }
+        let broadcast_at = withdrawal
+            .get_datetime("broadcast_at")
+            .map(DateTime::timestamp_millis)
+            .unwrap_or_default();
+        let stuck_for_ms = DateTime::now().timestamp_millis() - broadcast_at;
+        if stuck_for_ms < (auto_fee_bump_after * 60 * 1000) as i64 {
+            continue;
+        }
+        // Separate thread to catch any errors
+        let bump_state = state.clone();
+        let bump_id = id.clone();
+        let bump_result =
+            tokio::spawn(async move { bump_withdrawal_fee(&bump_state, bump_id, None).await })
+                .await;
+        match bump_result {
+            Ok(Ok(tx_id)) => info!("Withdrawal {id} fee is bumped automatically: {tx_id}"),
+            Ok(Err(error)) => warn!("Withdrawal {id} fee is not bumped: {error:#}"),
+            Err(task_error) => error!("Withdrawal {id} fee bump panicked: {task_error:#?}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Original TX ID and IDs of its replacements
+fn withdrawal_tx_ids(withdrawal: &Document) -> Vec<String> {
+    match withdrawal.get_array("tx_ids") {
+        Ok(tx_ids) => tx_ids
+            .iter()
+            .filter_map(|tx_id| tx_id.as_str().map(str::to_string))
+            .collect(),
+        Err(_) => withdrawal
+            .get_str("tx_id")
+            .map(|tx_id| vec![tx_id.to_string()])
+            .unwrap_or_default(),
+    }
+}
+
+/// Replace unconfirmed withdrawal TX with higher fee rate one, signed by the same cosigners.
+///
+/// Without `fee_rate` recommended fee rate is used. Returns replacement TX ID.
+pub async fn bump_withdrawal_fee(
+    state: &AppState,
+    id: Bson,
+    fee_rate: Option<FeeRate>,
+) -> anyhow::Result<String> {
+    let config = &state.config;
+    let btc_network = config.btc_network;
+
+    // Replacement TX would be broadcasted
+    if config.dry_run {
+        bail!("fee bump is disabled in dry run");
+    }
+
+    // Single bump at a time: bumps of the same TX would conflict
+    let _fee_bump_guard = state.fee_bump.lock().await;
+
+    let withdrawal = state
+        .db
+        .find_withdrawal(id.clone())
+        .await?
+        .ok_or_else(|| anyhow!("withdrawal {id} is not found"))?;
+    if withdrawal.get_str("status")? != WithdrawalStatus::Sent.as_str() {
+        bail!("withdrawal {id} is not sent");
+    }
+    if withdrawal.get_bool("confirmed").unwrap_or(false) {
+        bail!("withdrawal {id} is already confirmed");
+    }
+    // Inputs of other deposit addresses can't be signed by the wallet of `mint_address`
+    if withdrawal
+        .get_array("sources")
+        .is_ok_and(|sources| !sources.is_empty())
+    {
+        bail!("fee bump of multi-source withdrawal {id} is not supported");
+    }
+    // Replacement re-selects UTXOs of the deposit address
+    let mint_address = Pubkey::from_str(withdrawal.get_str("mint_address")?)?;
+    let multi_addresses = deposit_addresses(state, [mint_address]).await?;
+    let _deposit_lease = state.lease_deposit_addresses(&multi_addresses).await;
+
+    // Additional fee burned by an interrupted bump is already paid
+    let burned_amount = if is_exact_amount(&withdrawal) {
+        recover_fee_burn(state, id.clone(), &withdrawal).await?
+    } else {
+        0
+    };
+
+    let tx_id = withdrawal.get_str("tx_id")?;
+    match get_tx_status(btc_network, tx_id).await? {
+        None => bail!("withdrawal TX {tx_id} is not found"),
+        Some(tx_status) if tx_status.confirmed => bail!("withdrawal TX {tx_id} is confirmed"),
+        Some(_) => {}
+    }
+
+    let current_fee_rate = withdrawal.get_f64("fee_rate")? as f32;
+    let min_fee_rate = current_fee_rate + MIN_FEE_RATE_INCREMENT;
+    let fee_rate = match fee_rate {
+        Some(fee_rate) => fee_rate,
+        None => {
+            let recommended = get_recommended_fee_rate(btc_network).await;
+            if recommended.as_sat_per_vb() < min_fee_rate {
+                bail!(
+                    "recommended fee rate {} sat/vB is not higher than current {current_fee_rate} sat/vB",
+                    recommended.as_sat_per_vb()
+                );
+            }
+            recommended
+        }
+    };
+    if fee_rate.as_sat_per_vb() < min_fee_rate {
+        bail!("fee rate should be at least {min_fee_rate} sat/vB");
+    }
+    if fee_rate.as_sat_per_vb() > config.max_fee_bump_rate {
+        bail!(
+            "fee rate is above max fee bump rate {} sat/vB",
+            config.max_fee_bump_rate
+        );
+    }
+
+    let Some((_, key)) = state
+        .db
+        .find_by_mint_address(withdrawal.get_str("mint_address")?)
+        .await?
+    else {
+        bail!("keys of withdrawal {id} are not found");
+    };
+    let private_key_00: serde_json::Value = serde_json::from_str(key.get_str("private_key_00")?)?;
+    let xprv_00 = private_key_00["xprv"]
+        .as_str()
+        .ok_or_else(|| anyhow!("xprv is missing"))?;
+    let xpub_00 = key.get_str("public_key_00")?;
+    let xpub_01 = key.get_str("public_key_01")?;
+    let xpub_02 = key.get_str("public_key_02")?;
+    let xpub_03 = key.get_str("public_key_03")?;
+    let key_arn = key.get_str("public_key_arn_01")?;
+    let key_name = key.get_str("public_key_name_03")?;
+    let withdraw_address = withdrawal.get_str("withdraw_address")?;
+    // Recipient of `exact_amount` withdrawal keeps the amount, change pays the fee
+    let exact_amount = is_exact_amount(&withdrawal);
+    // Only one output could be shrunk, which breaks pro rata fee split of several recipients
+    if !exact_amount
+        && withdrawal
+            .get_array("recipients")
+            .is_ok_and(|recipients| recipients.len() > 1)
+    {
+        bail!("fee bump of multi-recipient withdrawal {id} is supported only in exact_amount fee mode");
+    }
+    let shrink_address = (!exact_amount).then_some(withdraw_address);
+
+    let cli = BdkCli::new(btc_network);
+
+    info!(
+        "Bump withdrawal {id} TX {tx_id} fee rate: {current_fee_rate} -> {} sat/vB",
+        fee_rate.as_sat_per_vb()
+    );
+    let OnesigOutput { onesig_psbt, fee } = cli
+        .bump_fee_onesig(
+            xprv_00,
+            xpub_01,
+            xpub_02,
+            xpub_03,
+            tx_id,
+            shrink_address,
+            fee_rate,
+        )
+        .await
+        .map_err(|bump_error| anyhow!(bump_error))?;
+
+    // Additional fee of `exact_amount` withdrawal is burned from its BTCi
+    let burn_more = if exact_amount {
+        let withdraw_amount: u64 = withdrawal.get_str("withdraw_amount")?.parse()?;
+        let btci_amount: u64 = withdrawal.get_str("btci_amount")?.parse()?;
+        if withdraw_amount + fee > btci_amount {
+            bail!(
+                "fee {fee} sat exceeds fee covered by btci_amount {} sat",
+                btci_amount - withdraw_amount
+            );
+        }
+        (withdraw_amount + fee).saturating_sub(burned_amount)
+    } else {
+        0
+    };
+    let psbt_policy = PsbtPolicy {
+        network: btc_network,
+        recipients: withdrawal_recipients(&withdrawal)?,
+        // Shrunk recipient pays the whole new fee
+        deducted_fee: (!exact_amount).then_some(fee),
+        descriptors: vec![
+            cli.get_pub_multi_descriptor(xpub_00, xpub_01, xpub_02, xpub_03)
+                .await,
+        ],
+        max_fee: config.withdrawal_max_fee,
+    };
+
+    psbt_policy
+        .check(&onesig_psbt)
+        .context("PSBT is rejected before secondsig")?;
+    let secondsig_psbt = cli
+        .secondsig(xpub_00, xpub_01, xpub_02, xpub_03, &onesig_psbt, key_arn)
+        .await?;
+    psbt_policy
+        .check(&secondsig_psbt)
+        .context("PSBT is rejected before thirdsig")?;
+    let thirdsig_psbt = cli
+        .thirdsig(
+            xpub_00,
+            xpub_01,
+            xpub_02,
+            xpub_03,
+            &secondsig_psbt,
+            key_name,
+        )
+        .await?;
+    let new_tx_id = psbt_txid(&thirdsig_psbt)?;
+
+    // Replacement is recorded only after its fee is burned, right before broadcast
+    if burn_more > 0 {
+        burn_withdrawal_fee(state, id.clone(), &withdrawal, burned_amount, burn_more).await?;
+    }
+
+    state
+        .db
+        .add_withdrawal_replacement(
+            id.clone(),
+            &new_tx_id,
+            doc! {
+                "tx_id": &new_tx_id,
+                "replaced_tx_id": tx_id,
+                "fee": fee as i64,
+                "fee_rate": fee_rate.as_sat_per_vb() as f64,
+                "thirdsig_psbt": &thirdsig_psbt,
+                "bumped_at": DateTime::now(),
+            },
+        )
+        .await?;
+
+    let sent_tx_id = cli
+        .send(xpub_00, xpub_01, xpub_02, xpub_03, &thirdsig_psbt)
+        .await?;
+    if sent_tx_id != new_tx_id {
+        warn!("Sent TX ID {sent_tx_id} is different from PSBT TX ID {new_tx_id}");
+    }
+
+    state
+        .db
+        .set_withdrawal_fields(
+            id,
+            doc! {
+                "tx_id": &new_tx_id,
+                "fee": fee as i64,
+                "fee_rate": fee_rate.as_sat_per_vb() as f64,
+                "thirdsig_psbt": &thirdsig_psbt,
+                "broadcast_at": DateTime::now(),
+            },
+        )
+        .await?;
+
+    Ok(new_tx_id)
+}
@@ -0,0 +1,140 @@
+//! Fee quotes of `/estimate_fee`, signed by the service wallet key.
+//!
+//! Quote fixes fee rate, fee and exact BTC outputs of the withdrawal to the quoted recipients
+//! until it expires, so `/sign_multisig_tx` doesn't trust `fee_rate` and `vbytes` of the client.
+//! Each quote is used by one withdrawal only.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use domichain_program::pubkey::Pubkey;
+use domichain_sdk::signature::{read_keypair_file, Signature, Signer};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bdk_cli_struct::split_fee,
+    sign_multisig_tx::{WithdrawalFeeMode, WithdrawalRecipient},
+    utils::serde_as_str,
+};
+
+/// How long quote is accepted
+pub const QUOTE_TTL_SECS: i64 = 10 * 60;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FeeQuote {
+    pub quote_id: String,
+    #[serde(with = "serde_as_str")]
+    pub mint_address: Pubkey,
+    /// BTC outputs before fee deduction
+    pub recipients: Vec<WithdrawalRecipient>,
+    pub fee_mode: WithdrawalFeeMode,
+    /// Exact BTC outputs of the withdrawal TX: `recipients` after fee deduction in `fee_mode`
+    pub send_amounts: Vec<WithdrawalRecipient>,
+    /// sat/vB
+    pub fee_rate: f32,
+    pub vbytes: u64,
+    /// sat
+    pub fee: u64,
+    /// Unix time in seconds
+    pub expires_at: i64,
+}
+
+impl FeeQuote {
+    /// `None` if fee can't be deducted from the amounts
+    pub fn new(
+        mint_address: Pubkey,
+        recipients: &[(String, u64)],
+        fee_mode: WithdrawalFeeMode,
+        fee_rate: f32,
+        vbytes: u64,
+        fee: u64,
+    ) -> Option<Self> {
+        let amounts: Vec<u64> = recipients.iter().map(|(_, amount)| *amount).collect();
+        let fee_shares = match fee_mode {
+            WithdrawalFeeMode::DeductFromAmount => split_fee(fee, &amounts),
+            WithdrawalFeeMode::ExactAmount => vec![0; amounts.len()],
+        };
+        let send_amounts = recipients
+            .iter()
+            .zip(fee_shares)
+            .map(|((address, amount), fee_share)| {
+                // Same as the withdrawal TX: output can't be zero
+                (*amount > fee_share).then(|| WithdrawalRecipient {
+                    address: address.clone(),
+                    amount: (amount - fee_share).to_string(),
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            quote_id: ObjectId::new().to_hex(),
+            mint_address,
+            recipients: recipients
+                .iter()
+                .map(|(address, amount)| WithdrawalRecipient {
+                    address: address.clone(),
+                    amount: amount.to_string(),
+                })
+                .collect(),
+            fee_mode,
+            send_amounts,
+            fee_rate,
+            vbytes,
+            fee,
+            expires_at: DateTime::now().timestamp_millis() / 1000 + QUOTE_TTL_SECS,
+        })
+    }
+
+    /// Exact BTC outputs of the withdrawal TX
+    pub fn send_amounts(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        self.send_amounts
+            .iter()
+            .map(|send_amount| Ok((send_amount.address.clone(), send_amount.amount.parse()?)))
+            .collect()
+    }
+
+    /// Signed message: JSON of the quote as returned to the client
+    fn message(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Sign by the service wallet key
+    pub fn sign(&self, keypair_path: &Path) -> anyhow::Result<Signature> {
+        let keypair = read_keypair_file(keypair_path)
+            .map_err(|keypair_error| anyhow!("service keypair is not loaded: {keypair_error}"))?;
+        Ok(keypair.sign_message(self.message().as_bytes()))
+    }
+
+    /// Check that the quote is signed by the service, not expired and is for the withdrawal
+    pub fn verify(
+        &self,
+        signature: &Signature,
+        service_address: Pubkey,
+        mint_address: Pubkey,
+        recipients: &[(String, u64)],
+        fee_mode: WithdrawalFeeMode,
+    ) -> Result<(), String> {
+        if !signature.verify(service_address.as_ref(), self.message().as_bytes()) {
+            return Err("quote signature is invalid".to_string());
+        }
+        if self.expires_at < DateTime::now().timestamp_millis() / 1000 {
+            return Err("quote is expired".to_string());
+        }
+        if self.mint_address != mint_address {
+            return Err("quote is for another mint_address".to_string());
+        }
+        let quoted = self
+            .recipients
+            .iter()
+            .map(|recipient| Ok((recipient.address.clone(), recipient.amount.parse()?)))
+            .collect::<Result<Vec<(String, u64)>, std::num::ParseIntError>>()
+            .map_err(|parse_error| format!("quote amount is invalid: {parse_error}"))?;
+        if quoted != recipients {
+            return Err("quote is for other recipients or amounts".to_string());
+        }
+        if self.fee_mode != fee_mode {
+            return Err(format!("quote is for {} fee mode", self.fee_mode.as_str()));
+        }
+        Ok(())
+    }
+}
@@ -1,16 +1,14 @@
-use axum::{extract::State, Json};
+use axum::{extract::State, http::StatusCode, Json};
 use bdk::bitcoin::Network;
 use mongodb::bson::{doc, Document};
 use primitive_types::U256;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 use tokio::fs::read_to_string;
 use tracing::info;
 
-use crate::{
-    bdk_cli_struct::BdkCli, utils::serde_convert, watch_addresses::watch_address, AppState, Args
-};
+use crate::{bdk_cli_struct::BdkCli, utils::serde_convert, AppState, Args};
 
 /*
 {
@@ -37,23 +35,9 @@ struct GetPubkeyResult {
 }
 
 pub async fn new_multisig_address(state: &AppState, domi_address: String) -> String {
-    let Args {
-        bdk_cli_path_default,
-        bdk_cli_path_patched,
-        btc_network,
-        ..
-    } = &state.config;
-
-    let temp_wallet_dir = None;
-    let descriptor = None;
-    let cli = BdkCli::new(
-        *btc_network,
-        bdk_cli_path_default.clone(),
-        bdk_cli_path_patched.clone(),
-        temp_wallet_dir,
-        descriptor,
-    )
-    .await;
+    let Args { btc_network, .. } = &state.config;
+
+    let cli = BdkCli::new(*btc_network);
 
     let key_00 = cli.generate_key().await;
     let xprv_00 = &key_00.xprv;
@@ -133,23 +117,62 @@ pub async fn new_multisig_address(state: &AppState, domi_address: String) -> Str
 #[derive(Deserialize)]
 pub struct NewMiltisigAddressRequest {
     pub domi_address: String,
+    /// Issue a new address even if wallet has an unused one
+    #[serde(default)]
+    pub new: bool,
 }
 
+/// Returns unused deposit address of the wallet, or issues a new one
 pub async fn get_address_from_db(
     State(state): State<AppState>,
     Json(request): Json<NewMiltisigAddressRequest>,
-) -> Json<String> {
-    let Args { btc_network, .. } = state.config;
-
-    let address = new_multisig_address(&state, request.domi_address).await;
+) -> Result<Json<String>, (StatusCode, Json<Value>)> {
+    let NewMiltisigAddressRequest { domi_address, new } = request;
 
-    let _watch_handle = tokio::spawn(watch_address(
-        state,
-        address.clone(),
-        btc_network,
-    ));
+    // Concurrent requests of the same wallet should not issue several addresses
+    let _issuance_guard = state.address_issuance.lock().await;
 
-    Json(address)
+    let unused_addresses = state
+        .db
+        .find_unused_deposit_addresses(&domi_address)
+        .await
+        .map_err(|db_error| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "status": "error",
+                    "message": format!("Failed to get wallet addresses: {db_error}"),
+                })),
+            )
+        })?;
+
+    if !new {
+        if let Some(address) = unused_addresses.last() {
+            info!("Reuse unused address {address} of {domi_address}");
+            state.watchers.add(address.clone());
+            return Ok(Json(address.clone()));
+        }
+    }
+
+    let max_open_addresses = state.config.max_open_addresses_per_wallet;
+    if unused_addresses.len() >= max_open_addresses {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({
+                "status": "error",
+                "message": format!(
+                    "Wallet already has {} unused deposit addresses, max is {max_open_addresses}",
+                    unused_addresses.len(),
+                ),
+            })),
+        ));
+    }
+
+    let address = new_multisig_address(&state, domi_address).await;
+
+    state.watchers.add(address.clone());
+
+    Ok(Json(address))
 }
 
 fn get_hash(data: &[u8]) -> U256 {
@@ -0,0 +1,130 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use mongodb::bson::Document;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{db::DepositStatus, AppState};
+
+/// User facing deposit state
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DepositState {
+    Mempool,
+    Confirming,
+    Minting,
+    Minted,
+    /// Mint is simulated in dry run, nothing is minted
+    DryRunMinted,
+    /// Below minimum deposit, waiting for more deposits or operator
+    BelowMinimum,
+    /// Below minimum deposit, minted together with later deposit
+    Accumulated,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DepositInfo {
+    tx_hash: String,
+    value: String,
+    state: DepositState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confirmations: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    required_confirmations: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mint_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account_address: Option<String>,
+    /// Why deposit is failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl DepositInfo {
+    fn from_document(deposit: &Document) -> Self {
+        let status = deposit.get_str("status").ok();
+        let minted = deposit.get_bool("minted").unwrap_or(false);
+        let (state, reason) = match status {
+            _ if minted && status != Some(DepositStatus::Quarantined.as_str()) => {
+                (DepositState::Minted, None)
+            }
+            // Legacy records are minted immediately or not at all
+            None => (DepositState::Failed, Some("not minted")),
+            Some(status) if status == DepositStatus::Mempool.as_str() => {
+                (DepositState::Mempool, None)
+            }
+            Some(status) if status == DepositStatus::Pending.as_str() => {
+                (DepositState::Confirming, None)
+            }
+            Some(status) if status == DepositStatus::Minting.as_str() => {
+                (DepositState::Minting, None)
+            }
+            Some(status) if status == DepositStatus::DryRunMinted.as_str() => {
+                (DepositState::DryRunMinted, None)
+            }
+            Some(status) if status == DepositStatus::BelowMinimum.as_str() => {
+                (DepositState::BelowMinimum, None)
+            }
+            Some(status) if status == DepositStatus::Accumulated.as_str() => {
+                (DepositState::Accumulated, None)
+            }
+            Some(status) if status == DepositStatus::Dropped.as_str() => {
+                (DepositState::Failed, Some("dropped from mempool"))
+            }
+            Some(status) if status == DepositStatus::Quarantined.as_str() => (
+                DepositState::Failed,
+                Some("deposit transaction disappeared after reorg"),
+            ),
+            Some(status) if status == DepositStatus::Review.as_str() => {
+                (DepositState::Failed, Some("manual review required"))
+            }
+            Some(_) => (DepositState::Failed, Some("unknown status")),
+        };
+
+        Self {
+            tx_hash: deposit.get_str("tx_hash").unwrap_or_default().to_string(),
+            value: deposit.get_str("value").unwrap_or_default().to_string(),
+            state,
+            confirmations: deposit.get_i64("confirmations").ok(),
+            required_confirmations: deposit.get_i64("required_confirmations").ok(),
+            mint_address: deposit.get_str("mint_address").ok().map(str::to_string),
+            account_address: deposit.get_str("account_address").ok().map(str::to_string),
+            reason: reason.map(str::to_string),
+        }
+    }
+}
+
+/// All deposits to the BTC deposit address with their state
+pub async fn get_deposits(
+    State(state): State<AppState>,
+    Path(btc_deposit_address): Path<String>,
+) -> Json<serde_json::Value> {
+    let meta = state
+        .db
+        .find_by_deposit_address(&btc_deposit_address)
+        .await
+        .unwrap();
+    if meta.is_none() {
+        return Json(json!({
+            "status": "error",
+            "message": format!("Deposit address not found: {btc_deposit_address}"),
+        }));
+    }
+
+    let deposits: Vec<DepositInfo> = state
+        .db
+        .find_deposits_by_address(&btc_deposit_address)
+        .await
+        .unwrap()
+        .iter()
+        .map(DepositInfo::from_document)
+        .collect();
+
+    Json(json!({
+        "status": "ok",
+        "deposits": deposits,
+    }))
+}
@@ -1,22 +1,32 @@
+mod admin;
+mod alert;
 mod balance_by_addresses;
-mod bdk_cli;
 mod bdk_cli_struct;
 mod catchup;
+mod confirmation_tracker;
+mod consolidation;
 mod db;
 mod deprecated;
 mod domichain;
 mod estimate_fee;
+mod fee_bump;
+mod fee_quote;
 mod get_address;
-mod get_mint_info;
+mod get_deposits;
 mod log_progress;
 mod mempool;
 mod mint_token;
+mod multi_source;
+mod psbt_validator;
 mod sign_multisig_tx;
 mod spl_token;
 mod utils;
 mod watch_addresses;
 mod watch_tx;
+mod withdrawal;
+mod withdrawal_limits;
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
@@ -26,8 +36,11 @@ use axum::routing::{get, post};
 use axum::{Json, Router};
 use catchup::process_catchup;
 use clap::Parser;
+use confirmation_tracker::{track_confirmations, BelowMinimumPolicy, ConfirmationPolicy};
+use consolidation::consolidate_utxos;
 use db::DB;
 use domichain_program::pubkey::Pubkey;
+use fee_bump::track_withdrawals;
 use kms_sign::load_dotenv;
 use reqwest::Url;
 use serde_json::json;
@@ -36,6 +49,9 @@ use tracing::{debug, info};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use utils::ArcPathValueParser;
+use watch_addresses::{supervise_watchers, Watchers};
+use withdrawal::recover_withdrawals;
+use withdrawal_limits::WithdrawalLimits;
 
 /// BTC Transfer service
 #[derive(Parser, Debug, Clone)]
@@ -69,7 +85,7 @@ pub struct Args {
     #[arg(long, env = "SERVICE_ALLOW_ORIGIN")]
     service_allow_origin: HeaderValue,
 
-    /// Dry run, don't send BTC TX
+    /// Dry run: simulate BTCi mint, burn and transfer, sign BTC TX but don't broadcast it
     #[arg(long, default_value_t = false)]
     dry_run: bool,
 
@@ -89,14 +105,6 @@ pub struct Args {
     #[arg(long, env = "SPL_TOKEN_PROGRAM_ID")]
     spl_token_program_id: Pubkey,
 
-    /// Path to bdk-cli
-    #[arg(long, env = "BDK_CLI_PATH_DEFAULT", value_parser=ArcPathValueParser)]
-    bdk_cli_path_default: Arc<Path>,
-
-    /// Path to bdk-cli with AWS KMS support
-    #[arg(long, env = "BDK_CLI_PATH_PATCHED", value_parser=ArcPathValueParser)]
-    bdk_cli_path_patched: Arc<Path>,
-
     /// Bitcoin network
     #[arg(long, env = "BTC_NETWORK")]
     btc_network: bdk::bitcoin::Network,
@@ -116,17 +124,151 @@ pub struct Args {
     /// AWS Region
     #[arg(long, env = "AWS_REGION")]
     aws_region: String,
+
+    /// Confirmations required before BTCi mint by deposit amount in sat.
+    /// Format: `<below_sat>:<confirmations>,...,<confirmations>`
+    #[arg(
+        long,
+        env = "CONFIRMATION_POLICY",
+        default_value = "1000000:1,100000000:3,6"
+    )]
+    confirmation_policy: ConfirmationPolicy,
+
+    /// Depth in blocks to re-validate minted deposits against chain reorganisations
+    #[arg(long, env = "REORG_CHECK_DEPTH", default_value_t = 144)]
+    reorg_check_depth: u64,
+
+    /// Webhook URL to post alerts which require operator attention
+    #[arg(long, env = "ALERT_WEBHOOK_URL")]
+    alert_webhook_url: Option<Url>,
+
+    /// Bearer token of admin API. Admin API is disabled if not set
+    #[arg(long, env = "ADMIN_TOKEN")]
+    admin_token: Option<String>,
+
+    /// Bump fee of withdrawals unconfirmed for this many minutes. Disabled if not set
+    #[arg(long, env = "AUTO_FEE_BUMP_AFTER_MINUTES")]
+    auto_fee_bump_after_minutes: Option<u64>,
+
+    /// Max fee rate of withdrawal fee bump, sat/vB
+    #[arg(long, env = "MAX_FEE_BUMP_RATE", default_value_t = 200.0)]
+    max_fee_bump_rate: f32,
+
+    /// Max fee of withdrawal BTC TX, sat. PSBT with higher fee is not passed to cosigners
+    #[arg(long, env = "WITHDRAWAL_MAX_FEE", default_value_t = 100_000)]
+    withdrawal_max_fee: u64,
+
+    /// Domain of withdrawal request signatures, unique per service deployment,
+    /// so that signed requests of one deployment are not valid for another.
+    /// `btc-transfer.<BTC_NETWORK>` if not set
+    #[arg(long, env = "REQUEST_SIGNATURE_DOMAIN")]
+    request_signature_domain: Option<String>,
+
+    /// Accept deprecated request signatures of plain JSON, without domain, nonce and expiry
+    #[arg(
+        long,
+        env = "ALLOW_LEGACY_REQUEST_SIGNATURE",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    allow_legacy_request_signature: bool,
+
+    /// Accept only withdrawals with a fee quote of `/estimate_fee`.
+    /// Deprecated opt-out: if disabled, fee rate of a withdrawal without quote is trusted
+    #[arg(
+        long,
+        env = "REQUIRE_FEE_QUOTE",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    require_fee_quote: bool,
+
+    /// Max fee rate to consolidate UTXOs of deposit addresses, sat/vB. Disabled if not set
+    #[arg(long, env = "CONSOLIDATION_MAX_FEE_RATE")]
+    consolidation_max_fee_rate: Option<f32>,
+
+    /// Min confirmed UTXOs of deposit address to consolidate them
+    #[arg(long, env = "CONSOLIDATION_MIN_UTXOS", default_value_t = 5)]
+    consolidation_min_utxos: usize,
+
+    /// Minimum deposit amount in sat to mint BTCi
+    #[arg(long, env = "MIN_DEPOSIT_AMOUNT", default_value_t = 10000)]
+    min_deposit_amount: u64,
+
+    /// Handling of confirmed deposits below `MIN_DEPOSIT_AMOUNT`
+    #[arg(
+        long,
+        env = "BELOW_MINIMUM_POLICY",
+        value_enum,
+        default_value_t = BelowMinimumPolicy::Accumulate
+    )]
+    below_minimum_policy: BelowMinimumPolicy,
+
+    /// Max deposit addresses without deposits per Domichain wallet
+    #[arg(long, env = "MAX_OPEN_ADDRESSES_PER_WALLET", default_value_t = 5)]
+    max_open_addresses_per_wallet: usize,
+
+    #[command(flatten)]
+    withdrawal_limits: WithdrawalLimits,
+}
+
+impl Args {
+    /// Domain of withdrawal request signatures, see `request_signature_domain` argument
+    pub fn request_signature_domain(&self) -> String {
+        self.request_signature_domain
+            .clone()
+            .unwrap_or_else(|| format!("btc-transfer.{}", self.btc_network))
+    }
 }
 
 #[derive(Clone)]
 struct AppState {
     db: Arc<DB>,
     config: Args,
+    watchers: Arc<Watchers>,
+    /// Serializes deposit address issuance
+    address_issuance: Arc<tokio::sync::Mutex<()>>,
+    /// Serializes withdrawal fee bumps
+    fee_bump: Arc<tokio::sync::Mutex<()>>,
+    /// Leases of deposit addresses, see [`AppState::lease_deposit_addresses`]
+    deposit_address_leases: Arc<std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
 }
 
 impl AppState {
     fn new(db: Arc<DB>, config: Args) -> Self {
-        Self { db, config }
+        Self {
+            db,
+            config,
+            watchers: Arc::new(Watchers::default()),
+            address_issuance: Arc::new(tokio::sync::Mutex::new(())),
+            fee_bump: Arc::new(tokio::sync::Mutex::new(())),
+            deposit_address_leases: Arc::default(),
+        }
+    }
+
+    /// Exclusive lease of deposit addresses, held while their UTXOs are selected, signed and sent.
+    /// Withdrawals and consolidations of the same address would spend the same UTXOs
+    async fn lease_deposit_addresses(
+        &self,
+        multi_addresses: &[String],
+    ) -> Vec<tokio::sync::OwnedMutexGuard<()>> {
+        // Same order for everyone, so two leases of several addresses can't deadlock
+        let mut multi_addresses = multi_addresses.to_vec();
+        multi_addresses.sort();
+        multi_addresses.dedup();
+
+        let mut guards = Vec::new();
+        for multi_address in multi_addresses {
+            let lease = self
+                .deposit_address_leases
+                .lock()
+                .unwrap()
+                .entry(multi_address)
+                .or_default()
+                .clone();
+            guards.push(lease.lock_owned().await);
+        }
+        guards
     }
 }
 
@@ -160,20 +302,43 @@ async fn main() {
         mongodb_master_key_path,
         service_bind_address,
         service_allow_origin,
-        dry_run: _,
+        dry_run,
         skip_catchup,
         spl_token_cli_path,
         spl_token_combined_mint_cli_path,
         spl_token_program_id,
-        bdk_cli_path_default,
-        bdk_cli_path_patched,
         btc_network: _,
         ledger_keys_path,
         aws_access_key_id: _,
         aws_secret_access_key: _,
         aws_region: _,
+        confirmation_policy,
+        reorg_check_depth: _,
+        alert_webhook_url: _,
+        max_open_addresses_per_wallet: _,
+        min_deposit_amount,
+        below_minimum_policy,
+        admin_token: _,
+        auto_fee_bump_after_minutes,
+        max_fee_bump_rate,
+        withdrawal_max_fee,
+        request_signature_domain: _,
+        allow_legacy_request_signature,
+        require_fee_quote,
+        consolidation_max_fee_rate,
+        consolidation_min_utxos,
+        withdrawal_limits,
     } = args.clone();
 
+    info!("dry_run = {dry_run}");
+    info!("confirmation_policy = {confirmation_policy:?}");
+    info!("auto_fee_bump_after_minutes = {auto_fee_bump_after_minutes:?}, max_fee_bump_rate = {max_fee_bump_rate}, withdrawal_max_fee = {withdrawal_max_fee}");
+    info!("consolidation_max_fee_rate = {consolidation_max_fee_rate:?}, consolidation_min_utxos = {consolidation_min_utxos}");
+    info!("withdrawal_limits = {withdrawal_limits:?}");
+    info!("request_signature_domain = {}, allow_legacy_request_signature = {allow_legacy_request_signature}", args.request_signature_domain());
+    info!("require_fee_quote = {require_fee_quote}");
+    info!("min_deposit_amount = {min_deposit_amount}, below_minimum_policy = {below_minimum_policy:?}");
+
     let service_allow_origin = service_allow_origin.clone();
     let service_bind_address = service_bind_address.clone();
 
@@ -181,8 +346,6 @@ async fn main() {
     assert!(mongodb_master_key_path.exists());
     assert!(spl_token_cli_path.exists());
     assert!(spl_token_combined_mint_cli_path.exists());
-    assert!(bdk_cli_path_default.exists());
-    assert!(bdk_cli_path_patched.exists());
     assert!(ledger_keys_path.exists());
 
     debug!("starting");
@@ -212,22 +375,54 @@ async fn main() {
         debug!("catchup skipped");
     }
 
+    // Resume live watching of all previously issued deposit addresses
+    for address in all_multisig_addresses {
+        app_state.watchers.add(address);
+    }
+    info!("watched addresses: {}", app_state.watchers.count());
+    app_state.watchers.ensure_running(&app_state);
+    tokio::spawn(supervise_watchers(app_state.clone()));
+    tokio::spawn(track_confirmations(app_state.clone()));
+
+    // Resume or refund withdrawals interrupted by previous shutdown
+    recover_withdrawals(&app_state).await;
+    tokio::spawn(track_withdrawals(app_state.clone()));
+    tokio::spawn(consolidate_utxos(app_state.clone()));
+
     let app = Router::new()
         .route(
             "/get_address_from_db",
             post(get_address::get_address_from_db),
         )
+        .route(
+            "/deposits/:btc_deposit_address",
+            get(get_deposits::get_deposits),
+        )
         .route("/estimate_fee", post(estimate_fee::estimate_fee))
         .route(
             "/sign_multisig_tx",
             post(sign_multisig_tx::sign_multisig_tx),
         )
+        .route(
+            "/withdrawal_status/:withdrawal_id",
+            get(sign_multisig_tx::withdrawal_status),
+        )
+        .route("/admin/bump_fee", post(admin::bump_fee))
+        .route(
+            "/admin/withdrawals_awaiting_approval",
+            get(admin::withdrawals_awaiting_approval),
+        )
+        .route("/admin/approve_withdrawal", post(admin::approve_withdrawal))
+        .route("/admin/reject_withdrawal", post(admin::reject_withdrawal))
         .route("/health", get(health))
         .layer(
             CorsLayer::new()
                 .allow_origin(service_allow_origin)
                 .allow_methods([Method::GET, Method::POST])
-                .allow_headers(vec![http::header::CONTENT_TYPE]),
+                .allow_headers(vec![
+                    http::header::CONTENT_TYPE,
+                    http::header::AUTHORIZATION,
+                ]),
         )
         .with_state(app_state);
 
@@ -2,6 +2,7 @@ use std::time::Instant;
 
 use bdk::{bitcoin::Network, FeeRate};
 use cached::{proc_macro::cached, Return};
+use reqwest::StatusCode;
 use serde::Deserialize;
 use serde_json::Number;
 use tracing::debug;
@@ -57,6 +58,59 @@ pub async fn get_recommended_fee_rate(btc_network: Network) -> FeeRate {
     FeeRate::from_sat_per_vb(recommended_fee.as_f64().unwrap() as f32)
 }
 
+/// See: https://mempool.space/docs/api/rest#get-block-tip-height
+pub async fn get_tip_height(btc_network: Network) -> anyhow::Result<u64> {
+    let mempool_url = get_mempool_url(btc_network);
+    let height = reqwest::get(format!("{mempool_url}/api/blocks/tip/height"))
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    Ok(height.trim().parse()?)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TxStatus {
+    pub confirmed: bool,
+    pub block_height: Option<u64>,
+    pub block_hash: Option<String>,
+}
+
+/// See: https://mempool.space/docs/api/rest#get-transaction-status
+///
+/// Returns `None` if transaction is unknown: never seen or dropped from mempool
+pub async fn get_tx_status(
+    btc_network: Network,
+    tx_hash: &str,
+) -> anyhow::Result<Option<TxStatus>> {
+    let mempool_url = get_mempool_url(btc_network);
+    let response = reqwest::get(format!("{mempool_url}/api/tx/{tx_hash}/status")).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let status = response.error_for_status()?.json().await?;
+    Ok(Some(status))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Utxo {
+    pub txid: String,
+    pub vout: u32,
+    pub value: u64,
+    pub status: TxStatus,
+}
+
+/// See: https://mempool.space/docs/api/rest#get-address-utxo
+pub async fn get_address_utxos(btc_network: Network, address: &str) -> anyhow::Result<Vec<Utxo>> {
+    let mempool_url = get_mempool_url(btc_network);
+    let utxos = reqwest::get(format!("{mempool_url}/api/address/{address}/utxo"))
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(utxos)
+}
+
 #[tokio::test]
 async fn test_get_recommended_fee_rate() {
     dbg!(get_recommended_fee_rate(Network::Bitcoin).await);
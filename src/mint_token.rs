@@ -1,12 +1,25 @@
 use std::str::FromStr;
 
+use anyhow::Context;
 use axum::{extract::State, Json};
 use domichain_account_decoder::parse_token::token_amount_to_ui_amount;
-use domichain_program::pubkey::Pubkey;
+use domichain_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    system_instruction, system_program,
+};
+use domichain_sdk::{
+    message::Message,
+    signature::{Keypair, Signature, Signer},
+};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tracing::info;
 
 use crate::{
+    domichain::{
+        get_minimum_balance_for_rent_exemption, get_token_account_balance, simulate_transaction,
+    },
     spl_token::{combined_burn_cli, combined_mint_cli, combined_transfer_cli, spl_token},
     AppState, Args,
 };
@@ -19,8 +32,7 @@ pub fn get_account_address(token_address: Pubkey) -> Pubkey {
     let token_program_id_string = std::env::var("SPL_TOKEN_PROGRAM_ID").unwrap();
     let token_program_id = Pubkey::from_str(&token_program_id_string).unwrap();
 
-    let associated_token_program_id =
-        Pubkey::from_str("Dt8fRCpjeV6JDemhPmtcTKijgKdPxXHn9Wo9cXY5agtG").unwrap();
+    let associated_token_program_id = Pubkey::from_str(ASSOCIATED_TOKEN_PROGRAM_ID).unwrap();
     // owner == Fk2HRYuDw9h29yKs1tNDjvjdvYMqQ2dGg9sS4JhUzQ6w
     let owner =
         Pubkey::from_str(spl_token(&["address"])["walletAddress"].as_str().unwrap()).unwrap();
@@ -49,8 +61,7 @@ pub fn get_user_account_address(token_address: Pubkey, user: Pubkey) -> Pubkey {
     let token_program_id_string = std::env::var("SPL_TOKEN_PROGRAM_ID").unwrap();
     let token_program_id = Pubkey::from_str(&token_program_id_string).unwrap();
 
-    let associated_token_program_id =
-        Pubkey::from_str("Dt8fRCpjeV6JDemhPmtcTKijgKdPxXHn9Wo9cXY5agtG").unwrap();
+    let associated_token_program_id = Pubkey::from_str(ASSOCIATED_TOKEN_PROGRAM_ID).unwrap();
 
     let (pubkey, _bump_seed) = Pubkey::find_program_address(
         &[
@@ -113,7 +124,9 @@ pub async fn mint_token_inner(
     address: &str,
 ) -> anyhow::Result<MintTokenResult> {
     let use_combined_mint = true;
-    if use_combined_mint {
+    if args.dry_run {
+        dry_run_mint_token_inner(args, amount, address).await
+    } else if use_combined_mint {
         combined_mint_token_inner(args, amount, address).await
     } else {
         separate_mint_token_inner(args, amount, address).await
@@ -195,10 +208,155 @@ pub async fn combined_mint_token_inner(
     })
 }
 
-pub async fn burn_token_inner(args: &Args, mint_address: Pubkey, amount: u64) {
+/// Associated Token Account program of Domichain
+const ASSOCIATED_TOKEN_PROGRAM_ID: &str = "Dt8fRCpjeV6JDemhPmtcTKijgKdPxXHn9Wo9cXY5agtG";
+/// Size of SPL Token mint account
+const MINT_ACCOUNT_SIZE: usize = 82;
+
+/// Dry run: simulate the mint transaction, don't mint.
+/// Mint address is a new random one, like a real mint would create
+pub async fn dry_run_mint_token_inner(
+    args: &Args,
+    amount: &str,
+    address: &str,
+) -> anyhow::Result<MintTokenResult> {
+    let amount: u64 = amount.parse()?;
+    let destination_address: Pubkey = address.parse()?;
+    let decimals = 8;
+
+    let mint = Keypair::new().pubkey();
+    let payer = args.domichain_service_address;
+    let token_program = args.spl_token_program_id;
+    let destination_account = get_user_account_address(mint, destination_address);
+    let rent =
+        get_minimum_balance_for_rent_exemption(args.domichain_rpc_url.clone(), MINT_ACCOUNT_SIZE)
+            .await?;
+
+    // Same steps as `spl-token` mint: create token, create user account, mint, disable mint
+    let mut initialize_mint = vec![20, decimals];
+    initialize_mint.extend(payer.to_bytes());
+    initialize_mint.push(0); // No freeze authority
+    let mut mint_to = vec![14];
+    mint_to.extend(amount.to_le_bytes());
+    mint_to.push(decimals);
+    let instructions = [
+        system_instruction::create_account(
+            &payer,
+            &mint,
+            rent,
+            MINT_ACCOUNT_SIZE as u64,
+            &token_program,
+        ),
+        // InitializeMint2
+        Instruction::new_with_bytes(
+            token_program,
+            &initialize_mint,
+            vec![AccountMeta::new(mint, false)],
+        ),
+        // Create associated token account
+        Instruction::new_with_bytes(
+            Pubkey::from_str(ASSOCIATED_TOKEN_PROGRAM_ID)?,
+            &[],
+            vec![
+                AccountMeta::new(payer, true),
+                AccountMeta::new(destination_account, false),
+                AccountMeta::new_readonly(destination_address, false),
+                AccountMeta::new_readonly(mint, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(token_program, false),
+            ],
+        ),
+        // MintToChecked
+        Instruction::new_with_bytes(
+            token_program,
+            &mint_to,
+            vec![
+                AccountMeta::new(mint, false),
+                AccountMeta::new(destination_account, false),
+                AccountMeta::new_readonly(payer, true),
+            ],
+        ),
+        // SetAuthority: no mint authority
+        Instruction::new_with_bytes(
+            token_program,
+            &[6, 0, 0],
+            vec![
+                AccountMeta::new(mint, false),
+                AccountMeta::new_readonly(payer, true),
+            ],
+        ),
+    ];
+    let message = Message::new(&instructions, Some(&payer));
+    simulate_transaction(args.domichain_rpc_url.clone(), &message)
+        .await
+        .context("Dry run: mint simulation failed")?;
+
+    info!("Dry run: mint {amount} to {destination_address} as {mint}");
+    let account_address = get_account_address(mint);
+
+    Ok(MintTokenResult {
+        mint_address: mint.to_string(),
+        account_address: account_address.to_string(),
+        output: json!({
+            "dry_run": true,
+            "amount": amount,
+            "destination_address": destination_address.to_string(),
+            "destination_account": destination_account.to_string(),
+        }),
+    })
+}
+
+/// Dry run: simulate burn of `amount` from service token account, don't burn
+async fn dry_run_burn_token(args: &Args, mint_address: Pubkey, amount: u64) -> anyhow::Result<()> {
+    let decimals = 8;
+    let owner = args.domichain_service_address;
+    let token_account_address = get_account_address(mint_address);
+
+    let mut burn = vec![15];
+    burn.extend(amount.to_le_bytes());
+    burn.push(decimals);
+    // BurnChecked
+    let instruction = Instruction::new_with_bytes(
+        args.spl_token_program_id,
+        &burn,
+        vec![
+            AccountMeta::new(token_account_address, false),
+            AccountMeta::new(mint_address, false),
+            AccountMeta::new_readonly(owner, true),
+        ],
+    );
+    let message = Message::new(&[instruction], Some(&owner));
+    simulate_transaction(args.domichain_rpc_url.clone(), &message).await?;
+    info!("Dry run: burn {amount} of {mint_address} from {token_account_address}");
+    Ok(())
+}
+
+/// Dry run: check that service token account holds `amount` to burn or transfer
+async fn dry_run_check_token_balance(args: &Args, mint_address: Pubkey, amount: u64) {
+    let token_account_address = get_account_address(mint_address);
+    let balance = get_token_account_balance(args.domichain_rpc_url.clone(), token_account_address)
+        .await
+        .unwrap()
+        .unwrap_or(0);
+    info!("Dry run: token account {token_account_address} has {balance}, needs {amount}");
+    assert!(
+        balance >= amount,
+        "Dry run: token account {token_account_address} balance {balance} is less than {amount}"
+    );
+}
+
+/// Returns burn TX signature, if it's known
+pub async fn burn_token_inner(args: &Args, mint_address: Pubkey, amount: u64) -> Option<Signature> {
     let decimals = 8;
     let token_account_address = get_account_address(mint_address);
 
+    if args.dry_run {
+        dry_run_burn_token(args, mint_address, amount)
+            .await
+            .unwrap_or_else(|burn_error| panic!("Dry run: burn simulation failed: {burn_error:#}"));
+        return None;
+    }
+
     let use_combined_burn = true;
     if use_combined_burn {
         info!("Burn amount integer: {amount}");
@@ -215,23 +373,32 @@ pub async fn burn_token_inner(args: &Args, mint_address: Pubkey, amount: u64) {
         .await;
         info!("burn_output: {burn_output:#?}");
         assert_eq!(&burn_output.status, "ok");
+        Some(burn_output.signature)
     } else {
         let ui_amount = token_amount_to_ui_amount(amount, 8);
         let burn_amount = ui_amount.ui_amount_string;
         info!("Burn amount: {burn_amount}");
         let burn_output = spl_token(&["burn", &token_account_address.to_string(), &burn_amount]);
         info!("burn_output: {burn_output:#?}");
+        None
     }
 }
 
+/// Returns transfer TX signature, `None` in dry run
 pub async fn transfer_token_inner(
     args: &Args,
     mint_address: Pubkey,
     amount: u64,
     destination: Pubkey,
-) {
+) -> Option<Signature> {
     let decimals = 8;
     let token_account_address = get_account_address(mint_address);
+
+    if args.dry_run {
+        dry_run_check_token_balance(args, mint_address, amount).await;
+        return None;
+    }
+
     info!("Transfer amount integer: {amount}");
     let transfer_output = combined_transfer_cli(
         &args.spl_token_combined_mint_cli_path,
@@ -247,4 +414,5 @@ pub async fn transfer_token_inner(
     .await;
     info!("transfer_output: {transfer_output:#?}");
     assert_eq!(&transfer_output.status, "ok");
+    Some(transfer_output.signature)
 }
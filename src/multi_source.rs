@@ -0,0 +1,292 @@
+//! Withdrawal spending from one or several deposit addresses in one BTC TX.
+//!
+//! Each deposit address has its own descriptor, so the TX is built in-process with inputs of other
+//! descriptors added as foreign UTXOs, and every signing round is done for each descriptor.
+//! Single-source withdrawal is the case of one descriptor.
+
+use std::{cmp::Reverse, str::FromStr};
+
+use anyhow::{anyhow, bail, Context};
+use bdk::{
+    bitcoin::{psbt::PartiallySignedTransaction, Address, Network, ScriptBuf},
+    blockchain::ElectrumBlockchain,
+    database::MemoryDatabase,
+    electrum_client::Client,
+    FeeRate, KeychainKind, LocalUtxo, SignOptions, SyncOptions, Wallet,
+};
+use domichain_program::pubkey::Pubkey;
+use tracing::info;
+
+use crate::{
+    bdk_cli_struct::{electrum_server, split_fee, BdkCli, KmsSignOutput, OnesigOutput},
+    AppState,
+};
+
+/// Deposit address and its keys to spend `amount` from
+pub struct SourceWallet {
+    pub mint_address: Pubkey,
+    pub multi_address: String,
+    pub amount: u64,
+    /// Descriptor with private key 00
+    pub multi_descriptor_00: String,
+    pub pub_multi_descriptor: String,
+    pub xpubs: [String; 4],
+    pub key_arn: String,
+    pub key_name: String,
+}
+
+pub async fn load_source_wallet(
+    state: &AppState,
+    cli: &BdkCli,
+    mint_address: Pubkey,
+    amount: u64,
+) -> anyhow::Result<SourceWallet> {
+    let Some((_, key)) = state
+        .db
+        .find_by_mint_address(&mint_address.to_string())
+        .await?
+    else {
+        bail!("Mint address not found: {mint_address}");
+    };
+    let private_key_00: serde_json::Value = serde_json::from_str(key.get_str("private_key_00")?)?;
+    let xprv_00 = private_key_00["xprv"]
+        .as_str()
+        .ok_or_else(|| anyhow!("xprv is missing"))?;
+    let xpubs = [
+        key.get_str("public_key_00")?.to_string(),
+        key.get_str("public_key_01")?.to_string(),
+        key.get_str("public_key_02")?.to_string(),
+        key.get_str("public_key_03")?.to_string(),
+    ];
+    let multi_descriptor_00 = cli
+        .get_multi_descriptor(xprv_00, &xpubs[1], &xpubs[2], &xpubs[3])
+        .await;
+    let pub_multi_descriptor = cli
+        .get_pub_multi_descriptor(&xpubs[0], &xpubs[1], &xpubs[2], &xpubs[3])
+        .await;
+
+    Ok(SourceWallet {
+        mint_address,
+        multi_address: key.get_str("multi_address")?.to_string(),
+        amount,
+        multi_descriptor_00,
+        pub_multi_descriptor,
+        xpubs,
+        key_arn: key.get_str("public_key_arn_01")?.to_string(),
+        key_name: key.get_str("public_key_name_03")?.to_string(),
+    })
+}
+
+/// Build TX spending `amount` of each source to `recipients` and sign it by key 00 of each source.
+///
+/// Every source gets its change back, so its balance still backs the rest of its BTCi.
+/// Fee is deducted from recipient amounts pro rata. The first source pays nothing extra.
+pub async fn multi_source_onesig(
+    network: Network,
+    sources: &[SourceWallet],
+    recipients: &[(String, u64)],
+    fee_rate: FeeRate,
+) -> anyhow::Result<OnesigOutput> {
+    let sources: Vec<(String, String, u64)> = sources
+        .iter()
+        .map(|source| {
+            (
+                source.multi_descriptor_00.clone(),
+                source.multi_address.clone(),
+                source.amount,
+            )
+        })
+        .collect();
+    let recipients = recipients.to_vec();
+
+    // Electrum sync of BDK wallet is blocking
+    tokio::task::spawn_blocking(move || build_and_sign(network, &sources, &recipients, fee_rate))
+        .await
+        .context("onesig is failed")?
+}
+
+fn build_and_sign(
+    network: Network,
+    sources: &[(String, String, u64)],
+    recipients: &[(String, u64)],
+    fee_rate: FeeRate,
+) -> anyhow::Result<OnesigOutput> {
+    let script_of = |address: &str| -> anyhow::Result<ScriptBuf> {
+        Ok(Address::from_str(address)?
+            .require_network(network)?
+            .script_pubkey())
+    };
+    let recipient_scripts = recipients
+        .iter()
+        .map(|(address, _)| script_of(address))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let blockchain = ElectrumBlockchain::from(Client::new(electrum_server(network))?);
+    let mut wallets = Vec::new();
+    for (multi_descriptor_00, multi_address, amount) in sources {
+        let wallet = Wallet::new(
+            multi_descriptor_00.as_str(),
+            None,
+            network,
+            MemoryDatabase::default(),
+        )?;
+        wallet.sync(&blockchain, SyncOptions::default())?;
+        let change_script = script_of(multi_address)?;
+        let (utxos, change) = select_utxos(&wallet, *amount, &change_script)
+            .with_context(|| multi_address.clone())?;
+        wallets.push((wallet, utxos, change_script, change));
+    }
+    let total: u64 = sources.iter().map(|(_, _, amount)| amount).sum();
+
+    let build = |recipient_values: &[u64], fee: Option<u64>| {
+        let (primary, primary_utxos, primary_change_script, _) = &wallets[0];
+        let mut builder = primary.build_tx();
+        builder
+            .manually_selected_only()
+            .add_utxos(
+                &primary_utxos
+                    .iter()
+                    .map(|utxo| utxo.outpoint)
+                    .collect::<Vec<_>>(),
+            )?
+            .drain_to(primary_change_script.clone())
+            .enable_rbf();
+        for (script, value) in recipient_scripts.iter().zip(recipient_values) {
+            builder.add_recipient(script.clone(), *value);
+        }
+        for (wallet, utxos, change_script, change) in &wallets[1..] {
+            let satisfaction_weight = wallet
+                .get_descriptor_for_keychain(KeychainKind::External)
+                .max_satisfaction_weight()?;
+            for utxo in utxos {
+                let psbt_input = wallet.get_psbt_input(utxo.clone(), None, false)?;
+                builder.add_foreign_utxo(utxo.outpoint, psbt_input, satisfaction_weight)?;
+            }
+            if *change > 0 {
+                builder.add_recipient(change_script.clone(), *change);
+            }
+        }
+        match fee {
+            Some(fee) => builder.fee_absolute(fee),
+            None => builder.fee_rate(fee_rate),
+        };
+        anyhow::Ok(builder.finish()?)
+    };
+
+    // TX size doesn't depend on output values: estimate fee with the minimal recipient outputs
+    let dust_values: Vec<u64> = recipient_scripts
+        .iter()
+        .map(|script| script.dust_value().to_sat())
+        .collect();
+    let (_, details) = build(&dust_values, None)?;
+    let fee = details
+        .fee
+        .ok_or_else(|| anyhow!("fee of the TX is unknown"))?;
+
+    let amounts: Vec<u64> = recipients.iter().map(|(_, amount)| *amount).collect();
+    let mut recipient_values = Vec::new();
+    for (amount, fee_share) in amounts.iter().zip(split_fee(fee, &amounts)) {
+        let value = amount
+            .checked_sub(fee_share)
+            .filter(|value| *value > 0)
+            .ok_or_else(|| anyhow!("Withdraw amount {amount} is less than fee {fee_share}"))?;
+        recipient_values.push(value);
+    }
+    info!("multi-source withdrawal: total {total}, fee {fee}, send {recipient_values:?}");
+    let (mut psbt, _) = build(&recipient_values, Some(fee))?;
+
+    for (wallet, ..) in &wallets {
+        wallet.sign(
+            &mut psbt,
+            SignOptions {
+                trust_witness_utxo: true,
+                try_finalize: false,
+                ..Default::default()
+            },
+        )?;
+    }
+
+    Ok(OnesigOutput {
+        onesig_psbt: psbt.to_string(),
+        fee,
+    })
+}
+
+/// Largest confirmed UTXOs covering `amount`. Change is zero or above dust limit.
+fn select_utxos(
+    wallet: &Wallet<MemoryDatabase>,
+    amount: u64,
+    change_script: &ScriptBuf,
+) -> anyhow::Result<(Vec<LocalUtxo>, u64)> {
+    let dust = change_script.dust_value().to_sat();
+    let mut utxos = Vec::new();
+    for utxo in wallet.list_unspent()? {
+        let confirmed = wallet
+            .get_tx(&utxo.outpoint.txid, false)?
+            .and_then(|tx| tx.confirmation_time)
+            .is_some();
+        if confirmed {
+            utxos.push(utxo);
+        }
+    }
+    utxos.sort_by_key(|utxo| Reverse(utxo.txout.value));
+
+    let enough = |selected: u64| selected == amount || selected >= amount + dust;
+    let mut selected = Vec::new();
+    let mut selected_value = 0;
+    for utxo in utxos {
+        if enough(selected_value) {
+            break;
+        }
+        selected_value += utxo.txout.value;
+        selected.push(utxo);
+    }
+    if !enough(selected_value) {
+        bail!("Confirmed balance is less than withdraw amount {amount}");
+    }
+    Ok((selected, selected_value - amount))
+}
+
+/// Sign by AWS KMS key of each source
+pub async fn multi_source_secondsig(
+    cli: &BdkCli,
+    sources: &[SourceWallet],
+    onesig_psbt: &str,
+) -> anyhow::Result<String> {
+    let mut psbt = onesig_psbt.to_string();
+    for source in sources {
+        let [xpub_00, xpub_01, xpub_02, xpub_03] = &source.xpubs;
+        let KmsSignOutput { psbt: signed, .. } = cli
+            .aws_kms_sign(xpub_00, xpub_01, xpub_02, xpub_03, &psbt, &source.key_arn)
+            .await?;
+        psbt = signed;
+    }
+    if psbt == onesig_psbt {
+        bail!("Secondsig don't change PSBT");
+    }
+    Ok(psbt)
+}
+
+/// Sign by Google KMS key of each source. All inputs should be finalized after that
+pub async fn multi_source_thirdsig(
+    cli: &BdkCli,
+    sources: &[SourceWallet],
+    secondsig_psbt: &str,
+) -> anyhow::Result<String> {
+    let mut psbt = secondsig_psbt.to_string();
+    let mut is_finalized = false;
+    for source in sources {
+        let [xpub_00, xpub_01, xpub_02, xpub_03] = &source.xpubs;
+        let output = cli
+            .google_kms_sign(xpub_00, xpub_01, xpub_02, xpub_03, &psbt, &source.key_name)
+            .await?;
+        psbt = output.psbt;
+        is_finalized = output.is_finalized;
+    }
+    if !is_finalized {
+        bail!("Still not finalized after thirdsig");
+    }
+    // Sanity check of the result
+    PartiallySignedTransaction::from_str(&psbt)?;
+    Ok(psbt)
+}
@@ -0,0 +1,238 @@
+//! Independent check of PSBT built by `bdk-cli`, before it's passed to the next cosigner
+
+use std::{collections::HashSet, str::FromStr};
+
+use anyhow::{anyhow, bail, Context};
+use bdk::{
+    bitcoin::{psbt::PartiallySignedTransaction, Address, Network, ScriptBuf},
+    miniscript::{Descriptor, DescriptorPublicKey},
+};
+
+use crate::bdk_cli_struct::split_fee;
+
+/// Derivation indexes of a descriptor checked for its addresses.
+/// Deposits use index 0, change of each spend moves to the next index.
+const DERIVATION_LOOKAHEAD: u32 = 1000;
+
+/// What the PSBT is allowed to do
+pub struct PsbtPolicy {
+    pub network: Network,
+    /// Requested recipient addresses and amounts, before fee deduction
+    pub recipients: Vec<(String, u64)>,
+    /// Fee deducted from recipient amounts pro rata. `None` if recipients receive exact amounts
+    pub deducted_fee: Option<u64>,
+    /// Public multisig descriptors of spent deposit addresses. Change should return to them
+    pub descriptors: Vec<String>,
+    /// Max fee of the TX, sat
+    pub max_fee: u64,
+}
+
+impl PsbtPolicy {
+    /// Check that PSBT spends only UTXOs of the descriptors, pays exactly the recipients,
+    /// returns the rest to the descriptors, and its fee is under the cap
+    pub fn check(&self, psbt: &str) -> anyhow::Result<()> {
+        let psbt = PartiallySignedTransaction::from_str(psbt).context("PSBT is invalid")?;
+        let tx = &psbt.unsigned_tx;
+        let owned_scripts = self.owned_scripts()?;
+
+        let mut input_value = 0;
+        for (index, (txin, input)) in tx.input.iter().zip(&psbt.inputs).enumerate() {
+            let utxo = match (&input.witness_utxo, &input.non_witness_utxo) {
+                (Some(utxo), _) => utxo.clone(),
+                (None, Some(prev_tx)) => prev_tx
+                    .output
+                    .get(txin.previous_output.vout as usize)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("input {index} UTXO is not found"))?,
+                (None, None) => bail!("input {index} has no UTXO"),
+            };
+            if !owned_scripts.contains(&utxo.script_pubkey) {
+                bail!("input {index} doesn't belong to deposit address");
+            }
+            input_value += utxo.value;
+        }
+
+        let output_value: u64 = tx.output.iter().map(|output| output.value).sum();
+        let fee = input_value
+            .checked_sub(output_value)
+            .ok_or_else(|| anyhow!("outputs exceed inputs"))?;
+        if fee > self.max_fee {
+            bail!("fee {fee} sat is above max fee {} sat", self.max_fee);
+        }
+
+        let amounts: Vec<u64> = self.recipients.iter().map(|(_, amount)| *amount).collect();
+        let fee_shares = match self.deducted_fee {
+            Some(deducted_fee) => split_fee(deducted_fee, &amounts),
+            None => vec![0; amounts.len()],
+        };
+        let mut expected_outputs = Vec::new();
+        for ((address, amount), fee_share) in self.recipients.iter().zip(fee_shares) {
+            let script = self.script_of(address)?;
+            let value = amount
+                .checked_sub(fee_share)
+                .ok_or_else(|| anyhow!("fee share exceeds amount of {address}"))?;
+            expected_outputs.push((script, value));
+        }
+
+        for (index, output) in tx.output.iter().enumerate() {
+            if let Some(position) = expected_outputs.iter().position(|(script, value)| {
+                *script == output.script_pubkey && *value == output.value
+            }) {
+                expected_outputs.swap_remove(position);
+            } else if !owned_scripts.contains(&output.script_pubkey) {
+                bail!(
+                    "output {index} of {} sat is neither recipient nor change",
+                    output.value
+                );
+            }
+        }
+        if let Some((script, value)) = expected_outputs.first() {
+            bail!("output of {value} sat to {script} is missing");
+        }
+
+        Ok(())
+    }
+
+    fn script_of(&self, address: &str) -> anyhow::Result<ScriptBuf> {
+        Ok(Address::from_str(address)?
+            .require_network(self.network)?
+            .script_pubkey())
+    }
+
+    fn owned_scripts(&self) -> anyhow::Result<HashSet<ScriptBuf>> {
+        let mut scripts = HashSet::new();
+        for descriptor in &self.descriptors {
+            let descriptor = Descriptor::<DescriptorPublicKey>::from_str(descriptor)
+                .context("descriptor is invalid")?;
+            for index in 0..DERIVATION_LOOKAHEAD {
+                scripts.insert(descriptor.at_derivation_index(index)?.script_pubkey());
+            }
+        }
+        Ok(scripts)
+    }
+}
+
+#[test]
+fn test_psbt_policy_check() {
+    use bdk::bitcoin::{
+        absolute::LockTime,
+        bip32::{ExtendedPrivKey, ExtendedPubKey},
+        hashes::Hash,
+        secp256k1::Secp256k1,
+        OutPoint, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    };
+
+    let network = Network::Testnet;
+    let descriptor = |seed: u8| {
+        let xprv = ExtendedPrivKey::new_master(network, &[seed; 32]).unwrap();
+        let xpub = ExtendedPubKey::from_priv(&Secp256k1::new(), &xprv);
+        format!("wpkh({xpub}/0/*)")
+    };
+    let script = |descriptor: &str, index: u32| {
+        Descriptor::<DescriptorPublicKey>::from_str(descriptor)
+            .unwrap()
+            .at_derivation_index(index)
+            .unwrap()
+            .script_pubkey()
+    };
+    let psbt = |inputs: &[(ScriptBuf, u64)], outputs: &[(ScriptBuf, u64)]| {
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: (0..inputs.len())
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(Txid::all_zeros(), vout as u32),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(script_pubkey, value)| TxOut {
+                    value: *value,
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        for (input, (script_pubkey, value)) in psbt.inputs.iter_mut().zip(inputs) {
+            input.witness_utxo = Some(TxOut {
+                value: *value,
+                script_pubkey: script_pubkey.clone(),
+            });
+        }
+        psbt.to_string()
+    };
+
+    let deposit = descriptor(1);
+    let foreign = descriptor(2);
+    let recipient = script(&descriptor(3), 0);
+    let recipient_address = Address::from_script(&recipient, network).unwrap();
+    let policy = PsbtPolicy {
+        network,
+        recipients: vec![(recipient_address.to_string(), 50_000)],
+        deducted_fee: None,
+        descriptors: vec![deposit.clone()],
+        max_fee: 2_000,
+    };
+    let deposit_input = (script(&deposit, 0), 100_000);
+
+    // Change at a later derivation index
+    policy
+        .check(&psbt(
+            &[deposit_input.clone()],
+            &[(recipient.clone(), 50_000), (script(&deposit, 5), 49_000)],
+        ))
+        .unwrap();
+
+    let error = policy
+        .check(&psbt(
+            &[deposit_input.clone(), (script(&foreign, 0), 10_000)],
+            &[(recipient.clone(), 50_000), (script(&deposit, 5), 59_000)],
+        ))
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "input 1 doesn't belong to deposit address"
+    );
+
+    let error = policy
+        .check(&psbt(
+            &[deposit_input.clone()],
+            &[
+                (recipient.clone(), 50_000),
+                (script(&foreign, 0), 1_000),
+                (script(&deposit, 5), 48_000),
+            ],
+        ))
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "output 1 of 1000 sat is neither recipient nor change"
+    );
+
+    let error = policy
+        .check(&psbt(
+            &[deposit_input.clone()],
+            &[(recipient.clone(), 49_999), (script(&deposit, 5), 49_001)],
+        ))
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "output 0 of 49999 sat is neither recipient nor change"
+    );
+
+    let error = policy
+        .check(&psbt(
+            &[deposit_input.clone()],
+            &[(recipient.clone(), 50_000), (script(&deposit, 5), 47_000)],
+        ))
+        .unwrap_err();
+    assert_eq!(error.to_string(), "fee 3000 sat is above max fee 2000 sat");
+
+    let error = policy
+        .check(&psbt(&[deposit_input], &[(script(&deposit, 5), 99_000)]))
+        .unwrap_err();
+    assert!(error.to_string().starts_with("output of 50000 sat to "));
+}
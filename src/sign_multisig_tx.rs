@@ -1,31 +1,81 @@
 use std::str::FromStr;
 
-use axum::{extract::State, Json};
+use axum::{
+    extract::{Path, State},
+    Json,
+};
 use bdk::FeeRate;
 use domichain_program::pubkey::Pubkey;
-use domichain_sdk::signature::Signature;
+use domichain_sdk::{offchain_message::OffchainMessage, signature::Signature};
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
 use reqwest::Url;
-use serde::Deserialize;
-use serde_json::{json, Value};
-use tokio::fs::remove_dir_all;
-use tracing::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    bdk_cli::{
-        bdk_cli, bdk_cli_wallet, bdk_cli_wallet_patched, bdk_cli_wallet_temp, WALLET_DIR_PERMIT,
-    },
+    alert::send_alert,
     bdk_cli_struct::{BdkCli, OnesigOutput},
+    db::WithdrawalStatus,
     domichain::{get_block_height, get_transaction_poll, DomiTransactionInstructionInfo},
     estimate_fee::get_vbytes,
+    fee_quote::FeeQuote,
     mempool::{get_mempool_url, get_recommended_fee_rate},
-    mint_token::{
-        burn_token_inner, get_account_address, get_user_account_address, transfer_token_inner,
+    mint_token::{get_account_address, get_user_account_address, transfer_token_inner},
+    multi_source::{
+        load_source_wallet, multi_source_onesig, multi_source_secondsig, multi_source_thirdsig,
     },
+    psbt_validator::PsbtPolicy,
     utils::{serde_as_str, serde_convert},
+    withdrawal::{
+        burn_withdrawal, deposit_addresses, psbt_txid, recover_withdrawal, send_withdrawal,
+    },
+    withdrawal_limits::check_withdrawal_limits,
     AppState, Args,
 };
 
-#[derive(Clone, Deserialize)]
+/// Request signature scheme: Domichain offchain message of [`request_message`]
+const SIGNATURE_VERSION_OFFCHAIN: u8 = 1;
+/// Max lifetime of a signed request
+const MAX_REQUEST_TTL_SECS: i64 = 60 * 60;
+const MAX_NONCE_LENGTH: usize = 64;
+
+/// Who pays BTC network fee of the withdrawal
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WithdrawalFeeMode {
+    /// Fee is deducted from `withdraw_amount`
+    #[default]
+    DeductFromAmount,
+    /// Recipient receives exact `withdraw_amount`. Fee is covered by extra BTCi of `btci_amount`
+    ExactAmount,
+}
+
+impl WithdrawalFeeMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::DeductFromAmount => "deduct_from_amount",
+            Self::ExactAmount => "exact_amount",
+        }
+    }
+}
+
+/// BTCi of one deposit mint withdrawn by multi-source withdrawal
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WithdrawalSource {
+    #[serde(with = "serde_as_str")]
+    pub mint_address: Pubkey,
+    pub amount: String,
+}
+
+/// BTC output of multi-recipient withdrawal
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WithdrawalRecipient {
+    pub address: String,
+    pub amount: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SignMultisigTxRequest {
     #[serde(with = "serde_as_str")]
     mint_address: Pubkey,
@@ -41,55 +91,391 @@ pub struct SignMultisigTxRequest {
     btci_tx_signature: Signature,
     #[serde(with = "serde_as_str")]
     signature: Signature,
+    #[serde(default)]
+    fee_mode: WithdrawalFeeMode,
+    /// Transferred BTCi in `exact_amount` fee mode: `withdraw_amount` plus max fee
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    btci_amount: Option<String>,
+    /// Deposit mints to withdraw from in one BTC TX. The first one is `mint_address`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<WithdrawalSource>,
+    /// BTC outputs of the withdrawal. The first one is `withdraw_address`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recipients: Vec<WithdrawalRecipient>,
+    /// Scheme of `signature`, see [`request_message`]. Deprecated plain JSON signature if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature_version: Option<u8>,
+    /// Unique per `domi_address`, required by signature version 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    /// Unix time in seconds, required by signature version 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+    /// Fee quote of `/estimate_fee`. Its fee rate is used instead of `fee_rate`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quote: Option<FeeQuote>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quote_signature: Option<String>,
+}
+
+impl SignMultisigTxRequest {
+    /// BTCi amount transferred to the service by `btci_tx_signature`
+    pub fn btci_amount(&self) -> &str {
+        match self.fee_mode {
+            WithdrawalFeeMode::DeductFromAmount => &self.withdraw_amount,
+            WithdrawalFeeMode::ExactAmount => {
+                self.btci_amount.as_deref().unwrap_or(&self.withdraw_amount)
+            }
+        }
+    }
+
+    /// BTCi transfers of `btci_tx_signature`, one per deposit mint
+    pub fn sources(&self) -> Vec<WithdrawalSource> {
+        if self.sources.is_empty() {
+            vec![WithdrawalSource {
+                mint_address: self.mint_address,
+                amount: self.btci_amount().to_string(),
+            }]
+        } else {
+            self.sources.clone()
+        }
+    }
+
+    /// BTC outputs before fee deduction
+    pub fn recipients(&self) -> Vec<WithdrawalRecipient> {
+        if self.recipients.is_empty() {
+            vec![WithdrawalRecipient {
+                address: self.withdraw_address.clone(),
+                amount: self.withdraw_amount.clone(),
+            }]
+        } else {
+            self.recipients.clone()
+        }
+    }
 }
 
-/// Wrapper to check for errors and return BTCi to the user
+/// Validate withdrawal request and process it in background.
+///
+/// Returns withdrawal ID to poll `/withdrawal_status/:withdrawal_id`.
 pub async fn sign_multisig_tx(
     State(state): State<AppState>,
     Json(request): Json<SignMultisigTxRequest>,
 ) -> Json<serde_json::Value> {
+    let mut withdrawal = withdrawal_document(&request);
+    if state.config.dry_run {
+        withdrawal.insert("dry_run", true);
+    }
+    let withdrawal_id = match state.db.insert_withdrawal(withdrawal).await {
+        Ok(withdrawal_id) => withdrawal_id,
+        Err(db_error) => {
+            error!("sign_multisig_tx: failed to record withdrawal: {db_error}");
+            return Json(json!({
+                "status": "error",
+                "message": "Internal service error. Try again later",
+            }));
+        }
+    };
+    let withdrawal_id_str = withdrawal_id
+        .as_object_id()
+        .map(|id| id.to_hex())
+        .unwrap_or_default();
+
     // Separate thread to catch any errors
-    let task_handler = tokio::spawn(sign_multisig_tx_inner(
-        State(state.clone()),
-        Json(request.clone()),
-    ));
-    let task_result = task_handler.await;
-
-    let final_result = match task_result {
-        Ok(withdraw_result) => match withdraw_result {
-            Ok(mut output) => {
-                output["status"] = "ok".into();
-                Ok(output)
+    let validate_result = tokio::spawn(validate_withdrawal(
+        state.clone(),
+        withdrawal_id.clone(),
+        request.clone(),
+    ))
+    .await
+    .unwrap_or_else(|task_error| {
+        // Internal log of panic message
+        error!("sign_multisig_tx: validation thread panicked: {task_error:#?}");
+        Err("Internal service error. Try again later".to_string())
+    });
+
+    match validate_result {
+        Ok(withdrawal_status) => {
+            if withdrawal_status == WithdrawalStatus::Verified {
+                tokio::spawn(process_withdrawal(state, withdrawal_id, request));
             }
-            Err(withdraw_error_message) => Err(withdraw_error_message),
-        },
+            Json(json!({
+                "status": "ok",
+                "withdrawal_id": withdrawal_id_str,
+                "withdrawal_status": withdrawal_status.as_str(),
+            }))
+        }
+        Err(error_message) => {
+            // Send BTCi back, if it's verified
+            tokio::spawn(finish_failed_withdrawal(
+                state,
+                withdrawal_id,
+                error_message.clone(),
+            ));
+            Json(json!({
+                "status": "error",
+                "message": error_message,
+                "withdrawal_id": withdrawal_id_str,
+            }))
+        }
+    }
+}
+
+/// Sign, burn and send validated withdrawal. Refund or resume it on error
+pub async fn process_withdrawal(
+    state: AppState,
+    withdrawal_id: Bson,
+    request: SignMultisigTxRequest,
+) {
+    // Held until BTC TX is sent or the withdrawal is recovered, so consolidation doesn't
+    // spend the same UTXOs
+    let multi_addresses = deposit_addresses(
+        &state,
+        request
+            .sources()
+            .into_iter()
+            .map(|source| source.mint_address),
+    )
+    .await
+    .unwrap_or_default();
+    let _deposit_lease = state.lease_deposit_addresses(&multi_addresses).await;
+
+    // Separate thread to catch any errors
+    let task_result = tokio::spawn(sign_multisig_tx_inner(
+        state.clone(),
+        withdrawal_id.clone(),
+        request,
+    ))
+    .await;
+
+    let error_message = match task_result {
+        Ok(Ok(tx_link)) => {
+            info!("Withdrawal {withdrawal_id} is sent: {tx_link}");
+            return;
+        }
+        Ok(Err(withdraw_error_message)) => withdraw_error_message,
         Err(task_error) => {
             // Internal log of panic message
             error!("sign_multisig_tx: sending thread panicked: {task_error:#?}");
-            Err("Internal service error. Try again later".to_string())
+            "Internal service error. Try again later".to_string()
         }
     };
+    finish_failed_withdrawal(state, withdrawal_id, error_message).await;
+}
 
-    Json(match final_result {
-        Ok(output) => output,
-        Err(error_message) => {
-            // Send BTCi back in case of error
-            if let Err(refund_err) = refund_user(state, request).await {
-                json!({
-                    "status": "error",
-                    "message": format!("original error: {error_message}; refund error: {refund_err}"),
-                })
-            } else {
-                json!({
-                    "status": "error",
-                    "message": error_message,
-                })
-            }
+/// Send BTCi back or finish sending BTC, depending on the last done step
+pub async fn finish_failed_withdrawal(state: AppState, withdrawal_id: Bson, error_message: String) {
+    match recover_withdrawal(&state, withdrawal_id.clone(), &error_message).await {
+        Ok(status) => warn!(
+            "Withdrawal {withdrawal_id} is {} after error: {error_message}",
+            status.as_str()
+        ),
+        Err(recover_error) => error!(
+            "Withdrawal {withdrawal_id} is failed: {error_message}; recover error: {recover_error:#}"
+        ),
+    }
+}
+
+/// Progress of the withdrawal
+pub async fn withdrawal_status(
+    State(state): State<AppState>,
+    Path(withdrawal_id): Path<String>,
+) -> Json<serde_json::Value> {
+    let Ok(object_id) = ObjectId::parse_str(&withdrawal_id) else {
+        return Json(json!({
+            "status": "error",
+            "message": format!("withdrawal_id is invalid: {withdrawal_id}"),
+        }));
+    };
+    let withdrawal = match state.db.find_withdrawal(Bson::ObjectId(object_id)).await {
+        Ok(Some(withdrawal)) => withdrawal,
+        Ok(None) => {
+            return Json(json!({
+                "status": "error",
+                "message": format!("Withdrawal not found: {withdrawal_id}"),
+            }));
         }
-    })
+        Err(db_error) => {
+            error!("withdrawal_status: {db_error}");
+            return Json(json!({
+                "status": "error",
+                "message": "Internal service error. Try again later",
+            }));
+        }
+    };
+
+    let tx_id = withdrawal.get_str("tx_id").ok();
+    let status = withdrawal.get_str("status").unwrap_or_default();
+    let sent = status == WithdrawalStatus::Sent.as_str();
+    // Signed TX is returned instead of being broadcasted
+    let dry_run_psbt = withdrawal
+        .get_str("thirdsig_psbt")
+        .ok()
+        .filter(|_| status == WithdrawalStatus::DryRun.as_str());
+    let refund_signatures: Vec<&str> = withdrawal
+        .get_array("refund_transfers")
+        .map(|transfers| {
+            transfers
+                .iter()
+                .filter_map(|transfer| transfer.as_document()?.get_str("signature").ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    let mempool_url = get_mempool_url(state.config.btc_network);
+    Json(json!({
+        "status": "ok",
+        "withdrawal_id": withdrawal_id,
+        "withdrawal_status": status,
+        "tx_id": tx_id.filter(|_| sent),
+        "tx_link": tx_id.filter(|_| sent).map(|tx_id| format!("{mempool_url}/tx/{tx_id}")),
+        "error": withdrawal.get_str("error").ok(),
+        "refund_signatures": refund_signatures,
+        "psbt": dry_run_psbt,
+        "created_at": withdrawal.get_datetime("created_at").ok().map(|date| date.to_string()),
+        "updated_at": withdrawal.get_datetime("updated_at").ok().map(|date| date.to_string()),
+    }))
+}
+
+/// Withdrawal record with the original request to refund or resume it later
+fn withdrawal_document(request: &SignMultisigTxRequest) -> Document {
+    let mut withdrawal = doc! {
+        "mint_address": request.mint_address.to_string(),
+        "domi_address": request.domi_address.to_string(),
+        "withdraw_address": &request.withdraw_address,
+        "withdraw_amount": &request.withdraw_amount,
+        "fee_mode": request.fee_mode.as_str(),
+        "btci_amount": request.btci_amount(),
+        "btci_tx_signature": request.btci_tx_signature.to_string(),
+        "request": serde_convert::<_, Document>(request),
+    };
+    if !request.sources.is_empty() {
+        let sources: Vec<Document> = request
+            .sources
+            .iter()
+            .map(|source| {
+                doc! {
+                    "mint_address": source.mint_address.to_string(),
+                    "amount": &source.amount,
+                }
+            })
+            .collect();
+        withdrawal.insert("sources", sources);
+    }
+    if !request.recipients.is_empty() {
+        let recipients: Vec<Document> = request
+            .recipients
+            .iter()
+            .map(|recipient| {
+                doc! {
+                    "address": &recipient.address,
+                    "amount": &recipient.amount,
+                }
+            })
+            .collect();
+        withdrawal.insert("recipients", recipients);
+    }
+    if let Some(quote) = &request.quote {
+        withdrawal.insert("quote_id", &quote.quote_id);
+    }
+    withdrawal
+}
+
+/// Reject expired, replayed or deprecated signed requests
+async fn check_request_replay(
+    state: &AppState,
+    withdrawal_id: Bson,
+    request: &SignMultisigTxRequest,
+) -> Result<(), String> {
+    if request.signature_version.is_none() {
+        if !state.config.allow_legacy_request_signature {
+            return Err(format!(
+                "legacy request signature is disabled, use signature_version {}",
+                SIGNATURE_VERSION_OFFCHAIN
+            ));
+        }
+        warn!(
+            "Withdrawal {withdrawal_id}: deprecated request signature of {}",
+            request.domi_address
+        );
+        return Ok(());
+    }
+
+    let (Some(nonce), Some(expires_at)) = (&request.nonce, request.expires_at) else {
+        return Err("nonce and expires_at are required".to_string());
+    };
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_LENGTH {
+        return Err(format!(
+            "nonce should be 1 to {MAX_NONCE_LENGTH} characters"
+        ));
+    }
+    let now = DateTime::now().timestamp_millis() / 1000;
+    if expires_at < now {
+        return Err("request is expired".to_string());
+    }
+    if expires_at > now + MAX_REQUEST_TTL_SECS {
+        return Err(format!(
+            "expires_at should be within {MAX_REQUEST_TTL_SECS} seconds"
+        ));
+    }
+
+    match state
+        .db
+        .consume_request_nonce(&request.domi_address.to_string(), nonce, withdrawal_id)
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("nonce is already used: {nonce}")),
+        Err(db_error) => {
+            error!("Failed to consume request nonce {nonce}: {db_error}");
+            Err("Internal service error. Try again later".to_string())
+        }
+    }
+}
+
+/// Mark BTCi transfer as used by the withdrawal. Reused transfer fails the withdrawal without refund
+async fn consume_btci_tx_signature(
+    state: &AppState,
+    withdrawal_id: Bson,
+    request: &SignMultisigTxRequest,
+) -> Result<(), String> {
+    let btci_tx_signature = request.btci_tx_signature.to_string();
+    match state
+        .db
+        .consume_signature(&btci_tx_signature, withdrawal_id.clone())
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            let error_message = format!("btci_tx_signature is already used: {btci_tx_signature}");
+            warn!("Withdrawal {withdrawal_id}: {error_message}");
+            state
+                .db
+                .update_withdrawal(
+                    withdrawal_id,
+                    WithdrawalStatus::Failed,
+                    doc! { "error": &error_message },
+                )
+                .await
+                .map_err(|db_error| {
+                    error!("Failed to record withdrawal failure: {db_error}");
+                    "Internal service error. Try again later".to_string()
+                })?;
+            Err(error_message)
+        }
+        Err(db_error) => {
+            error!("Failed to consume btci_tx_signature {btci_tx_signature}: {db_error}");
+            Err("Internal service error. Try again later".to_string())
+        }
+    }
 }
 
-pub async fn refund_user(state: AppState, request: SignMultisigTxRequest) -> Result<(), String> {
+/// Return BTCi of the withdrawal to the user.
+/// Refund is possible only if BTCi transfer is not used by another withdrawal,
+/// and only once per withdrawal. Each transfer is recorded with its Domichain signature.
+pub async fn refund_user(
+    state: AppState,
+    withdrawal_id: Bson,
+    request: SignMultisigTxRequest,
+) -> Result<(), String> {
     let Args {
         domichain_rpc_url,
         spl_token_program_id,
@@ -97,88 +483,170 @@ pub async fn refund_user(state: AppState, request: SignMultisigTxRequest) -> Res
     } = state.config.clone();
 
     // Verifications
-    if let Err(verify_error) =
-        verify_request_signature(&domichain_rpc_url, spl_token_program_id, &request).await
+    if let Err(verify_error) = verify_request_signature(
+        &domichain_rpc_url,
+        spl_token_program_id,
+        &state.config.request_signature_domain(),
+        &request,
+    )
+    .await
     {
         return Err(format!("refund: verification is failed: {verify_error}"));
     }
+    let btci_tx_signature = request.btci_tx_signature.to_string();
+    match state
+        .db
+        .consume_signature(&btci_tx_signature, withdrawal_id.clone())
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err(format!(
+                "refund: btci_tx_signature is used by another withdrawal: {btci_tx_signature}"
+            ));
+        }
+        Err(db_error) => return Err(format!("refund: {db_error}")),
+    }
 
-    let SignMultisigTxRequest {
-        mint_address,
-        withdraw_amount,
-        domi_address,
-        ..
-    } = request;
+    // Interrupted refund is left to the operator: its transfers could be already done
+    match state
+        .db
+        .claim_withdrawal_refund(withdrawal_id.clone())
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => return Err("refund: refund is already started".to_string()),
+        Err(db_error) => return Err(format!("refund: {db_error}")),
+    }
 
-    let amount_tokens: u64 = withdraw_amount.parse().unwrap();
-    let destination_token_account_address = get_user_account_address(mint_address, domi_address);
-    transfer_token_inner(
-        &state.config,
+    for WithdrawalSource {
         mint_address,
-        amount_tokens,
-        destination_token_account_address,
-    )
-    .await;
+        amount,
+    } in request.sources()
+    {
+        let amount_tokens: u64 = amount.parse().unwrap();
+        let destination_token_account_address =
+            get_user_account_address(mint_address, request.domi_address);
+        let transfer_signature = transfer_token_inner(
+            &state.config,
+            mint_address,
+            amount_tokens,
+            destination_token_account_address,
+        )
+        .await;
+        state
+            .db
+            .add_withdrawal_refund_transfer(
+                withdrawal_id.clone(),
+                doc! {
+                    "mint_address": mint_address.to_string(),
+                    "amount": &amount,
+                    // Not set in dry run
+                    "signature": transfer_signature.map(|signature| signature.to_string()),
+                    "transferred_at": DateTime::now(),
+                },
+            )
+            .await
+            .map_err(|db_error| {
+                format!("refund: transfer {transfer_signature:?} is not recorded: {db_error}")
+            })?;
+    }
 
     Ok(())
 }
 
-/// Sends BTC multisig transaction and burns BTCi
-pub async fn sign_multisig_tx_inner(
-    State(state): State<AppState>,
-    Json(request): Json<SignMultisigTxRequest>,
-) -> Result<serde_json::Value, String> {
+/// Verify the request and mark its BTCi transfer as used.
+/// Recorded as `verified`, so BTCi is refunded on later errors.
+///
+/// Returns `awaiting_approval` status if the withdrawal should be approved by operator.
+async fn validate_withdrawal(
+    state: AppState,
+    withdrawal_id: Bson,
+    request: SignMultisigTxRequest,
+) -> Result<WithdrawalStatus, String> {
     let Args {
         domichain_rpc_url,
         spl_token_program_id,
-        bdk_cli_path_default,
-        bdk_cli_path_patched,
         btc_network,
         ..
     } = state.config.clone();
 
-    let temp_wallet_dir = None;
-    let descriptor = None;
-    let cli = BdkCli::new(
-        btc_network,
-        bdk_cli_path_default,
-        bdk_cli_path_patched,
-        temp_wallet_dir,
-        descriptor,
-    )
-    .await;
-
     // Verifications
-    if let Err(verify_error) =
-        verify_request_signature(&domichain_rpc_url, spl_token_program_id, &request).await
+    if let Err(verify_error) = verify_request_signature(
+        &domichain_rpc_url,
+        spl_token_program_id,
+        &state.config.request_signature_domain(),
+        &request,
+    )
+    .await
     {
         return Err(format!("verification is failed: {verify_error}"));
     }
+    check_request_replay(&state, withdrawal_id.clone(), &request).await?;
+    consume_btci_tx_signature(&state, withdrawal_id.clone(), &request).await?;
+    record_withdrawal_step(
+        &state,
+        withdrawal_id.clone(),
+        WithdrawalStatus::Verified,
+        doc! {},
+    )
+    .await?;
+    check_fee_quote(&state, withdrawal_id.clone(), &request).await?;
 
+    let amount = request.btci_amount().to_string();
+    let recipient_addresses: Vec<String> = request
+        .recipients()
+        .into_iter()
+        .map(|recipient| recipient.address)
+        .collect();
     let SignMultisigTxRequest {
         mint_address,
         withdraw_address,
         withdraw_amount,
-        fee_rate,
-        vbytes,
-        domi_address: _,
+        domi_address,
         block_height,
-        btci_tx_signature: _,
-        signature: _,
+        fee_mode,
+        btci_amount,
+        sources,
+        recipients,
+        ..
     } = request;
 
-    // Validate withdraw_address
-    match bdk::bitcoin::Address::from_str(&withdraw_address) {
-        Err(error) => {
-            // withdraw_address is invalid
-            return Err(format!("withdraw_address is invalid: {error}"));
-        }
-        Ok(address) => {
-            if !address.is_valid_for_network(btc_network) {
-                // withdraw_address is invalid for currnet network
-                return Err(format!(
-                    "withdraw_address is invalid for '{btc_network}' network"
-                ));
+    if !sources.is_empty() {
+        validate_sources(&state, mint_address, &withdraw_amount, fee_mode, &sources).await?;
+    }
+    if !recipients.is_empty() {
+        validate_recipients(&withdraw_address, &withdraw_amount, &recipients)?;
+    }
+
+    if fee_mode == WithdrawalFeeMode::ExactAmount {
+        let Some(btci_amount) = btci_amount else {
+            return Err("btci_amount is required in exact_amount fee mode".to_string());
+        };
+        let (Ok(withdraw_amount), Ok(btci_amount)) =
+            (withdraw_amount.parse::<u64>(), btci_amount.parse::<u64>())
+        else {
+            return Err("withdraw_amount or btci_amount is invalid".to_string());
+        };
+        if btci_amount < withdraw_amount {
+            return Err("btci_amount should not be less than withdraw_amount".to_string());
+        }
+    }
+
+    // Validate withdraw_address of each recipient
+    for withdraw_address in &recipient_addresses {
+        match bdk::bitcoin::Address::from_str(withdraw_address) {
+            Err(error) => {
+                // withdraw_address is invalid
+                return Err(format!("withdraw_address is invalid: {error}"));
+            }
+            Ok(address) => {
+                if !address.is_valid_for_network(btc_network) {
+                    // withdraw_address is invalid for currnet network
+                    return Err(format!(
+                        "withdraw_address is invalid for '{btc_network}' network"
+                    ));
+                }
             }
         }
     }
@@ -189,76 +657,299 @@ pub async fn sign_multisig_tx_inner(
         return Err("block_height is invalid".to_string());
     }
 
-    let (transaction, key) = if let Some(data) = state
+    if state
         .db
         .find_by_mint_address(&mint_address.to_string())
         .await
         .unwrap()
+        .is_none()
     {
-        data
-    } else {
         // Document not found
         return Err(format!("Mint address not found: {mint_address}"));
-    };
-
-    info!("transaction: {transaction:#?}");
-    info!("key: {key:#?}");
-    let _transaction: serde_json::Value = serde_convert(&transaction);
-    let key: serde_json::Value = serde_convert(&key);
+    }
 
     // Check that witdraw destination is not one of ours BTC multisig addresses
     let known_multisig_addresses = state.db.get_all_multisig_addresses().await;
-    if known_multisig_addresses.contains(&withdraw_address) {
+    if recipient_addresses
+        .iter()
+        .any(|withdraw_address| known_multisig_addresses.contains(withdraw_address))
+    {
         return Err("Withdraw address could not be internal address".to_string());
     }
 
-    // Starting preparing BTC multisig transaction
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| "withdraw_amount is invalid".to_string())?;
+    let domi_address = domi_address.to_string();
+    let withdrawal_status =
+        check_withdrawal_limits(&state, withdrawal_id.clone(), &domi_address, amount).await?;
+    if withdrawal_status == WithdrawalStatus::AwaitingApproval {
+        record_withdrawal_step(&state, withdrawal_id.clone(), withdrawal_status, doc! {}).await?;
+        send_alert(
+            &state.config,
+            "Withdrawal is awaiting approval",
+            json!({
+                "withdrawal_id": withdrawal_id.to_string(),
+                "domi_address": domi_address,
+                "withdraw_address": withdraw_address,
+                "amount": amount.to_string(),
+            }),
+        )
+        .await;
+    }
+
+    Ok(withdrawal_status)
+}
+
+/// Quote should be signed by the service, not expired, match the request and not used before.
+/// `fee_rate` and `vbytes` of the request are optional with a quote, but should match it if set.
+///
+/// Quote is required unless `REQUIRE_FEE_QUOTE` is disabled. Multi-source withdrawal has no quote,
+/// its fee rate is the recommended one.
+async fn check_fee_quote(
+    state: &AppState,
+    withdrawal_id: Bson,
+    request: &SignMultisigTxRequest,
+) -> Result<(), String> {
+    let config = &state.config;
+    let (quote, quote_signature) = match (&request.quote, &request.quote_signature) {
+        (Some(quote), Some(quote_signature)) => (quote, quote_signature),
+        // Legacy: fee rate of the request is trusted
+        (None, None) if !config.require_fee_quote => return Ok(()),
+        (None, None) if request.sources.len() > 1 => {
+            if request.fee_rate.is_some() || request.vbytes.is_some() {
+                return Err(
+                    "fee_rate and vbytes of multi-source withdrawal are chosen by the service"
+                        .to_string(),
+                );
+            }
+            return Ok(());
+        }
+        (None, None) => {
+            return Err("quote is required, get it from /estimate_fee".to_string());
+        }
+        _ => return Err("quote and quote_signature should be set together".to_string()),
+    };
+    // Quote is estimated for the deposit address of `mint_address` only
+    if request.sources.len() > 1 {
+        return Err("quote is not supported by multi-source withdrawal".to_string());
+    }
+
+    let quote_signature: Signature = quote_signature
+        .parse()
+        .map_err(|_| "quote_signature is invalid".to_string())?;
+    let recipients = request
+        .recipients()
+        .into_iter()
+        .map(|recipient| Ok((recipient.address, recipient.amount.parse()?)))
+        .collect::<Result<Vec<(String, u64)>, std::num::ParseIntError>>()
+        .map_err(|_| "withdraw_amount is invalid".to_string())?;
+    quote.verify(
+        &quote_signature,
+        config.domichain_service_address,
+        request.mint_address,
+        &recipients,
+        request.fee_mode,
+    )?;
+
+    if let Some(fee_rate) = &request.fee_rate {
+        if fee_rate.as_f64().map(|fee_rate| fee_rate as f32) != Some(quote.fee_rate) {
+            return Err("fee_rate is different from quote".to_string());
+        }
+    }
+    if request.vbytes.is_some_and(|vbytes| vbytes != quote.vbytes) {
+        return Err("vbytes is different from quote".to_string());
+    }
+
+    let quote_id = &quote.quote_id;
+    match state.db.consume_fee_quote(quote_id, withdrawal_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("quote is already used: {quote_id}")),
+        Err(db_error) => {
+            error!("Failed to consume fee quote {quote_id}: {db_error}");
+            Err("Internal service error. Try again later".to_string())
+        }
+    }
+}
+
+/// Multi-source withdrawal should list unique known mints, summing up to `withdraw_amount`
+async fn validate_sources(
+    state: &AppState,
+    mint_address: Pubkey,
+    withdraw_amount: &str,
+    fee_mode: WithdrawalFeeMode,
+    sources: &[WithdrawalSource],
+) -> Result<(), String> {
+    if fee_mode != WithdrawalFeeMode::DeductFromAmount {
+        return Err("sources are supported only in deduct_from_amount fee mode".to_string());
+    }
+    if sources[0].mint_address != mint_address {
+        return Err("First source should be mint_address".to_string());
+    }
+    let mut total: u64 = 0;
+    for (index, source) in sources.iter().enumerate() {
+        if sources[..index]
+            .iter()
+            .any(|other| other.mint_address == source.mint_address)
+        {
+            return Err(format!("Duplicated source: {}", source.mint_address));
+        }
+        let amount: u64 = match source.amount.parse() {
+            Ok(amount) if amount > 0 => amount,
+            _ => return Err(format!("Source amount is invalid: {}", source.amount)),
+        };
+        total += amount;
+        if state
+            .db
+            .find_by_mint_address(&source.mint_address.to_string())
+            .await
+            .unwrap()
+            .is_none()
+        {
+            return Err(format!("Mint address not found: {}", source.mint_address));
+        }
+    }
+    if withdraw_amount.parse::<u64>().ok() != Some(total) {
+        return Err("Sum of source amounts should be withdraw_amount".to_string());
+    }
+    Ok(())
+}
 
-    // TODO: get fields from meta
+/// Multi-recipient withdrawal should list unique addresses, summing up to `withdraw_amount`
+fn validate_recipients(
+    withdraw_address: &str,
+    withdraw_amount: &str,
+    recipients: &[WithdrawalRecipient],
+) -> Result<(), String> {
+    if recipients[0].address != withdraw_address {
+        return Err("First recipient should be withdraw_address".to_string());
+    }
+    let mut total: u64 = 0;
+    for (index, recipient) in recipients.iter().enumerate() {
+        if recipients[..index]
+            .iter()
+            .any(|other| other.address == recipient.address)
+        {
+            return Err(format!("Duplicated recipient: {}", recipient.address));
+        }
+        let amount: u64 = match recipient.amount.parse() {
+            Ok(amount) if amount > 0 => amount,
+            _ => return Err(format!("Recipient amount is invalid: {}", recipient.amount)),
+        };
+        total += amount;
+    }
+    if withdraw_amount.parse::<u64>().ok() != Some(total) {
+        return Err("Sum of recipient amounts should be withdraw_amount".to_string());
+    }
+    Ok(())
+}
 
-    // export DESCRIPTOR_00="$XPRV_00/84h/1h/0h/0/*"
-    // export XPUB_01=$(cat pub_kms_01.json)
-    // export XPUB_02=$(bdk-cli key derive --xprv $XPRV_02 --path "m/84'/1'/0'/0" | jq -r ".xpub")
+async fn record_withdrawal_step(
+    state: &AppState,
+    withdrawal_id: Bson,
+    status: WithdrawalStatus,
+    update: Document,
+) -> Result<(), String> {
+    state
+        .db
+        .update_withdrawal(withdrawal_id, status, update)
+        .await
+        .map(|_| ())
+        .map_err(|db_error| {
+            error!(
+                "Failed to record withdrawal step {}: {db_error}",
+                status.as_str()
+            );
+            "Internal service error. Try again later".to_string()
+        })
+}
 
-    let private_key_00: serde_json::Value =
-        serde_json::from_str(key["private_key_00"].as_str().unwrap()).unwrap();
-    let xprv_00 = &private_key_00["xprv"].as_str().unwrap();
-    // let descriptor_00 = format!("{xprv_00}/84h/1h/0h/0/*");
+/// Sends one BTC multisig transaction spending from deposit addresses of all sources and burns
+/// BTCi of each source. Each done step is recorded to the withdrawal.
+///
+/// Returns link to the sent BTC transaction.
+pub async fn sign_multisig_tx_inner(
+    state: AppState,
+    withdrawal_id: Bson,
+    request: SignMultisigTxRequest,
+) -> Result<String, String> {
+    let Args { btc_network, .. } = state.config.clone();
+
+    let record_step = |status: WithdrawalStatus, update: Document| {
+        record_withdrawal_step(&state, withdrawal_id.clone(), status, update)
+    };
+    let internal_error = |error: anyhow::Error| {
+        error!("Withdrawal {withdrawal_id}: {error:#}");
+        "Internal service error. Try again later".to_string()
+    };
 
-    let xpub_00 = key["public_key_00"].as_str().unwrap();
-    let xpub_01 = key["public_key_01"].as_str().unwrap();
-    let xpub_02 = key["public_key_02"].as_str().unwrap();
-    let xpub_03 = key["public_key_03"].as_str().unwrap();
+    let cli = BdkCli::new(btc_network);
 
-    let key_arn = key["public_key_arn_01"].as_str().unwrap();
-    let key_name = key["public_key_name_03"].as_str().unwrap();
+    // Already validated
+    let recipients: Vec<(String, u64)> = request
+        .recipients()
+        .into_iter()
+        .map(|recipient| (recipient.address, recipient.amount.parse().unwrap()))
+        .collect();
+    let btci_amount: u64 = request.btci_amount().parse().unwrap();
+    let amount_tokens: u64 = request.withdraw_amount.parse().unwrap();
 
-    // TODO: check constrains, check burn
+    let mut sources = Vec::new();
+    for WithdrawalSource {
+        mint_address,
+        amount,
+    } in request.sources()
+    {
+        let amount: u64 = amount.parse().unwrap();
+        let source = load_source_wallet(&state, &cli, mint_address, amount)
+            .await
+            .map_err(internal_error)?;
+        sources.push(source);
+    }
 
-    let to_address = &withdraw_address;
-    let amount = &withdraw_amount;
-    // let to_address = "tb1qjk7wqccmetsngh9e0zff73rhsqny568g5fs758";
-    // let amount = "400";
+    let SignMultisigTxRequest {
+        fee_rate,
+        vbytes,
+        fee_mode,
+        quote,
+        ..
+    } = request;
 
-    let fee_rate = if let Some(sat_per_vb) = fee_rate {
+    let fee_rate = if let Some(quote) = &quote {
+        FeeRate::from_sat_per_vb(quote.fee_rate)
+    } else if let Some(sat_per_vb) = fee_rate {
         FeeRate::from_sat_per_vb(sat_per_vb.as_f64().unwrap() as f32)
     } else {
         get_recommended_fee_rate(btc_network).await
     };
 
-    let OnesigOutput { onesig_psbt, fee } = match cli
-        .onesig(
-            xprv_00, xpub_01, xpub_02, xpub_03, to_address, amount, fee_rate,
-        )
-        .await
-    {
-        Ok(output) => output,
-        Err(onesig_error) => {
-            return Err(format!("error on creating BTC signature: {onesig_error}"));
-        }
+    // Single source is spent by coin selection of its wallet, with any fee mode and quote.
+    // Several sources spend exactly their amounts, fee is deducted from recipients
+    let OnesigOutput { onesig_psbt, fee } = match sources.as_slice() {
+        [source] => cli
+            .onesig(
+                &source.multi_descriptor_00,
+                &recipients,
+                fee_rate,
+                fee_mode == WithdrawalFeeMode::DeductFromAmount,
+                quote.as_ref().map(|quote| quote.fee),
+            )
+            .await
+            .map_err(|onesig_error| format!("error on creating BTC signature: {onesig_error}"))?,
+        _ => multi_source_onesig(btc_network, &sources, &recipients, fee_rate)
+            .await
+            .map_err(|onesig_error| format!("error on creating BTC signature: {onesig_error:#}"))?,
     };
-    // let onesig_psbt = onesig(&descriptor_00, xpub_01, xpub_02, to_address, amount).await;
     info!("onesig_psbt: {:#?}", &onesig_psbt);
+    record_step(
+        WithdrawalStatus::Onesig,
+        doc! {
+            "onesig_psbt": &onesig_psbt,
+            "fee": fee as i64,
+            "fee_rate": fee_rate.as_sat_per_vb() as f64,
+        },
+    )
+    .await?;
 
     if let Some(expected_vbytes) = vbytes {
         let actual_vbytes = get_vbytes(fee, fee_rate);
@@ -271,132 +962,226 @@ pub async fn sign_multisig_tx_inner(
             return Err(format!("vbytes is different from expected: expected {expected_vbytes}, found {actual_vbytes}"));
         }
     }
+    // UTXOs of the deposit address could change since the quote
+    if let Some(quote) = &quote {
+        if fee > quote.fee {
+            return Err(format!(
+                "fee {fee} sat is above quoted fee {} sat, get a new quote",
+                quote.fee
+            ));
+        }
+    }
 
-    let secondsig_psbt = cli
-        .secondsig(xpub_00, xpub_01, xpub_02, xpub_03, &onesig_psbt, key_arn)
-        .await;
-    info!("secondsig_psbt: {:#?}", &secondsig_psbt);
-    // let (secondsig_psbt, multi_descriptor_01) =
-    //     secondsig(xpub_00, xpub_01, xpub_02, &onesig_psbt, key_arn).await;
-
-    let thirdsig_psbt = cli
-        .thirdsig(
-            xpub_00,
-            xpub_01,
-            xpub_02,
-            xpub_03,
-            &secondsig_psbt,
-            key_name,
-        )
-        .await;
-    info!("thirdsig_psbt: {:#?}", &thirdsig_psbt);
+    if fee_mode == WithdrawalFeeMode::ExactAmount {
+        let fee_allowance = btci_amount - amount_tokens;
+        if fee > fee_allowance {
+            return Err(format!(
+                "fee {fee} sat exceeds fee covered by btci_amount {fee_allowance} sat"
+            ));
+        }
+    }
 
-    // Burning BTCi
+    // Outputs should be exactly the quoted ones
+    let (recipients, deducted_fee) = match &quote {
+        Some(quote) => (
+            quote
+                .send_amounts()
+                .map_err(|quote_error| internal_error(quote_error.context("quote is invalid")))?,
+            None,
+        ),
+        None => (
+            recipients,
+            (fee_mode == WithdrawalFeeMode::DeductFromAmount).then_some(fee),
+        ),
+    };
+    let psbt_policy = PsbtPolicy {
+        network: btc_network,
+        recipients,
+        deducted_fee,
+        descriptors: sources
+            .iter()
+            .map(|source| source.pub_multi_descriptor.clone())
+            .collect(),
+        max_fee: state.config.withdrawal_max_fee,
+    };
 
-    let account_address = get_account_address(mint_address);
-    info!("Burn system account_address: {account_address:?}");
-    let amount_tokens: u64 = withdraw_amount.parse().unwrap();
-    burn_token_inner(&state.config, mint_address, amount_tokens).await;
+    check_psbt_policy(&psbt_policy, &onesig_psbt, "secondsig")?;
+    let secondsig_psbt = multi_source_secondsig(&cli, &sources, &onesig_psbt)
+        .await
+        .map_err(internal_error)?;
+    info!("secondsig_psbt: {:#?}", &secondsig_psbt);
+    record_step(
+        WithdrawalStatus::Secondsig,
+        doc! { "secondsig_psbt": &secondsig_psbt },
+    )
+    .await?;
 
-    // Sending prepared BTC multisig transaction
+    check_psbt_policy(&psbt_policy, &secondsig_psbt, "thirdsig")?;
+    let thirdsig_psbt = multi_source_thirdsig(&cli, &sources, &secondsig_psbt)
+        .await
+        .map_err(internal_error)?;
+    info!("thirdsig_psbt: {:#?}", &thirdsig_psbt);
+    let tx_id = psbt_txid(&thirdsig_psbt).map_err(internal_error)?;
+    record_step(
+        WithdrawalStatus::Thirdsig,
+        doc! {
+            "thirdsig_psbt": &thirdsig_psbt,
+            "tx_id": &tx_id,
+        },
+    )
+    .await?;
 
-    let tx_id = cli
-        .send(xpub_00, xpub_01, xpub_02, xpub_03, &thirdsig_psbt)
-        .await;
-    // let tx_id = send(&multi_descriptor_01, &secondsig_psbt).await;
+    // Burning BTCi of each source: `withdraw_amount`, plus fee in `exact_amount` fee mode.
+    // Burn is marked as started first, so recovery never burns it twice.
+    // Then sending BTC TX, as recovery of signed withdrawal does
+    let withdrawal = state
+        .db
+        .find_withdrawal(withdrawal_id.clone())
+        .await
+        .map_err(|db_error| internal_error(db_error.into()))?
+        .ok_or_else(|| internal_error(anyhow::anyhow!("withdrawal is not found")))?;
+    burn_withdrawal(&state, withdrawal_id.clone(), &withdrawal)
+        .await
+        .map_err(internal_error)?;
+    let status = send_withdrawal(&state, withdrawal_id.clone(), &withdrawal)
+        .await
+        .map_err(internal_error)?;
+    if status == WithdrawalStatus::DryRun {
+        return Ok(format!("dry run, not sent: {tx_id}"));
+    }
 
     let mempool_url = get_mempool_url(btc_network);
     let tx_link = format!("{mempool_url}/tx/{tx_id}");
     info!("transaction sent: {tx_link}");
-    Ok(json!({
-        "thirdsig_psbt": thirdsig_psbt,
-        "tx_id": tx_id,
-        "tx_link": tx_link,
-    }))
+    Ok(tx_link)
+}
+
+/// Check PSBT before it's passed to the next cosigner
+fn check_psbt_policy(psbt_policy: &PsbtPolicy, psbt: &str, round: &str) -> Result<(), String> {
+    psbt_policy.check(psbt).map_err(|policy_error| {
+        error!("PSBT is rejected before {round}: {policy_error:#}");
+        format!("BTC transaction is rejected by policy: {policy_error}")
+    })
 }
 
 async fn verify_request_signature(
     domichain_rpc_url: &Url,
     spl_token_program_id: Pubkey,
+    domain: &str,
     request: &SignMultisigTxRequest,
 ) -> Result<(), String> {
     let SignMultisigTxRequest {
-        mint_address,
-        withdraw_address,
-        withdraw_amount,
-        fee_rate,
-        vbytes,
         domi_address,
-        block_height,
         btci_tx_signature,
         signature,
+        signature_version,
+        ..
     } = request;
 
-    let btci_tx = get_transaction_poll(domichain_rpc_url.clone(), *btci_tx_signature).await;
+    let btci_tx = get_transaction_poll(domichain_rpc_url.clone(), *btci_tx_signature)
+        .await
+        .map_err(|rpc_error| {
+            error!("Failed to get BTCi transfer {btci_tx_signature}: {rpc_error}");
+            "BTCi transfer transaction is not found".to_string()
+        })?;
 
     // Verify is success transaction
-    assert!(btci_tx.meta.err.is_null());
-    assert!(btci_tx.meta.status == json!({"Ok": null}));
+    if !btci_tx.meta.err.is_null() || btci_tx.meta.status != json!({"Ok": null}) {
+        return Err("BTCi transfer transaction is failed".to_string());
+    }
 
     // TODO: check slot is recent
 
     // Verify only one signer
-    assert_eq!(btci_tx.transaction.signatures.len(), 1);
-    assert_eq!(btci_tx.transaction.signatures[0].0, *btci_tx_signature);
-    // Get token transfer instruction
-    let mut ixs = btci_tx
+    if btci_tx.transaction.signatures.len() != 1
+        || btci_tx.transaction.signatures[0].0 != *btci_tx_signature
+    {
+        return Err("BTCi transfer transaction should have one signer".to_string());
+    }
+    // Get token transfer instructions, one per source in the same order
+    let ixs: Vec<_> = btci_tx
         .transaction
         .message
         .instructions
         .into_iter()
-        .filter(|ix| &ix.program == "spl-token" && ix.program_id == spl_token_program_id);
-    let ix = ixs.next().unwrap();
-    assert!(ixs.next().is_none());
-
-    assert_eq!(&ix.parsed.instruction_type, "transferChecked");
-    let info: DomiTransactionInstructionInfo = serde_json::from_value(ix.parsed.info).unwrap();
-    // Verify transfer authority is request sender
-    assert_eq!(&info.authority, domi_address);
-    // Verify transfer destination is service account
-    let service_token_account = get_account_address(*mint_address);
-    assert_eq!(info.destination, service_token_account);
-    // Verify mint address
-    assert_eq!(info.mint, *mint_address);
-    // Verify BTCi token amount is same as in request
-    assert_eq!(
-        info.token_amount["amount"].as_str().unwrap(),
-        withdraw_amount
-    );
+        .filter(|ix| &ix.program == "spl-token" && ix.program_id == spl_token_program_id)
+        .collect();
+    let transfers = request.sources();
+    if ixs.len() != transfers.len() {
+        return Err(format!(
+            "BTCi transfer transaction should have {} token instructions, found {}",
+            transfers.len(),
+            ixs.len()
+        ));
+    }
 
-    let request_body = json!({
-        "mint_address": mint_address.to_string(),
-        "withdraw_address": withdraw_address,
-        "withdraw_amount": withdraw_amount,
-        "fee_rate": fee_rate,
-        "vbytes": vbytes,
-        "domi_address": domi_address.to_string(),
-        "block_height": block_height,
-        "btci_tx_signature": btci_tx_signature.to_string(),
-    });
-    let request_body_str = serde_json::to_string(&request_body).unwrap();
+    for (index, (ix, transfer)) in ixs.into_iter().zip(&transfers).enumerate() {
+        if ix.parsed.instruction_type != "transferChecked" {
+            return Err(format!("BTCi transfer {index} is not transferChecked"));
+        }
+        let info: DomiTransactionInstructionInfo = serde_json::from_value(ix.parsed.info)
+            .map_err(|_| format!("BTCi transfer {index} is invalid"))?;
+        // Verify transfer authority is request sender
+        if &info.authority != domi_address {
+            return Err(format!(
+                "BTCi transfer {index} authority is not domi_address"
+            ));
+        }
+        // Verify transfer destination is service account
+        let service_token_account = get_account_address(transfer.mint_address);
+        if info.destination != service_token_account {
+            return Err(format!(
+                "BTCi transfer {index} destination is not the service account"
+            ));
+        }
+        // Verify mint address
+        if info.mint != transfer.mint_address {
+            return Err(format!(
+                "BTCi transfer {index} mint is not {}",
+                transfer.mint_address
+            ));
+        }
+        // Verify BTCi token amount is same as in request
+        if info.token_amount["amount"].as_str() != Some(transfer.amount.as_str()) {
+            return Err(format!(
+                "BTCi transfer {index} amount is not {}",
+                transfer.amount
+            ));
+        }
+    }
 
-    // Verify `signature` is for `request_body` and `domi_address`
-    if !signature.verify(domi_address.as_ref(), request_body_str.as_bytes()) {
+    // Verify `signature` is for the request message and `domi_address`
+    let (message, verified) = match signature_version {
+        None => {
+            let message = legacy_request_message(request);
+            let verified = signature.verify(domi_address.as_ref(), message.as_bytes());
+            (message, verified)
+        }
+        Some(SIGNATURE_VERSION_OFFCHAIN) => {
+            let message = request_message(domain, request);
+            let verified = OffchainMessage::new(0, message.as_bytes())
+                .and_then(|offchain_message| offchain_message.verify(domi_address, signature))
+                .map_err(|message_error| format!("message is invalid: {message_error}"))?;
+            (message, verified)
+        }
+        Some(version) => return Err(format!("signature_version {version} is not supported")),
+    };
+    if !verified {
         #[allow(dead_code)]
         #[derive(Debug)]
         struct DebugLog {
             signature: Signature,
             domi_address: Pubkey,
-            request_body_str: String,
-            request_body: Value,
+            signature_version: Option<u8>,
+            message: String,
         }
         debug!(
             "Failed to verify: {:#?}",
             DebugLog {
                 signature: *signature,
                 domi_address: *domi_address,
-                request_body_str,
-                request_body,
+                signature_version: *signature_version,
+                message,
             }
         );
         return Err("Failed to verify".to_string());
@@ -405,6 +1190,101 @@ async fn verify_request_signature(
     Ok(())
 }
 
+/// Signed text of the request, signature version 1. Signed as Domichain offchain message.
+///
+/// One `key: value` line per field, in this order, separated by `\n` without trailing newline.
+/// Not set fields have empty value. `sources` and `recipients` are `address:amount` items
+/// separated by `,`. `fee_rate` is formatted as a JSON number of the request.
+fn request_message(domain: &str, request: &SignMultisigTxRequest) -> String {
+    let sources = request
+        .sources
+        .iter()
+        .map(|source| format!("{}:{}", source.mint_address, source.amount))
+        .collect::<Vec<_>>()
+        .join(",");
+    let recipients = request
+        .recipients
+        .iter()
+        .map(|recipient| format!("{}:{}", recipient.address, recipient.amount))
+        .collect::<Vec<_>>()
+        .join(",");
+    let optional = |value: Option<String>| value.unwrap_or_default();
+
+    [
+        ("domain", domain.to_string()),
+        ("signature_version", SIGNATURE_VERSION_OFFCHAIN.to_string()),
+        ("domi_address", request.domi_address.to_string()),
+        ("nonce", optional(request.nonce.clone())),
+        (
+            "expires_at",
+            optional(request.expires_at.map(|time| time.to_string())),
+        ),
+        ("mint_address", request.mint_address.to_string()),
+        ("withdraw_address", request.withdraw_address.clone()),
+        ("withdraw_amount", request.withdraw_amount.clone()),
+        (
+            "fee_rate",
+            optional(request.fee_rate.as_ref().map(|rate| rate.to_string())),
+        ),
+        (
+            "vbytes",
+            optional(request.vbytes.map(|vbytes| vbytes.to_string())),
+        ),
+        ("block_height", request.block_height.to_string()),
+        ("btci_tx_signature", request.btci_tx_signature.to_string()),
+        ("fee_mode", request.fee_mode.as_str().to_string()),
+        ("btci_amount", optional(request.btci_amount.clone())),
+        ("sources", sources),
+        ("recipients", recipients),
+    ]
+    .iter()
+    .map(|(key, value)| format!("{key}: {value}"))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+/// Signed JSON of the request, deprecated: depends on key order and number formatting
+fn legacy_request_message(request: &SignMultisigTxRequest) -> String {
+    let SignMultisigTxRequest {
+        mint_address,
+        withdraw_address,
+        withdraw_amount,
+        fee_rate,
+        vbytes,
+        domi_address,
+        block_height,
+        btci_tx_signature,
+        fee_mode,
+        btci_amount,
+        sources,
+        recipients,
+        ..
+    } = request;
+
+    let mut request_body = json!({
+        "mint_address": mint_address.to_string(),
+        "withdraw_address": withdraw_address,
+        "withdraw_amount": withdraw_amount,
+        "fee_rate": fee_rate,
+        "vbytes": vbytes,
+        "domi_address": domi_address.to_string(),
+        "block_height": block_height,
+        "btci_tx_signature": btci_tx_signature.to_string(),
+    });
+    // Fee mode fields are signed only if set, to keep signatures of older clients valid
+    if *fee_mode == WithdrawalFeeMode::ExactAmount {
+        request_body["fee_mode"] = json!(fee_mode);
+        request_body["btci_amount"] = json!(btci_amount);
+    }
+    if !sources.is_empty() {
+        request_body["sources"] = json!(sources);
+    }
+    if !recipients.is_empty() {
+        request_body["recipients"] = json!(recipients);
+    }
+    serde_json::to_string(&request_body).unwrap()
+}
+
 #[test]
 fn test_verify_request_signature() {
     // await temp1.createSignature(new TextEncoder().encode(JSON.stringify({a:5,b:6})))
@@ -442,108 +1322,61 @@ fn test_verify_request_signature() {
     assert!(!signature.verify(domi_address.as_ref(), request_body_str_wrong.as_bytes()));
 }
 
-#[allow(dead_code)]
-pub async fn onesig(
-    descriptor_00: &str,
-    xpub_01: &str,
-    xpub_02: &str,
-    to_address: &str,
-    amount: &str,
-) -> String {
-    let wallet_dir_permit = WALLET_DIR_PERMIT.acquire().await.unwrap();
-    let _ = remove_dir_all("/home/domi/.bdk-bitcoin/wallet_name_temp").await;
-
-    // export MULTI_DESCRIPTOR_00=$(bdk-cli compile "thresh(2,pk($DESCRIPTOR_00),pk($XPUB_01),pk($XPUB_02))" | jq -r '.descriptor')
-    let multi_descriptor_00_ = format!("thresh(2,pk({descriptor_00}),pk({xpub_01}),pk({xpub_02}))");
-    let multi_descriptor_00_result = bdk_cli(&["compile", &multi_descriptor_00_]).await;
-    let multi_descriptor_00 = multi_descriptor_00_result["descriptor"].as_str().unwrap();
-
-    // bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 sync
-    let sync_output = bdk_cli_wallet_temp(multi_descriptor_00, &["sync"]).await;
-    info!("sync_output: {:#?}", sync_output);
-
-    // bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 get_balance | jq
-    let get_balance_result = bdk_cli_wallet_temp(multi_descriptor_00, &["get_balance"]).await;
-    info!("get_balance_result: {:#?}", get_balance_result);
-
-    // export CHANGE_ID=$(bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 policies | jq -r ".external.id")
-    let change_id_ = bdk_cli_wallet_temp(multi_descriptor_00, &["policies"]).await;
-    let change_id = change_id_["external"]["id"].as_str().unwrap();
-
-    // export UNSIGNED_PSBT=$(bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 create_tx --to $TO_ADDRESS:$AMOUNT --external_policy "{\"$CHANGE_ID\": [0,1]}" | jq -r '.psbt')
-    let unsigned_psbt_ = bdk_cli_wallet_temp(
-        multi_descriptor_00,
-        &[
-            "create_tx",
-            "--to",
-            &format!("{to_address}:{amount}"),
-            "--external_policy",
-            &format!("{{\"{change_id}\": [0,1]}}"),
-        ],
-    )
-    .await;
-    let unsigned_psbt = unsigned_psbt_["psbt"].as_str().unwrap();
-
-    // export ONESIG_PSBT=$(bdk-cli wallet --wallet wallet_name_msd00 --descriptor $MULTI_DESCRIPTOR_00 sign --psbt $UNSIGNED_PSBT | jq -r '.psbt')
-    let onesig_psbt_ =
-        bdk_cli_wallet_temp(multi_descriptor_00, &["sign", "--psbt", unsigned_psbt]).await;
-    let onesig_psbt = onesig_psbt_["psbt"].as_str().unwrap().to_string();
-
-    let _ = remove_dir_all("/home/domi/.bdk-bitcoin/wallet_name_temp").await;
-    drop(wallet_dir_permit);
-
-    onesig_psbt
-}
-
-#[allow(dead_code)]
-pub async fn secondsig(
-    xpub_00: &str,
-    xpub_01: &str,
-    xpub_02: &str,
-    onesig_psbt: &str,
-    key_arn: &str,
-) -> (String, String) {
-    // export MULTI_DESCRIPTOR_01=$(cat multi_descriptor_01.json)
-    // export ONESIG_PSBT=$(cat onesig_psbt.json)
-    // export KEY_ARN="arn:aws:kms:us-east-2:571922870935:key/17be5d9e-d752-4350-bbc1-68993fa25a4f"
-
-    // export MULTI_DESCRIPTOR_01=$(bdk-cli compile "thresh(2,pk($XPUB_00),pk($XPUB_01),pk($XPUB_02))" | jq -r '.descriptor')
-    let multi_descriptor_01_ = format!("thresh(2,pk({xpub_00}),pk({xpub_01}),pk({xpub_02}))");
-    let multi_descriptor_01_result = bdk_cli(&["compile", &multi_descriptor_01_]).await;
-    let multi_descriptor_01 = multi_descriptor_01_result["descriptor"]
-        .as_str()
-        .unwrap()
-        .to_string();
-
-    // export SECONDSIG_PSBT=$(./bdk-cli/target/release/bdk-cli wallet --aws_kms $KEY_ARN --wallet wallet_name_msd01 --descriptor $MULTI_DESCRIPTOR_01 sign --psbt $ONESIG_PSBT | jq -r '.psbt')
-    let secondsig_psbt_ = bdk_cli_wallet_patched(
-        &multi_descriptor_01,
-        &["--aws_kms", key_arn, "sign", "--psbt", onesig_psbt],
-    )
-    .await;
-    let secondsig_psbt = secondsig_psbt_["psbt"].as_str().unwrap();
+#[test]
+fn test_verify_offchain_request_signature() {
+    use domichain_sdk::signature::{Keypair, Signer};
+
+    let keypair = Keypair::new();
+    let request: SignMultisigTxRequest = serde_json::from_value(json!({
+        "mint_address": "Dm6phGa5eh7ihFtvbqM2cjxYrpvvzg5h5y3CnrXHEb2x",
+        "withdraw_address": "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+        "withdraw_amount": "100000",
+        "fee_rate": 12.5,
+        "domi_address": keypair.pubkey().to_string(),
+        "block_height": 1000,
+        "btci_tx_signature": "4LaPQGRQHbjNyv4ET9CtDiPCBoZSBgXLSesmXAwtNKB7n1HYdbRNTiKg6T3YTitHPsuW31gAyiyJ4i3PHgZSGkZK",
+        "signature": "4LaPQGRQHbjNyv4ET9CtDiPCBoZSBgXLSesmXAwtNKB7n1HYdbRNTiKg6T3YTitHPsuW31gAyiyJ4i3PHgZSGkZK",
+        "signature_version": 1,
+        "nonce": "1",
+        "expires_at": 1700000000,
+    }))
+    .unwrap();
 
-    // if [ "$ONESIG_PSBT" = "$SECONDSIG_PSBT" ]; then
-    //     echo "ERROR: Secondsig don't change PSBT"
-    //     exit 1
-    // fi
-    assert_ne!(onesig_psbt, secondsig_psbt);
+    let message = request_message("btc-transfer.test", &request);
+    assert_eq!(
+        message,
+        format!(
+            "domain: btc-transfer.test\n\
+             signature_version: 1\n\
+             domi_address: {}\n\
+             nonce: 1\n\
+             expires_at: 1700000000\n\
+             mint_address: Dm6phGa5eh7ihFtvbqM2cjxYrpvvzg5h5y3CnrXHEb2x\n\
+             withdraw_address: tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx\n\
+             withdraw_amount: 100000\n\
+             fee_rate: 12.5\n\
+             vbytes: \n\
+             block_height: 1000\n\
+             btci_tx_signature: 4LaPQGRQHbjNyv4ET9CtDiPCBoZSBgXLSesmXAwtNKB7n1HYdbRNTiKg6T3YTitHPsuW31gAyiyJ4i3PHgZSGkZK\n\
+             fee_mode: deduct_from_amount\n\
+             btci_amount: \n\
+             sources: \n\
+             recipients: ",
+            keypair.pubkey()
+        )
+    );
 
-    (secondsig_psbt.to_string(), multi_descriptor_01)
-}
+    let offchain_message = OffchainMessage::new(0, message.as_bytes()).unwrap();
+    let signature = offchain_message.sign(&keypair).unwrap();
+    assert!(offchain_message
+        .verify(&keypair.pubkey(), &signature)
+        .unwrap());
 
-#[allow(dead_code)]
-pub async fn send(multi_descriptor_01: &str, secondsig_psbt: &str) -> String {
-    // # broadcast
-    // export TX_ID=$(bdk-cli wallet --wallet wallet_name_msd01 --descriptor $MULTI_DESCRIPTOR_01 broadcast --psbt $SECONDSIG_PSBT)
-    // echo $TX_ID
-    let tx_id_ = bdk_cli_wallet(
-        multi_descriptor_01,
-        &["broadcast", "--psbt", secondsig_psbt],
+    // Signature of one deployment is not valid for another
+    let other_domain = OffchainMessage::new(
+        0,
+        request_message("btc-transfer.other", &request).as_bytes(),
     )
-    .await;
-    let tx_id = tx_id_["txid"].as_str().unwrap().to_string();
-
-    // echo "Check: https://mempool.space/testnet/tx/$(echo $TX_ID | jq -r ".txid")"
-    tx_id
+    .unwrap();
+    assert!(!other_domain.verify(&keypair.pubkey(), &signature).unwrap());
 }
@@ -1,19 +1,34 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeSet, HashSet},
+    sync::Mutex,
     time::{Duration, Instant},
 };
 
-use futures::{SinkExt, StreamExt, TryStreamExt};
+use anyhow::bail;
+use futures::{SinkExt, StreamExt};
 use mongodb::{bson::doc, results::InsertOneResult};
 use serde::Deserialize;
 use serde_json::json;
-use tokio::time::{interval, sleep};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Notify,
+    },
+    task::JoinHandle,
+    time::{interval, sleep},
+};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
-use crate::{mempool::get_mempool_ws_url, mint_token::mint_token_inner, AppState};
+use crate::{
+    alert::send_alert,
+    db::DepositStatus,
+    mempool::{get_mempool_ws_url, TxStatus},
+    AppState,
+};
 
 const PING_INTERVAL: Duration = Duration::from_secs(30);
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(60);
 
 const IGNORED_KEYS: &[&str] = &[
     "mempool-blocks",
@@ -31,7 +46,66 @@ const IGNORED_KEYS: &[&str] = &[
     "pong",
 ];
 
-pub async fn watch_address(state: AppState, address: String, btc_network: bdk::bitcoin::Network) {
+/// Deposit addresses watcher. All addresses are tracked over one mempool WebSocket connection
+#[derive(Default)]
+pub struct Watchers {
+    addresses: Mutex<BTreeSet<String>>,
+    /// Wakes up the connection to resend `track-addresses` list
+    addresses_changed: Notify,
+    connection: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Watchers {
+    /// Start tracking the address. Returns `false` if it is already tracked
+    pub fn add(&self, address: String) -> bool {
+        let inserted = self.addresses.lock().unwrap().insert(address);
+        if inserted {
+            self.addresses_changed.notify_one();
+        }
+        inserted
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        self.addresses.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn contains(&self, address: &str) -> bool {
+        self.addresses.lock().unwrap().contains(address)
+    }
+
+    pub fn count(&self) -> usize {
+        self.addresses.lock().unwrap().len()
+    }
+
+    /// Start connection task unless it is alive. Returns `true` if task was (re)started
+    pub fn ensure_running(&self, state: &AppState) -> bool {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(handle) = connection.as_ref() {
+            if !handle.is_finished() {
+                return false;
+            }
+            warn!("Watcher connection task is finished. Restarting");
+        }
+        *connection = Some(tokio::spawn(watch_addresses(state.clone())));
+        true
+    }
+}
+
+/// Periodically check watcher liveness and restart it if crashed
+pub async fn supervise_watchers(state: AppState) {
+    let mut supervise_interval = interval(SUPERVISE_INTERVAL);
+    loop {
+        supervise_interval.tick().await;
+        if state.watchers.ensure_running(&state) {
+            error!("Watcher connection task was crashed and restarted");
+        }
+    }
+}
+
+/// Connection manager: keeps one WebSocket subscription for all watched addresses
+pub async fn watch_addresses(state: AppState) {
+    let btc_network = state.config.btc_network;
+
     let mut sleep_duration = Duration::from_secs(1);
     let mut last_sleep = Instant::now();
     let sleep_reset_interval = Duration::from_secs(10 * 60); // 10min
@@ -44,37 +118,26 @@ pub async fn watch_address(state: AppState, address: String, btc_network: bdk::b
                 .checked_mul(2)
                 .unwrap()
                 .checked_add(Duration::from_millis(500))
-                .unwrap();
+                .unwrap()
+                .min(sleep_reset_interval);
         }
         last_sleep = Instant::now();
         Some(sleep_duration)
     });
 
+    // Messages are processed sequentially in separate task to not block the connection
+    let (mut messages_sender, mut process_handle) = spawn_process_messages(&state);
+
     // Infinite loop to retry subscription on errors
     loop {
-        let state = state.clone();
-        let address_clone = address.clone();
-        let address_clone_2 = address.clone();
-        info!("Subscribing on {address}");
-
-        let connect_handle = tokio::spawn(async move {
-            let address = address_clone;
-            let (ws_stream, _) = connect_async(get_mempool_ws_url(btc_network))
-                .await
-                .expect("Failed to connect");
-
-            let (mut ws_write, ws_read) = ws_stream.split();
-
-            let init = json!({"action": "init"}).to_string();
-            ws_write.send(Message::text(init)).await.unwrap();
-            let track_addresses = json!({"track-addresses": [&address]}).to_string();
-            ws_write.send(Message::text(track_addresses)).await.unwrap();
-
-            (ws_write, ws_read)
-        });
+        if process_handle.is_finished() {
+            error!("Messages processing task is finished. Restarting");
+            (messages_sender, process_handle) = spawn_process_messages(&state);
+        }
 
-        let (mut ws_write, ws_read) = match connect_handle.await {
-            Ok((ws_write, ws_read)) => (ws_write, ws_read),
+        info!("Connecting to mempool WebSocket");
+        let mut ws_stream = match connect_async(get_mempool_ws_url(btc_network)).await {
+            Ok((ws_stream, _)) => ws_stream,
             Err(connect_error) => {
                 error!("WebSocket connect_error: {connect_error:?}");
                 sleep(sleep_duration.next().unwrap()).await;
@@ -82,66 +145,157 @@ pub async fn watch_address(state: AppState, address: String, btc_network: bdk::b
             }
         };
 
-        let write_handle = tokio::spawn(async move {
-            let mut ping_interval = interval(PING_INTERVAL);
+        let init = json!({"action": "init"}).to_string();
+        if let Err(init_error) = ws_stream.send(Message::text(init)).await {
+            error!("WebSocket init error: {init_error:?}");
+            sleep(sleep_duration.next().unwrap()).await;
+            continue;
+        }
 
-            loop {
-                ping_interval.tick().await;
-                ws_write
-                    .send(Message::text(json!({"action": "ping"}).to_string()))
-                    .await
-                    .unwrap();
-            }
-        });
+        let mut ping_interval = interval(PING_INTERVAL);
+        // Initial `track-addresses` is sent on the first iteration
+        state.watchers.addresses_changed.notify_one();
 
-        let read_handle = tokio::spawn(async move {
-            let address = address_clone_2;
-            ws_read
-                .try_for_each(|msg| async {
+        let connection_error = loop {
+            tokio::select! {
+                _ = ping_interval.tick() => {
+                    let ping = json!({"action": "ping"}).to_string();
+                    if let Err(error) = ws_stream.send(Message::text(ping)).await {
+                        break format!("ping error: {error:?}");
+                    }
+                }
+                _ = state.watchers.addresses_changed.notified() => {
+                    let addresses = state.watchers.addresses();
+                    info!("Subscribing on {} addresses", addresses.len());
+                    let track_addresses = json!({"track-addresses": addresses}).to_string();
+                    if let Err(error) = ws_stream.send(Message::text(track_addresses)).await {
+                        break format!("track-addresses error: {error:?}");
+                    }
+                }
+                msg = ws_stream.next() => {
                     match msg {
-                        Message::Text(msg) => handle_text_message(&state, &address, &msg).await,
-                        other => panic!("expected a text message but got {other:?}"),
+                        Some(Ok(Message::Text(msg))) => {
+                            if messages_sender.send(msg).is_err() {
+                                break "messages processing task is finished".to_string();
+                            }
+                        }
+                        Some(Ok(Message::Close(frame))) => break format!("closed: {frame:?}"),
+                        Some(Ok(other)) => debug!("expected a text message but got {other:?}"),
+                        Some(Err(error)) => break format!("read error: {error:?}"),
+                        None => break "stream ended".to_string(),
                     }
-                    Ok(())
-                })
-                .await
-                .unwrap();
-        });
-
-        if let Err(write_error) = write_handle.await {
-            error!("WebSocket write_error: {write_error:?}");
-        }
-        if let Err(read_error) = read_handle.await {
-            error!("WebSocket read_error: {read_error:?}");
-        }
+                }
+            }
+        };
+        error!("WebSocket connection error: {connection_error}");
 
         sleep(sleep_duration.next().unwrap()).await;
     }
 }
 
-pub async fn handle_text_message(state: &AppState, address: &str, msg: &str) {
-    let mut msg_json: serde_json::Value = serde_json::from_str(msg).unwrap();
-    let msg_object = msg_json.as_object_mut().unwrap();
+fn spawn_process_messages(state: &AppState) -> (UnboundedSender<String>, JoinHandle<()>) {
+    let (messages_sender, messages_receiver) = unbounded_channel();
+    let handle = tokio::spawn(process_messages(state.clone(), messages_receiver));
+    (messages_sender, handle)
+}
+
+async fn process_messages(state: AppState, mut messages_receiver: UnboundedReceiver<String>) {
+    while let Some(msg) = messages_receiver.recv().await {
+        handle_text_message(&state, &msg).await;
+    }
+}
+
+pub async fn handle_text_message(state: &AppState, msg: &str) {
+    let mut msg_json: serde_json::Value = match serde_json::from_str(msg) {
+        Ok(msg_json) => msg_json,
+        Err(parse_error) => {
+            warn!("Failed to parse WebSocket message: {parse_error}: {msg}");
+            return;
+        }
+    };
+    let Some(msg_object) = msg_json.as_object_mut() else {
+        warn!("Expected JSON object message but got: {msg}");
+        return;
+    };
     let keys: HashSet<_> = msg_object.keys().map(|s| s.to_string()).collect();
     // disable auto messages
     msg_object.retain(|k, _| !IGNORED_KEYS.contains(&k.as_str()));
     if !msg_object.is_empty() {
         info!(
             "got: {keys:?} {}",
-            serde_json::to_string_pretty(msg_object).unwrap(),
+            serde_json::to_string_pretty(msg_object).unwrap_or_default(),
         );
     }
     // Get amount from TX
     // Get multisig address by TX info
     // Get domi address by multisig address
-    if let Some(addresses) = msg_object.get("multi-address-transactions") {
-        let confirmed = addresses[&address]["confirmed"].as_array().unwrap();
-        if !confirmed.is_empty() {
-            let confirmed_tx = serde_json::from_value(confirmed[0].clone()).unwrap();
-            process_confirmed_transaction(&state, &address, confirmed_tx).await;
-
-            if confirmed.len() > 1 {
-                todo!("Confirmed TXs array have multiple entries");
+    let Some(addresses) = msg_object.get("multi-address-transactions") else {
+        return;
+    };
+    let Some(addresses) = addresses.as_object() else {
+        warn!("Unexpected `multi-address-transactions` format: {addresses}");
+        return;
+    };
+    for (address, transactions) in addresses {
+        if !state.watchers.contains(address) {
+            warn!("Got transactions for not watched address: {address}");
+            continue;
+        }
+        let tx_list = |key: &str| match transactions.get(key) {
+            Some(serde_json::Value::Array(txs)) => txs.as_slice(),
+            None | Some(serde_json::Value::Null) => &[],
+            Some(other) => {
+                warn!("Unexpected `{key}` format for {address}: {other}");
+                &[]
+            }
+        };
+
+        for mempool_tx in tx_list("mempool") {
+            let mempool_tx: Confirmed = match serde_json::from_value(mempool_tx.clone()) {
+                Ok(mempool_tx) => mempool_tx,
+                Err(parse_error) => {
+                    warn!("Failed to parse mempool TX for {address}: {parse_error}: {mempool_tx}");
+                    continue;
+                }
+            };
+            let tx_hash = mempool_tx.txid.clone();
+            if let Err(process_error) =
+                process_mempool_transaction(state, address, mempool_tx).await
+            {
+                error!("Failed to process mempool TX {tx_hash} for {address}: {process_error:#}");
+            }
+        }
+
+        for removed_tx in tx_list("removed") {
+            let Some(tx_hash) = removed_tx.get("txid").and_then(|txid| txid.as_str()) else {
+                warn!("Failed to parse removed TX for {address}: {removed_tx}");
+                continue;
+            };
+            if let Err(db_error) = state.db.drop_mempool_deposits(tx_hash).await {
+                error!("Failed to drop mempool TX {tx_hash}: {db_error}");
+            }
+        }
+
+        let confirmed = tx_list("confirmed");
+        let mut processed_tx_hashes = HashSet::new();
+        for confirmed_tx in confirmed {
+            let confirmed_tx: Confirmed = match serde_json::from_value(confirmed_tx.clone()) {
+                Ok(confirmed_tx) => confirmed_tx,
+                Err(parse_error) => {
+                    warn!(
+                        "Failed to parse confirmed TX for {address}: {parse_error}: {confirmed_tx}"
+                    );
+                    continue;
+                }
+            };
+            if !processed_tx_hashes.insert(confirmed_tx.txid.clone()) {
+                continue;
+            }
+            let tx_hash = confirmed_tx.txid.clone();
+            if let Err(process_error) =
+                process_confirmed_transaction(state, address, confirmed_tx).await
+            {
+                error!("Failed to process confirmed TX {tx_hash} for {address}: {process_error:#}");
             }
         }
     }
@@ -149,17 +303,25 @@ pub async fn handle_text_message(state: &AppState, address: &str, msg: &str) {
 
 #[derive(Debug, Deserialize)]
 pub struct VinPrevout {
-    pub scriptpubkey_address: String,
+    pub scriptpubkey_address: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Vin {
-    pub prevout: VinPrevout,
+    /// Missing for coinbase inputs
+    pub prevout: Option<VinPrevout>,
+}
+
+impl Vin {
+    pub fn address(&self) -> Option<&str> {
+        self.prevout.as_ref()?.scriptpubkey_address.as_deref()
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Vout {
-    pub scriptpubkey_address: String,
+    /// Missing for non-standard outputs, like `OP_RETURN`
+    pub scriptpubkey_address: Option<String>,
     pub value: u64,
 }
 
@@ -168,75 +330,162 @@ pub struct Confirmed {
     pub txid: String,
     pub vin: Vec<Vin>,
     pub vout: Vec<Vout>,
+    #[serde(default)]
+    pub status: TxStatus,
 }
 
-/// Find deposit amount and do the BTCi mint
+/// Record confirmed deposit. BTCi is minted by confirmation tracker after required confirmations.
+///
+/// All outputs to the address are summed into one deposit. Already recorded transactions and
+/// spends from the address itself are skipped. Deposits funded from other service multisig
+/// are recorded with `review` status and are not minted.
 pub async fn process_confirmed_transaction(
     state: &AppState,
     multi_address: &str,
     confirmed: Confirmed,
-) {
+) -> anyhow::Result<()> {
+    record_deposit(state, multi_address, confirmed, true).await
+}
+
+/// Record deposit seen in mempool to show it to user. Confirmed later by watcher or tracker.
+pub async fn process_mempool_transaction(
+    state: &AppState,
+    multi_address: &str,
+    transaction: Confirmed,
+) -> anyhow::Result<()> {
+    record_deposit(state, multi_address, transaction, false).await
+}
+
+async fn record_deposit(
+    state: &AppState,
+    multi_address: &str,
+    transaction: Confirmed,
+    confirmed: bool,
+) -> anyhow::Result<()> {
     let db = &state.db;
-    // Find corresponding DOMI address
-    let data = db
-        .find_by_deposit_address(multi_address)
-        .await
-        .unwrap()
-        .expect("multisig address doesn't found");
-    let domi_address = data.get_str("domi_address").unwrap();
-
-    // Check that this deposit is from external address
-    let vin = confirmed.vin;
+
+    let tx_hash = transaction.txid;
+    // Mempool record is updated on confirmation
+    let existing_tx = db.find_deposit(&tx_hash, multi_address).await?;
+    if let Some(existing_tx) = &existing_tx {
+        let existing_status = existing_tx.get_str("status").unwrap_or_default();
+        let unconfirmed = existing_status == DepositStatus::Mempool.as_str()
+            || existing_status == DepositStatus::Dropped.as_str();
+        if !(confirmed && unconfirmed) {
+            debug!(
+                "TX {tx_hash} is already recorded. DB ID: {}",
+                existing_tx.get("_id").unwrap()
+            );
+            return Ok(());
+        }
+    }
+
+    // Check that deposit address is known
+    if db.find_by_deposit_address(multi_address).await?.is_none() {
+        bail!("multisig address {multi_address} doesn't found");
+    }
+
+    // Spending from the deposit address itself: our withdraw with change, not a deposit
+    let vin = transaction.vin;
+    if vin
+        .iter()
+        .filter_map(Vin::address)
+        .any(|address| address == multi_address)
+    {
+        debug!("TX {tx_hash} spends from {multi_address}. Skip");
+        return Ok(());
+    }
+
+    // Transfer between service multisigs could not be minted automatically
     let known_multisig_addresses: HashSet<String> =
         HashSet::from_iter(db.get_all_multisig_addresses().await);
-    assert!(!vin
+    let internal_sources: Vec<&str> = vin
         .iter()
-        .any(|vin| known_multisig_addresses.contains(&vin.prevout.scriptpubkey_address)));
+        .filter_map(Vin::address)
+        .filter(|address| known_multisig_addresses.contains(*address))
+        .collect();
+    if !confirmed && !internal_sources.is_empty() {
+        debug!("TX {tx_hash} from service multisig will be recorded after confirmation");
+        return Ok(());
+    }
 
-    // Get TX output and value in sat
-    let vout = confirmed.vout;
-    // Check that transaction have only one our multisig address in output
-    let mut vouts = vout
+    // Sum all outputs to our multisig address
+    let vout = transaction.vout;
+    let address_vouts: Vec<(usize, u64)> = vout
         .iter()
-        .filter(|dest| dest.scriptpubkey_address == multi_address);
-    let address_vout = vouts.next().unwrap();
-    assert!(vouts.next().is_none());
-
-    let value = address_vout.value;
-
-    let tx_hash = confirmed.txid;
-    let InsertOneResult { inserted_id, .. } = db
-        .insert_tx(doc! {
-            "tx_hash": tx_hash,
-            "confirmed": true,
-            "multi_address": multi_address,
-            "value": value.to_string(),
+        .enumerate()
+        .filter_map(|(index, dest)| {
+            (dest.scriptpubkey_address.as_deref() == Some(multi_address))
+                .then_some((index, dest.value))
         })
-        .await
-        .unwrap();
-    info!("Inserted TX. DB ID: {inserted_id}");
-
-    // Fail if:
-    // - network issue
-    // - insufficient balance
-    // - address already exists
-    let mint_result = mint_token_inner(&state.config, &value.to_string(), domi_address).await;
-    info!("mint_result: {mint_result:#?}");
-    let mint_result = mint_result.unwrap();
-
-    let res = db
-        .update_tx(
-            inserted_id,
-            doc! {
-                "minted": true,
-                "mint_address": mint_result.mint_address,
-                "account_address": mint_result.account_address,
-                "domi_address": domi_address,
-            },
+        .collect();
+    let Some(&(vout_index, _)) = address_vouts.first() else {
+        bail!("TX {tx_hash} has no outputs to {multi_address}");
+    };
+    let value: u64 = address_vouts.iter().map(|(_, value)| value).sum();
+    let required_confirmations = state
+        .config
+        .confirmation_policy
+        .required_confirmations(value);
+
+    let status = if !confirmed {
+        DepositStatus::Mempool
+    } else if internal_sources.is_empty() {
+        DepositStatus::Pending
+    } else {
+        DepositStatus::Review
+    };
+
+    let mut insert = doc! {
+        "tx_hash": &tx_hash,
+        "vout": vout_index as i64,
+        "vouts": address_vouts.iter().map(|(index, _)| *index as i64).collect::<Vec<_>>(),
+        "confirmed": confirmed,
+        "status": status.as_str(),
+        "required_confirmations": required_confirmations as i64,
+        "multi_address": multi_address,
+        "value": value.to_string(),
+    };
+    if let Some(block_height) = transaction.status.block_height {
+        insert.insert("block_height", block_height as i64);
+    }
+    if let Some(block_hash) = transaction.status.block_hash {
+        insert.insert("block_hash", block_hash);
+    }
+    let deposit_id = match existing_tx {
+        Some(existing_tx) => {
+            let id = existing_tx.get("_id").unwrap().clone();
+            db.update_tx(id.clone(), insert).await?;
+            info!("Confirmed mempool TX. DB ID: {id}. Required confirmations: {required_confirmations}");
+            id
+        }
+        None => {
+            let Some(InsertOneResult { inserted_id, .. }) = db.insert_tx(insert).await? else {
+                debug!("Deposit {tx_hash}:{vout_index} is already recorded");
+                return Ok(());
+            };
+            info!(
+                "Inserted TX. DB ID: {inserted_id}. Status: {}",
+                status.as_str()
+            );
+            inserted_id
+        }
+    };
+
+    if !internal_sources.is_empty() {
+        send_alert(
+            &state.config,
+            "Deposit from service multisig requires review",
+            json!({
+                "tx_hash": tx_hash,
+                "multi_address": multi_address,
+                "value": value,
+                "from_multisig_addresses": internal_sources,
+                "deposit_id": deposit_id.to_string(),
+            }),
         )
-        .await
-        .unwrap();
-    assert_eq!(res.matched_count, 1);
-    assert_eq!(res.modified_count, 1);
-    assert_eq!(res.upserted_id, None);
+        .await;
+    }
+
+    Ok(())
 }
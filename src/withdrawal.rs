@@ -0,0 +1,564 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context};
+use bdk::bitcoin::psbt::PartiallySignedTransaction;
+use domichain_program::pubkey::Pubkey;
+use domichain_sdk::signature::Signature;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::{
+    alert::send_alert,
+    bdk_cli_struct::BdkCli,
+    db::WithdrawalStatus,
+    domichain::find_token_burn,
+    mempool::get_tx_status,
+    mint_token::{
+        burn_token_inner, get_account_address, get_user_account_address, transfer_token_inner,
+    },
+    sign_multisig_tx::{refund_user, SignMultisigTxRequest, WithdrawalFeeMode},
+    utils::serde_convert,
+    AppState,
+};
+
+/// BTC TX ID of PSBT
+pub fn psbt_txid(psbt: &str) -> anyhow::Result<String> {
+    let psbt = PartiallySignedTransaction::from_str(psbt)?;
+    Ok(psbt.unsigned_tx.txid().to_string())
+}
+
+/// Withdrawal is in `exact_amount` fee mode: BTC fee is paid by extra BTCi
+pub fn is_exact_amount(withdrawal: &Document) -> bool {
+    withdrawal.get_str("fee_mode").ok() == Some(WithdrawalFeeMode::ExactAmount.as_str())
+}
+
+/// BTCi to burn: `withdraw_amount`, plus BTC fee in `exact_amount` fee mode
+fn withdrawal_burn_amount(withdrawal: &Document) -> anyhow::Result<u64> {
+    let withdraw_amount: u64 = withdrawal.get_str("withdraw_amount")?.parse()?;
+    if is_exact_amount(withdrawal) {
+        Ok(withdraw_amount + withdrawal.get_i64("fee")? as u64)
+    } else {
+        Ok(withdraw_amount)
+    }
+}
+
+/// BTC recipients of the withdrawal with amounts before fee deduction
+pub fn withdrawal_recipients(withdrawal: &Document) -> anyhow::Result<Vec<(String, u64)>> {
+    match withdrawal.get_array("recipients") {
+        Ok(recipients) if !recipients.is_empty() => recipients
+            .iter()
+            .map(|recipient| {
+                let recipient = recipient
+                    .as_document()
+                    .ok_or_else(|| anyhow!("recipient is invalid"))?;
+                Ok((
+                    recipient.get_str("address")?.to_string(),
+                    recipient.get_str("amount")?.parse()?,
+                ))
+            })
+            .collect(),
+        _ => Ok(vec![(
+            withdrawal.get_str("withdraw_address")?.to_string(),
+            withdrawal.get_str("withdraw_amount")?.parse()?,
+        )]),
+    }
+}
+
+/// Deposit addresses spent by the withdrawal of BTCi of `mint_addresses`
+pub async fn deposit_addresses(
+    state: &AppState,
+    mint_addresses: impl IntoIterator<Item = Pubkey>,
+) -> anyhow::Result<Vec<String>> {
+    let mut multi_addresses = Vec::new();
+    for mint_address in mint_addresses {
+        let (transaction, _key) = state
+            .db
+            .find_by_mint_address(&mint_address.to_string())
+            .await?
+            .ok_or_else(|| anyhow!("mint address {mint_address} is not found"))?;
+        multi_addresses.push(transaction.get_str("multi_address")?.to_string());
+    }
+    Ok(multi_addresses)
+}
+
+/// Resume or compensate withdrawals which were interrupted by service restart
+pub async fn recover_withdrawals(state: &AppState) {
+    let withdrawals = match state.db.find_withdrawals_in_progress().await {
+        Ok(withdrawals) => withdrawals,
+        Err(db_error) => {
+            error!("Failed to get withdrawals in progress: {db_error}");
+            return;
+        }
+    };
+    if withdrawals.is_empty() {
+        return;
+    }
+    warn!("Found {} interrupted withdrawals", withdrawals.len());
+
+    for withdrawal in withdrawals {
+        let id = withdrawal.get("_id").unwrap().clone();
+        let mint_addresses = withdrawal_burns(&withdrawal)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(mint_address, _)| mint_address);
+        // Unknown deposit: nothing to send, refund doesn't spend UTXOs
+        let multi_addresses = deposit_addresses(state, mint_addresses)
+            .await
+            .unwrap_or_default();
+        let _deposit_lease = state.lease_deposit_addresses(&multi_addresses).await;
+        match recover_withdrawal(state, id.clone(), "interrupted by service restart").await {
+            Ok(status) => info!("Withdrawal {id} is recovered: {}", status.as_str()),
+            Err(recover_error) => error!("Failed to recover withdrawal {id}: {recover_error:#}"),
+        }
+    }
+}
+
+/// Finish the withdrawal depending on the last recorded step:
+/// - BTC TX is not fully signed: return BTCi to the user, once per withdrawal
+/// - BTC TX is fully signed: burn BTCi and send BTC TX
+/// - BTCi is burned: send BTC TX
+///
+/// Should not be called while withdrawal is processed by another task.
+pub async fn recover_withdrawal(
+    state: &AppState,
+    id: Bson,
+    reason: &str,
+) -> anyhow::Result<WithdrawalStatus> {
+    let db = &state.db;
+    let withdrawal = db
+        .find_withdrawal(id.clone())
+        .await?
+        .ok_or_else(|| anyhow!("withdrawal {id} is not found"))?;
+    let status = WithdrawalStatus::parse(withdrawal.get_str("status")?)
+        .ok_or_else(|| anyhow!("withdrawal {id} has unknown status"))?;
+
+    match status {
+        WithdrawalStatus::Created
+        | WithdrawalStatus::Verified
+        | WithdrawalStatus::AwaitingApproval
+        | WithdrawalStatus::Onesig
+        | WithdrawalStatus::Secondsig => {
+            let request: SignMultisigTxRequest =
+                serde_convert::<_, _>(withdrawal.get_document("request")?);
+            info!("Refund withdrawal {id}: {reason}");
+            let refund_result = tokio::spawn(refund_user(state.clone(), id.clone(), request))
+                .await
+                .unwrap_or_else(|task_error| Err(format!("refund panicked: {task_error}")));
+            match refund_result {
+                Ok(()) => {
+                    db.update_withdrawal(id, WithdrawalStatus::Refunded, doc! { "error": reason })
+                        .await?;
+                    Ok(WithdrawalStatus::Refunded)
+                }
+                Err(refund_error) => {
+                    db.update_withdrawal(
+                        id.clone(),
+                        WithdrawalStatus::Failed,
+                        doc! { "error": format!("{reason}; refund error: {refund_error}") },
+                    )
+                    .await?;
+                    // Not verified withdrawal is usually an invalid request, nothing to return
+                    if status != WithdrawalStatus::Created {
+                        send_alert(
+                            &state.config,
+                            "Withdrawal is not refunded",
+                            json!({
+                                "withdrawal_id": id.to_string(),
+                                "reason": reason,
+                                "error": refund_error,
+                            }),
+                        )
+                        .await;
+                    }
+                    Err(anyhow!(refund_error))
+                }
+            }
+        }
+        WithdrawalStatus::Thirdsig => {
+            burn_withdrawal(state, id.clone(), &withdrawal).await?;
+            send_withdrawal(state, id, &withdrawal).await
+        }
+        WithdrawalStatus::Burned => send_withdrawal(state, id, &withdrawal).await,
+        WithdrawalStatus::Sent
+        | WithdrawalStatus::DryRun
+        | WithdrawalStatus::Failed
+        | WithdrawalStatus::Refunded => Ok(status),
+    }
+}
+
+/// BTCi to burn per deposit mint: each source of multi-source withdrawal, or `mint_address`
+fn withdrawal_burns(withdrawal: &Document) -> anyhow::Result<Vec<(Pubkey, u64)>> {
+    match withdrawal.get_array("sources") {
+        Ok(sources) if !sources.is_empty() => sources
+            .iter()
+            .map(|source| {
+                let source = source
+                    .as_document()
+                    .ok_or_else(|| anyhow!("source is invalid"))?;
+                Ok((
+                    Pubkey::from_str(source.get_str("mint_address")?)?,
+                    source.get_str("amount")?.parse()?,
+                ))
+            })
+            .collect(),
+        _ => Ok(vec![(
+            Pubkey::from_str(withdrawal.get_str("mint_address")?)?,
+            withdrawal_burn_amount(withdrawal)?,
+        )]),
+    }
+}
+
+/// Burn BTCi of the withdrawal. Mints burned before interruption are recorded and skipped.
+///
+/// Each burn is marked as started before it's sent. Burn which was started but not recorded
+/// has unknown result: it's looked up on Domichain, and if it's not found the withdrawal
+/// is failed for operator, since burning again could burn tokens of another withdrawal.
+pub async fn burn_withdrawal(
+    state: &AppState,
+    id: Bson,
+    withdrawal: &Document,
+) -> anyhow::Result<()> {
+    let burning_mint = withdrawal.get_str("burning_mint").ok();
+    let burn_started_at = withdrawal.get_datetime("burn_started_at").ok().copied();
+    let mut burned_mints: Vec<String> = withdrawal
+        .get_array("burned_mints")
+        .map(|mints| {
+            mints
+                .iter()
+                .filter_map(|mint| mint.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut burn_signatures = Vec::new();
+    let mut burned_amount = 0;
+
+    for (mint_address, amount_tokens) in withdrawal_burns(withdrawal)? {
+        burned_amount += amount_tokens;
+        if burned_mints.contains(&mint_address.to_string()) {
+            continue;
+        }
+
+        if burning_mint == Some(mint_address.to_string().as_str()) {
+            let burn_signature =
+                find_interrupted_burn(state, mint_address, amount_tokens, burn_started_at).await;
+            let Ok(Some(burn_signature)) = burn_signature else {
+                let error = match burn_signature {
+                    Err(find_error) => format!("burn lookup error: {find_error:#}"),
+                    _ => "burn is not found on Domichain".to_string(),
+                };
+                state
+                    .db
+                    .update_withdrawal(
+                        id.clone(),
+                        WithdrawalStatus::Failed,
+                        doc! { "error": format!("burn result of {mint_address} is unknown: {error}") },
+                    )
+                    .await?;
+                send_alert(
+                    &state.config,
+                    "Withdrawal burn result is unknown",
+                    json!({
+                        "withdrawal_id": id.to_string(),
+                        "mint_address": mint_address.to_string(),
+                        "amount": amount_tokens,
+                        "error": error,
+                    }),
+                )
+                .await;
+                bail!("burn result of {mint_address} is unknown: {error}");
+            };
+            info!("Found burn {burn_signature} of {mint_address} of withdrawal {id}");
+            burn_signatures.push(burn_signature.to_string());
+            burned_mints.push(mint_address.to_string());
+            state
+                .db
+                .set_withdrawal_fields(id.clone(), doc! { "burned_mints": &burned_mints })
+                .await?;
+            continue;
+        }
+
+        // Recorded before sending, so a burn with lost result is never sent again
+        state
+            .db
+            .set_withdrawal_fields(
+                id.clone(),
+                doc! {
+                    "burning_mint": mint_address.to_string(),
+                    "burn_started_at": DateTime::now(),
+                },
+            )
+            .await?;
+        info!("Burn BTCi {mint_address} of withdrawal {id}");
+        let config = state.config.clone();
+        let burn_signature =
+            tokio::spawn(
+                async move { burn_token_inner(&config, mint_address, amount_tokens).await },
+            )
+            .await
+            .context("burn is failed")?;
+        if let Some(burn_signature) = burn_signature {
+            burn_signatures.push(burn_signature.to_string());
+        }
+
+        burned_mints.push(mint_address.to_string());
+        state
+            .db
+            .set_withdrawal_fields(id.clone(), doc! { "burned_mints": &burned_mints })
+            .await?;
+    }
+
+    let mut burned = doc! { "burned_amount": burned_amount.to_string() };
+    if let Some(burn_signature) = burn_signatures.first() {
+        burned.insert("burn_signature", burn_signature);
+    }
+    if burn_signatures.len() > 1 {
+        burned.insert("burn_signatures", burn_signatures);
+    }
+    state
+        .db
+        .update_withdrawal(id, WithdrawalStatus::Burned, burned)
+        .await?;
+    Ok(())
+}
+
+/// Burn transaction of the service token account sent since the burn was started
+async fn find_interrupted_burn(
+    state: &AppState,
+    mint_address: Pubkey,
+    amount: u64,
+    burn_started_at: Option<DateTime>,
+) -> anyhow::Result<Option<Signature>> {
+    let burn_started_at = burn_started_at.ok_or_else(|| anyhow!("burn_started_at is missing"))?;
+    // Allow clock difference with Domichain
+    let since = burn_started_at.timestamp_millis() / 1000 - 60;
+    find_token_burn(
+        state.config.domichain_rpc_url.clone(),
+        get_account_address(mint_address),
+        amount,
+        since,
+    )
+    .await
+}
+
+/// `burned_amount` of the withdrawal, including additional fee burn of a fee bump
+/// interrupted before its result was recorded.
+///
+/// Fails while that burn is not found on Domichain: it's left to operator, never sent again.
+pub async fn recover_fee_burn(
+    state: &AppState,
+    id: Bson,
+    withdrawal: &Document,
+) -> anyhow::Result<u64> {
+    let burned_amount: u64 = withdrawal.get_str("burned_amount")?.parse()?;
+    let Ok(burning_fee) = withdrawal.get_str("burning_fee") else {
+        return Ok(burned_amount);
+    };
+    let burning_fee: u64 = burning_fee.parse()?;
+    let mint_address = Pubkey::from_str(withdrawal.get_str("mint_address")?)?;
+    let burn_started_at = withdrawal.get_datetime("burn_started_at").ok().copied();
+
+    let burn_signature =
+        find_interrupted_burn(state, mint_address, burning_fee, burn_started_at).await;
+    let Ok(Some(burn_signature)) = burn_signature else {
+        let error = match burn_signature {
+            Err(find_error) => format!("burn lookup error: {find_error:#}"),
+            _ => "burn is not found on Domichain".to_string(),
+        };
+        send_alert(
+            &state.config,
+            "Withdrawal fee burn result is unknown",
+            json!({
+                "withdrawal_id": id.to_string(),
+                "mint_address": mint_address.to_string(),
+                "amount": burning_fee,
+                "error": error,
+            }),
+        )
+        .await;
+        bail!("additional fee burn result of {mint_address} is unknown: {error}");
+    };
+    info!("Found additional fee burn {burn_signature} of withdrawal {id}");
+    let burned_amount = burned_amount + burning_fee;
+    state
+        .db
+        .add_withdrawal_fee_burn(id, burned_amount, &burn_signature.to_string())
+        .await?;
+    Ok(burned_amount)
+}
+
+/// Burn additional fee BTCi of a fee bump, on top of `burned_amount`.
+///
+/// `burning_fee` is recorded before sending, see `recover_fee_burn`. Returns new `burned_amount`.
+pub async fn burn_withdrawal_fee(
+    state: &AppState,
+    id: Bson,
+    withdrawal: &Document,
+    burned_amount: u64,
+    amount: u64,
+) -> anyhow::Result<u64> {
+    let mint_address = Pubkey::from_str(withdrawal.get_str("mint_address")?)?;
+    state
+        .db
+        .set_withdrawal_fields(
+            id.clone(),
+            doc! {
+                "burning_fee": amount.to_string(),
+                "burn_started_at": DateTime::now(),
+            },
+        )
+        .await?;
+    info!("Burn additional fee {amount} of {mint_address} of withdrawal {id}");
+    let config = state.config.clone();
+    let burn_signature =
+        tokio::spawn(async move { burn_token_inner(&config, mint_address, amount).await })
+            .await
+            .context("burn of additional fee is failed")?
+            .ok_or_else(|| anyhow!("burn of additional fee has no signature"))?;
+
+    let burned_amount = burned_amount + amount;
+    state
+        .db
+        .add_withdrawal_fee_burn(id, burned_amount, &burn_signature.to_string())
+        .await?;
+    Ok(burned_amount)
+}
+
+/// Broadcast fully signed BTC TX, if it's not known to the network yet.
+/// In dry run the TX is only recorded.
+///
+/// Returns the recorded status: `sent` or `dry_run`.
+pub async fn send_withdrawal(
+    state: &AppState,
+    id: Bson,
+    withdrawal: &Document,
+) -> anyhow::Result<WithdrawalStatus> {
+    let btc_network = state.config.btc_network;
+    let thirdsig_psbt = withdrawal.get_str("thirdsig_psbt")?.to_string();
+    let tx_id = psbt_txid(&thirdsig_psbt)?;
+
+    if state.config.dry_run {
+        info!("Dry run: BTC TX {tx_id} of withdrawal {id} is not sent");
+        state
+            .db
+            .update_withdrawal(id, WithdrawalStatus::DryRun, doc! { "tx_id": &tx_id })
+            .await?;
+        return Ok(WithdrawalStatus::DryRun);
+    }
+
+    if get_tx_status(btc_network, &tx_id).await?.is_some() {
+        info!("BTC TX {tx_id} of withdrawal {id} is already sent");
+    } else {
+        let Some((_, key)) = state
+            .db
+            .find_by_mint_address(withdrawal.get_str("mint_address")?)
+            .await?
+        else {
+            bail!("keys of withdrawal {id} are not found");
+        };
+        let xpubs: Vec<String> = [
+            "public_key_00",
+            "public_key_01",
+            "public_key_02",
+            "public_key_03",
+        ]
+        .into_iter()
+        .map(|field| key.get_str(field).map(str::to_string))
+        .collect::<Result<_, _>>()?;
+
+        info!("Send BTC TX {tx_id} of withdrawal {id}");
+        let config = state.config.clone();
+        let sent_tx_id = tokio::spawn(async move {
+            let cli = BdkCli::new(config.btc_network);
+            cli.send(&xpubs[0], &xpubs[1], &xpubs[2], &xpubs[3], &thirdsig_psbt)
+                .await
+        })
+        .await
+        .context("broadcast is failed")??;
+        if sent_tx_id != tx_id {
+            warn!("Sent TX ID {sent_tx_id} is different from PSBT TX ID {tx_id}");
+        }
+    }
+
+    state
+        .db
+        .update_withdrawal(
+            id,
+            WithdrawalStatus::Sent,
+            doc! {
+                "tx_id": &tx_id,
+                "tx_ids": [&tx_id],
+                "broadcast_at": DateTime::now(),
+            },
+        )
+        .await?;
+    Ok(WithdrawalStatus::Sent)
+}
+
+/// Return BTCi which is not spent on BTC fee of `exact_amount` withdrawal.
+///
+/// Called once BTC TX is confirmed, as fee bumps could spend more of it.
+pub async fn refund_fee_excess(
+    state: &AppState,
+    id: Bson,
+    withdrawal: &Document,
+) -> anyhow::Result<()> {
+    if !is_exact_amount(withdrawal) || withdrawal.get_bool("fee_refunded").unwrap_or(false) {
+        return Ok(());
+    }
+    let btci_amount: u64 = withdrawal.get_str("btci_amount")?.parse()?;
+    let burned_amount: u64 = withdrawal.get_str("burned_amount")?.parse()?;
+    let excess = btci_amount.saturating_sub(burned_amount);
+
+    // Marked before transfer: failed transfer is left to the operator instead of paying twice
+    state
+        .db
+        .set_withdrawal_fields(
+            id.clone(),
+            doc! {
+                "fee_refunded": true,
+                "fee_refund_amount": excess.to_string(),
+            },
+        )
+        .await?;
+    if excess == 0 {
+        return Ok(());
+    }
+
+    let mint_address = Pubkey::from_str(withdrawal.get_str("mint_address")?)?;
+    let domi_address = Pubkey::from_str(withdrawal.get_str("domi_address")?)?;
+    info!("Refund {excess} BTCi of unspent fee of withdrawal {id}");
+    let config = state.config.clone();
+    let transfer_result = tokio::spawn(async move {
+        let destination = get_user_account_address(mint_address, domi_address);
+        transfer_token_inner(&config, mint_address, excess, destination).await
+    })
+    .await;
+    match transfer_result {
+        Ok(Some(transfer_signature)) => {
+            state
+                .db
+                .set_withdrawal_fields(
+                    id,
+                    doc! { "fee_refund_signature": transfer_signature.to_string() },
+                )
+                .await?;
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(task_error) => {
+            send_alert(
+                &state.config,
+                "Withdrawal fee excess is not refunded",
+                json!({
+                    "withdrawal_id": id.to_string(),
+                    "domi_address": domi_address.to_string(),
+                    "amount": excess.to_string(),
+                    "error": task_error.to_string(),
+                }),
+            )
+            .await;
+            bail!("fee excess refund is failed: {task_error}");
+        }
+    }
+}
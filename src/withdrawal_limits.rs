@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use mongodb::bson::{Bson, DateTime};
+use tracing::error;
+
+use crate::{db::WithdrawalStatus, AppState};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Withdrawal amount limits in sat of BTCi. Not limited if not set
+#[derive(clap::Args, Debug, Clone, Copy)]
+pub struct WithdrawalLimits {
+    /// Max amount of single withdrawal
+    #[arg(long, env = "WITHDRAWAL_MAX_AMOUNT")]
+    pub withdrawal_max_amount: Option<u64>,
+
+    /// Max amount withdrawn by Domichain wallet in the last hour
+    #[arg(long, env = "WITHDRAWAL_WALLET_HOURLY_LIMIT")]
+    pub withdrawal_wallet_hourly_limit: Option<u64>,
+
+    /// Max amount withdrawn by Domichain wallet in the last 24 hours
+    #[arg(long, env = "WITHDRAWAL_WALLET_DAILY_LIMIT")]
+    pub withdrawal_wallet_daily_limit: Option<u64>,
+
+    /// Max amount withdrawn by all wallets in the last hour
+    #[arg(long, env = "WITHDRAWAL_GLOBAL_HOURLY_LIMIT")]
+    pub withdrawal_global_hourly_limit: Option<u64>,
+
+    /// Max amount withdrawn by all wallets in the last 24 hours
+    #[arg(long, env = "WITHDRAWAL_GLOBAL_DAILY_LIMIT")]
+    pub withdrawal_global_daily_limit: Option<u64>,
+
+    /// Withdrawals above this amount wait for operator approval before signing
+    #[arg(long, env = "WITHDRAWAL_APPROVAL_THRESHOLD")]
+    pub withdrawal_approval_threshold: Option<u64>,
+}
+
+/// Check verified withdrawal against the limits.
+///
+/// Returns the next withdrawal status: `verified` or `awaiting_approval`.
+/// Withdrawal should be already recorded as `verified`, so concurrent withdrawals
+/// always count each other at least from one side.
+pub async fn check_withdrawal_limits(
+    state: &AppState,
+    withdrawal_id: Bson,
+    domi_address: &str,
+    amount: u64,
+) -> Result<WithdrawalStatus, String> {
+    let WithdrawalLimits {
+        withdrawal_max_amount,
+        withdrawal_wallet_hourly_limit,
+        withdrawal_wallet_daily_limit,
+        withdrawal_global_hourly_limit,
+        withdrawal_global_daily_limit,
+        withdrawal_approval_threshold,
+    } = state.config.withdrawal_limits;
+
+    if let Some(max_amount) = withdrawal_max_amount {
+        if amount > max_amount {
+            return Err(format!(
+                "Withdrawal amount exceeds limit of {max_amount} sat per request"
+            ));
+        }
+    }
+
+    let windows = [
+        (
+            "Wallet hourly",
+            Some(domi_address),
+            HOUR,
+            withdrawal_wallet_hourly_limit,
+        ),
+        (
+            "Wallet daily",
+            Some(domi_address),
+            DAY,
+            withdrawal_wallet_daily_limit,
+        ),
+        ("Global hourly", None, HOUR, withdrawal_global_hourly_limit),
+        ("Global daily", None, DAY, withdrawal_global_daily_limit),
+    ];
+    for (name, domi_address, window, limit) in windows {
+        let Some(limit) = limit else {
+            continue;
+        };
+        let since =
+            DateTime::from_millis(DateTime::now().timestamp_millis() - window.as_millis() as i64);
+        let withdrawn = state
+            .db
+            .sum_withdrawals_since(domi_address, since, withdrawal_id.clone())
+            .await
+            .map_err(|db_error| {
+                error!("Failed to sum withdrawals: {db_error}");
+                "Internal service error. Try again later".to_string()
+            })?;
+        if withdrawn + amount > limit {
+            return Err(format!(
+                "{name} withdrawal limit of {limit} sat is exceeded. Try again later"
+            ));
+        }
+    }
+
+    match withdrawal_approval_threshold {
+        Some(threshold) if amount > threshold => Ok(WithdrawalStatus::AwaitingApproval),
+        _ => Ok(WithdrawalStatus::Verified),
+    }
+}